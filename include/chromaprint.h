@@ -0,0 +1,103 @@
+/*
+ * C API of chromaprinter, compatible with libchromaprint's chromaprint.h.
+ *
+ * Functions returning int return 1 on success and 0 on failure. Memory returned through
+ * pointer arguments must be freed with chromaprint_dealloc().
+ *
+ * This file is maintained by hand. Keep it in sync with src/capi.rs, the tests check that
+ * every exported function is declared here with the same signature.
+ */
+
+#ifndef CHROMAPRINT_CHROMAPRINT_H_
+#define CHROMAPRINT_CHROMAPRINT_H_
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#include <stdint.h>
+
+#define CHROMAPRINT_API
+
+#define CHROMAPRINT_VERSION_MAJOR 1
+#define CHROMAPRINT_VERSION_MINOR 5
+#define CHROMAPRINT_VERSION_PATCH 1
+
+struct ChromaprintContextPrivate;
+typedef struct ChromaprintContextPrivate ChromaprintContext;
+
+/* Only CHROMAPRINT_ALGORITHM_TEST2 is supported, chromaprint_new() returns NULL for the
+ * others. */
+enum ChromaprintAlgorithm {
+	CHROMAPRINT_ALGORITHM_TEST1 = 0,
+	CHROMAPRINT_ALGORITHM_TEST2,
+	CHROMAPRINT_ALGORITHM_TEST3,
+	CHROMAPRINT_ALGORITHM_TEST4,
+	CHROMAPRINT_ALGORITHM_TEST5,
+	CHROMAPRINT_ALGORITHM_DEFAULT = CHROMAPRINT_ALGORITHM_TEST2,
+};
+
+/* Version of libchromaprint the API is compatible with. */
+CHROMAPRINT_API const char *chromaprint_get_version(void);
+
+CHROMAPRINT_API ChromaprintContext *chromaprint_new(int algorithm);
+
+CHROMAPRINT_API void chromaprint_free(ChromaprintContext *ctx);
+
+CHROMAPRINT_API int chromaprint_get_algorithm(ChromaprintContext *ctx);
+
+/* No options are supported, always fails. */
+CHROMAPRINT_API int chromaprint_set_option(ChromaprintContext *ctx, const char *name, int value);
+
+/* Number of channels the audio is downmixed to. */
+CHROMAPRINT_API int chromaprint_get_num_channels(ChromaprintContext *ctx);
+
+/* Sample rate the audio is resampled to. */
+CHROMAPRINT_API int chromaprint_get_sample_rate(ChromaprintContext *ctx);
+
+/* Duration of one subfingerprint in samples at chromaprint_get_sample_rate(). */
+CHROMAPRINT_API int chromaprint_get_item_duration(ChromaprintContext *ctx);
+
+CHROMAPRINT_API int chromaprint_get_item_duration_ms(ChromaprintContext *ctx);
+
+/* Audio needed before the first subfingerprint, in samples at chromaprint_get_sample_rate(). */
+CHROMAPRINT_API int chromaprint_get_delay(ChromaprintContext *ctx);
+
+CHROMAPRINT_API int chromaprint_get_delay_ms(ChromaprintContext *ctx);
+
+/* Starts a new fingerprint, discarding the previous one. */
+CHROMAPRINT_API int chromaprint_start(ChromaprintContext *ctx, int sample_rate, int num_channels);
+
+/* Feeds interleaved audio, size is the number of samples in data counting all channels. */
+CHROMAPRINT_API int chromaprint_feed(ChromaprintContext *ctx, const int16_t *data, int size);
+
+CHROMAPRINT_API int chromaprint_finish(ChromaprintContext *ctx);
+
+/* Compressed fingerprint as a zero terminated base64 string. */
+CHROMAPRINT_API int chromaprint_get_fingerprint(ChromaprintContext *ctx, char **fingerprint);
+
+CHROMAPRINT_API int chromaprint_get_raw_fingerprint(ChromaprintContext *ctx, uint32_t **fingerprint, int *size);
+
+CHROMAPRINT_API int chromaprint_get_raw_fingerprint_size(ChromaprintContext *ctx, int *size);
+
+CHROMAPRINT_API int chromaprint_get_fingerprint_hash(ChromaprintContext *ctx, uint32_t *hash);
+
+CHROMAPRINT_API int chromaprint_clear_fingerprint(ChromaprintContext *ctx);
+
+/* Compresses a raw fingerprint. With base64 set the result is a zero terminated string,
+ * encoded_size doesn't count the terminator. */
+CHROMAPRINT_API int chromaprint_encode_fingerprint(const uint32_t *fp, int size, int algorithm, char **encoded_fp, int *encoded_size, int base64);
+
+/* Reverses chromaprint_encode_fingerprint(), algorithm may be NULL. */
+CHROMAPRINT_API int chromaprint_decode_fingerprint(const char *encoded_fp, int encoded_size, uint32_t **fp, int *size, int *algorithm, int base64);
+
+/* SimHash of a raw fingerprint. */
+CHROMAPRINT_API int chromaprint_hash_fingerprint(const uint32_t *fp, int size, uint32_t *hash);
+
+CHROMAPRINT_API void chromaprint_dealloc(void *ptr);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
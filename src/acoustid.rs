@@ -0,0 +1,883 @@
+use rustls::{ClientConnection, RootCertStore, StreamOwned};
+use rustls::pki_types::ServerName;
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Algorithm;
+use crate::fingerprint::compress;
+use crate::fingerprint::codec::base64;
+use crate::util::{connect_http, connect_url};
+
+/// Service error codes worth retrying: internal error, service unavailable and too many requests.
+const RETRY_CODES: &[u32] = &[5, 13, 14];
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+
+    /// Unexpected HTTP status without an error description in the body.
+    Http(u16),
+
+    /// Error reported by the service.
+    Service { code: u32, message: String },
+
+    BadResponse(String),
+}
+
+impl Error {
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(_) => true,
+            &Error::Http(status) => status == 429 || status >= 500,
+            Error::Service { code, .. } => RETRY_CODES.contains(code),
+            Error::BadResponse(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Http(status) => write!(f, "unexpected HTTP status {}", status),
+            Error::Service { code, message } => write!(f, "{} (error {})", message, code),
+            Error::BadResponse(s) => write!(f, "bad response: {}", s),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+pub trait Transport {
+    /// Sends a POST request with a form encoded `body`.
+    fn post(&mut self, url: &str, body: &str) -> io::Result<Response>;
+}
+
+/// HTTP/1.1 transport over a new connection per request. `https://` URLs are requested over TLS
+/// with the Mozilla root certificates.
+#[derive(Clone, Debug)]
+pub struct HttpTransport {
+    pub timeout: Duration,
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn post(&mut self, url: &str, body: &str) -> io::Result<Response> {
+        if url.starts_with("https://") {
+            let (stream, host, path) = connect_url(url, "https://", 443, self.timeout)?;
+            post(&mut tls_connect(stream, host)?, host, path, body)
+        } else {
+            let (mut stream, host, path) = connect_http(url, self.timeout)?;
+            post(&mut stream, host, path, body)
+        }
+    }
+}
+
+fn post(stream: &mut impl ReadWrite, host: &str, path: &str, body: &str)
+    -> io::Result<Response>
+{
+    write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\
+        Content-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+        path, host, body.len(), body)?;
+    stream.flush()?;
+    let mut data = Vec::new();
+    match stream.read_to_end(&mut data) {
+        // Some servers close TLS connections without notifying, `parse_response()` catches
+        // truncated responses.
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !data.is_empty() => {}
+        r => {
+            r?;
+        }
+    }
+    parse_response(&data)
+}
+
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+fn tls_connect(stream: TcpStream, host: &str)
+    -> io::Result<StreamOwned<ClientConnection, TcpStream>>
+{
+    // Host without the port.
+    let name = match host.rfind(']') {
+        Some(i) => &host[1..i],
+        None => host.split(':').next().unwrap(),
+    };
+    let name = ServerName::try_from(name.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let conn = ClientConnection::new(Arc::new(config), name).map_err(io::Error::other)?;
+    Ok(StreamOwned::new(conn, stream))
+}
+
+fn parse_response(data: &[u8]) -> io::Result<Response> {
+    let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let head_len = data.windows(4).position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| bad("incomplete HTTP response"))?;
+    let head = String::from_utf8_lossy(&data[..head_len]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| bad("bad HTTP status line"))?;
+    let mut chunked = false;
+    let mut content_len = None;
+    for line in lines {
+        let mut kv = line.splitn(2, ':');
+        let name = kv.next().unwrap().trim().to_ascii_lowercase();
+        let value = kv.next().unwrap_or("").trim();
+        match name.as_str() {
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "content-length" => content_len = value.parse::<usize>().ok(),
+            _ => {}
+        }
+    }
+
+    let mut body = &data[head_len + 4..];
+    let body = if chunked {
+        let mut r = Vec::new();
+        loop {
+            let line_len = body.windows(2).position(|w| w == b"\r\n")
+                .ok_or_else(|| bad("truncated chunk"))?;
+            let size = String::from_utf8_lossy(&body[..line_len]);
+            let size = usize::from_str_radix(size.split(';').next().unwrap().trim(), 16)
+                .map_err(|_| bad("bad chunk size"))?;
+            body = &body[line_len + 2..];
+            if size == 0 {
+                break;
+            }
+            r.extend_from_slice(body.get(..size).ok_or_else(|| bad("truncated chunk"))?);
+            body = body.get(size + 2..).unwrap_or(&[]);
+        }
+        r
+    } else if let Some(len) = content_len {
+        body.get(..len).ok_or_else(|| bad("truncated body"))?.to_vec()
+    } else {
+        body.to_vec()
+    };
+    Ok(Response { status, body })
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Application API key.
+    pub api_key: String,
+
+    pub base_url: String,
+
+    pub max_requests_per_sec: u32,
+
+    /// Number of times a request is retried after a transient error.
+    pub max_retries: u32,
+
+    /// Delay before the first retry, doubled for each next one.
+    pub retry_delay: Duration,
+
+    /// Maximum number of fingerprints submitted in one request.
+    pub max_batch_len: usize,
+}
+
+impl ClientConfig {
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            api_key: api_key.into(),
+            base_url: "https://api.acoustid.org/v2".into(),
+            max_requests_per_sec: 3,
+            max_retries: 3,
+            retry_delay: Duration::from_secs(1),
+            max_batch_len: 50,
+        }
+    }
+}
+
+/// Compressed base64 fingerprint with the duration of the audio, as expected by the service.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Fingerprint {
+    pub fingerprint: String,
+
+    /// Duration in whole seconds.
+    pub duration: u32,
+}
+
+impl Fingerprint {
+    pub fn new(algorithm: Algorithm, fingerprint: &[u32], duration: f64) -> Self {
+        Self {
+            fingerprint: base64::encode(&compress(fingerprint, algorithm.id())),
+            duration: duration.round() as u32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LookupResult {
+    /// AcoustID track id.
+    pub id: String,
+    pub score: f64,
+    pub recordings: Vec<Recording>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    /// MusicBrainz recording id.
+    pub id: String,
+    pub title: Option<String>,
+
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    pub artists: Vec<Artist>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Artist {
+    /// MusicBrainz artist id.
+    pub id: String,
+    pub name: String,
+}
+
+/// Fingerprint with optional metadata to submit.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Submission {
+    pub fingerprint: String,
+    pub duration: u32,
+
+    /// MusicBrainz recording id.
+    pub mbid: Option<String>,
+    pub track: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub year: Option<u32>,
+    pub track_no: Option<u32>,
+    pub disc_no: Option<u32>,
+}
+
+impl Submission {
+    pub fn new(fingerprint: Fingerprint) -> Self {
+        Self {
+            fingerprint: fingerprint.fingerprint,
+            duration: fingerprint.duration,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SubmissionState {
+    Pending,
+
+    /// Imported with the AcoustID track id.
+    Imported(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubmissionStatus {
+    /// Index of the submission in the submitted slice.
+    pub index: usize,
+
+    /// Submission id to query the state later.
+    pub id: u64,
+    pub state: SubmissionState,
+}
+
+/// Client of the AcoustID web service. Requests are rate limited and retried on transient
+/// errors.
+pub struct Client<T: Transport = HttpTransport> {
+    transport: T,
+    config: ClientConfig,
+    last_request: Option<Instant>,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> Self {
+        Self::with_transport(config, HttpTransport::default())
+    }
+}
+
+impl<T: Transport> Client<T> {
+    pub fn with_transport(config: ClientConfig, transport: T) -> Self {
+        assert!(config.max_requests_per_sec > 0);
+        assert!(config.max_batch_len > 0);
+        Self {
+            transport,
+            config,
+            last_request: None,
+        }
+    }
+
+    /// Looks up recordings matching the fingerprint, best results first.
+    pub fn lookup(&mut self, fingerprint: &Fingerprint) -> Result<Vec<LookupResult>> {
+        let params = vec![
+            ("meta".into(), "recordings".into()),
+            ("duration".into(), fingerprint.duration.to_string()),
+            ("fingerprint".into(), fingerprint.fingerprint.clone()),
+        ];
+        let r = self.request("lookup", params, true)?;
+        let mut results = array(&r, "results")?.iter()
+            .map(parse_result)
+            .collect::<Result<Vec<_>>>()?;
+        results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        Ok(results)
+    }
+
+    /// Submits fingerprints on behalf of the user with the `user_key` API key, in batches of
+    /// at most `max_batch_len`. Transport errors aren't retried as the service may have received
+    /// the batch.
+    pub fn submit(&mut self, user_key: &str, submissions: &[Submission])
+        -> Result<Vec<SubmissionStatus>>
+    {
+        let mut statuses = Vec::with_capacity(submissions.len());
+        for (batch_i, batch) in submissions.chunks(self.config.max_batch_len).enumerate() {
+            let mut params = vec![("user".into(), user_key.into())];
+            for (i, s) in batch.iter().enumerate() {
+                let mut add = |name: &str, value: String| {
+                    params.push((format!("{}.{}", name, i), value));
+                };
+                add("duration", s.duration.to_string());
+                add("fingerprint", s.fingerprint.clone());
+                let strings = [
+                    ("mbid", &s.mbid),
+                    ("track", &s.track),
+                    ("artist", &s.artist),
+                    ("album", &s.album),
+                    ("albumartist", &s.album_artist),
+                ];
+                for &(name, value) in &strings {
+                    if let Some(v) = value {
+                        add(name, v.clone());
+                    }
+                }
+                let numbers = [("year", s.year), ("trackno", s.track_no), ("discno", s.disc_no)];
+                for &(name, value) in &numbers {
+                    if let Some(v) = value {
+                        add(name, v.to_string());
+                    }
+                }
+            }
+
+            let r = self.request("submit", params, false)?;
+            let start = batch_i * self.config.max_batch_len;
+            for s in array(&r, "submissions")? {
+                let mut status = parse_submission(s)?;
+                if status.index >= batch.len() {
+                    return Err(Error::BadResponse(format!("bad submission index {}",
+                        status.index)));
+                }
+                status.index += start;
+                statuses.push(status);
+            }
+        }
+        Ok(statuses)
+    }
+
+    /// Transport errors are only retried if `retry_io` is set.
+    fn request(&mut self, endpoint: &str, mut params: Vec<(String, String)>, retry_io: bool)
+        -> Result<Value>
+    {
+        params.push(("client".into(), self.config.api_key.clone()));
+        params.push(("format".into(), "json".into()));
+        let url = format!("{}/{}", self.config.base_url.trim_end_matches('/'), endpoint);
+        let body = params.iter()
+            .map(|(k, v)| format!("{}={}", url_encode(k), url_encode(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut retry_delay = self.config.retry_delay;
+        let mut retries = 0;
+        loop {
+            self.wait();
+            let r = self.transport.post(&url, &body)
+                .map_err(Error::from)
+                .and_then(|r| parse_body(&r));
+            match r {
+                Err(e) if e.is_transient() && (retry_io || !matches!(e, Error::Io(_))) &&
+                    retries < self.config.max_retries =>
+                {
+                    retries += 1;
+                    thread::sleep(retry_delay);
+                    retry_delay *= 2;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Waits until the next request is allowed by the rate limit.
+    fn wait(&mut self) {
+        let interval = Duration::from_secs(1) / self.config.max_requests_per_sec;
+        if let Some(last) = self.last_request {
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                thread::sleep(interval - elapsed);
+            }
+        }
+        self.last_request = Some(Instant::now());
+    }
+}
+
+fn parse_body(response: &Response) -> Result<Value> {
+    let v: Option<Value> = serde_json::from_slice(&response.body).ok();
+    match v {
+        Some(v) if v["status"] == "ok" && response.status == 200 => Ok(v),
+        Some(v) if v["status"] == "error" => {
+            let e = &v["error"];
+            Err(Error::Service {
+                code: e["code"].as_u64().unwrap_or(0) as u32,
+                message: e["message"].as_str().unwrap_or("unknown error").into(),
+            })
+        }
+        _ if response.status != 200 => Err(Error::Http(response.status)),
+        _ => Err(Error::BadResponse("expected a JSON object with status".into())),
+    }
+}
+
+fn array<'a>(v: &'a Value, name: &str) -> Result<&'a [Value]> {
+    match &v[name] {
+        Value::Array(v) => Ok(v),
+        Value::Null => Ok(&[]),
+        _ => Err(Error::BadResponse(format!("{} is not an array", name))),
+    }
+}
+
+fn string(v: &Value, name: &str) -> Result<String> {
+    v[name].as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| Error::BadResponse(format!("missing {}", name)))
+}
+
+fn parse_result(v: &Value) -> Result<LookupResult> {
+    Ok(LookupResult {
+        id: string(v, "id")?,
+        score: v["score"].as_f64().ok_or_else(|| Error::BadResponse("missing score".into()))?,
+        recordings: array(v, "recordings")?.iter()
+            .map(|r| Ok(Recording {
+                id: string(r, "id")?,
+                title: r["title"].as_str().map(|s| s.to_string()),
+                duration: r["duration"].as_f64(),
+                artists: array(r, "artists")?.iter()
+                    .map(|a| Ok(Artist {
+                        id: string(a, "id")?,
+                        name: string(a, "name")?,
+                    }))
+                    .collect::<Result<_>>()?,
+            }))
+            .collect::<Result<_>>()?,
+    })
+}
+
+fn parse_submission(v: &Value) -> Result<SubmissionStatus> {
+    let number = |name: &str| v[name].as_u64()
+        .ok_or_else(|| Error::BadResponse(format!("missing {}", name)));
+    let state = match v["status"].as_str() {
+        Some("pending") => SubmissionState::Pending,
+        Some("imported") => SubmissionState::Imported(string(&v["result"], "id")?),
+        _ => return Err(Error::BadResponse(format!("bad submission status {}", v["status"]))),
+    };
+    Ok(SubmissionStatus {
+        index: number("index")? as usize,
+        id: number("id")?,
+        state,
+    })
+}
+
+fn url_encode(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' =>
+                r.push(b as char),
+            _ => r.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use crate::fingerprint::decompress;
+
+    struct Request {
+        path: String,
+        params: HashMap<String, String>,
+    }
+
+    /// HTTP server responding with the given responses in order, one per connection. Connections
+    /// with status `0` are closed without a response.
+    struct StubServer {
+        url: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl StubServer {
+        fn new(responses: Vec<(u16, String)>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/v2", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let thread = {
+                let requests = requests.clone();
+                thread::spawn(move || {
+                    for (status, body) in responses {
+                        let (mut s, _) = listener.accept().unwrap();
+                        requests.lock().unwrap().push(read_request(&mut s));
+                        if status == 0 {
+                            continue;
+                        }
+                        write!(s, "HTTP/1.1 {} X\r\nContent-Type: application/json\r\n\
+                            Content-Length: {}\r\n\r\n{}", status, body.len(), body).unwrap();
+                    }
+                })
+            };
+            Self {
+                url,
+                requests,
+                thread: Some(thread),
+            }
+        }
+
+        fn client(&self) -> Client {
+            Client::new(ClientConfig {
+                base_url: self.url.clone(),
+                max_requests_per_sec: 100,
+                retry_delay: Duration::from_millis(1),
+                ..ClientConfig::new("key")
+            })
+        }
+
+        /// Waits for all responses to be sent and returns the requests.
+        fn finish(mut self) -> Vec<Request> {
+            self.thread.take().unwrap().join().unwrap();
+            self.requests.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    fn read_request(s: &mut TcpStream) -> Request {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        let (head_len, content_len) = loop {
+            let n = s.read(&mut buf).unwrap();
+            data.extend_from_slice(&buf[..n]);
+            if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8(data[..i].to_vec()).unwrap();
+                let len = head.lines()
+                    .find_map(|l| l.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                break (i + 4, len);
+            }
+        };
+        while data.len() < head_len + content_len {
+            let n = s.read(&mut buf).unwrap();
+            data.extend_from_slice(&buf[..n]);
+        }
+        let head = String::from_utf8(data[..head_len].to_vec()).unwrap();
+        let body = String::from_utf8(data[head_len..].to_vec()).unwrap();
+        Request {
+            path: head.split(' ').nth(1).unwrap().into(),
+            params: body.split('&')
+                .map(|p| {
+                    let mut kv = p.splitn(2, '=');
+                    (url_decode(kv.next().unwrap()), url_decode(kv.next().unwrap()))
+                })
+                .collect(),
+        }
+    }
+
+    fn url_decode(s: &str) -> String {
+        let mut r = Vec::new();
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            if b == b'%' {
+                let hex: String = bytes.by_ref().take(2).map(|b| b as char).collect();
+                r.push(u8::from_str_radix(&hex, 16).unwrap());
+            } else {
+                r.push(b);
+            }
+        }
+        String::from_utf8(r).unwrap()
+    }
+
+    fn ok(body: Value) -> (u16, String) {
+        (200, body.to_string())
+    }
+
+    fn error(status: u16, code: u32) -> (u16, String) {
+        (status, json!({
+            "status": "error",
+            "error": { "code": code, "message": "oops" },
+        }).to_string())
+    }
+
+    #[test]
+    fn lookup() {
+        let s = StubServer::new(vec![ok(json!({
+            "status": "ok",
+            "results": [{
+                "id": "t1",
+                "score": 0.5,
+            }, {
+                "id": "t2",
+                "score": 0.9,
+                "recordings": [{
+                    "id": "r1",
+                    "title": "Song & Dance",
+                    "duration": 180,
+                    "artists": [{ "id": "a1", "name": "Artist" }],
+                }, {
+                    "id": "r2",
+                }],
+            }],
+        }))]);
+        let fp = &[1, 2, 3, 0xffff_ffff];
+        let fingerprint = Fingerprint::new(Algorithm::Test2, fp, 179.6);
+        let results = s.client().lookup(&fingerprint).unwrap();
+        assert_eq!(results, vec![LookupResult {
+            id: "t2".into(),
+            score: 0.9,
+            recordings: vec![Recording {
+                id: "r1".into(),
+                title: Some("Song & Dance".into()),
+                duration: Some(180.0),
+                artists: vec![Artist {
+                    id: "a1".into(),
+                    name: "Artist".into(),
+                }],
+            }, Recording {
+                id: "r2".into(),
+                title: None,
+                duration: None,
+                artists: vec![],
+            }],
+        }, LookupResult {
+            id: "t1".into(),
+            score: 0.5,
+            recordings: vec![],
+        }]);
+
+        let requests = s.finish();
+        assert_eq!(requests.len(), 1);
+        let r = &requests[0];
+        assert_eq!(r.path, "/v2/lookup");
+        assert_eq!(r.params["client"], "key");
+        assert_eq!(r.params["format"], "json");
+        assert_eq!(r.params["meta"], "recordings");
+        assert_eq!(r.params["duration"], "180");
+        let compressed = base64::decode(r.params["fingerprint"].as_bytes()).unwrap();
+        assert_eq!(decompress(&compressed), Some((fp.to_vec(), 1)));
+    }
+
+    #[test]
+    fn submit() {
+        let s = StubServer::new(vec![
+            ok(json!({
+                "status": "ok",
+                "submissions": [
+                    { "index": 1, "id": 11, "status": "pending" },
+                    { "index": 0, "id": 10, "status": "imported", "result": { "id": "t1" } },
+                ],
+            })),
+            ok(json!({
+                "status": "ok",
+                "submissions": [{ "index": 0, "id": 12, "status": "pending" }],
+            })),
+        ]);
+        let submissions: Vec<_> = (0..3)
+            .map(|i| Submission {
+                mbid: if i == 1 { Some("m1".into()) } else { None },
+                year: Some(2000 + i),
+                ..Submission::new(Fingerprint {
+                    fingerprint: format!("fp{}", i),
+                    duration: 100 + i,
+                })
+            })
+            .collect();
+        let mut client = Client::with_transport(ClientConfig {
+            max_batch_len: 2,
+            ..s.client().config
+        }, HttpTransport::default());
+        assert_eq!(client.submit("user key", &submissions).unwrap(), vec![
+            SubmissionStatus { index: 1, id: 11, state: SubmissionState::Pending },
+            SubmissionStatus { index: 0, id: 10, state: SubmissionState::Imported("t1".into()) },
+            SubmissionStatus { index: 2, id: 12, state: SubmissionState::Pending },
+        ]);
+
+        let requests = s.finish();
+        assert_eq!(requests.len(), 2);
+        let p = &requests[0].params;
+        assert_eq!(requests[0].path, "/v2/submit");
+        assert_eq!(p["user"], "user key");
+        assert_eq!(p["client"], "key");
+        assert_eq!((&p["fingerprint.0"][..], &p["duration.0"][..]), ("fp0", "100"));
+        assert_eq!((&p["fingerprint.1"][..], &p["duration.1"][..]), ("fp1", "101"));
+        assert_eq!((p.get("mbid.0"), &p["mbid.1"][..]), (None, "m1"));
+        assert_eq!(p["year.1"], "2001");
+        assert_eq!(p.get("fingerprint.2"), None);
+        let p = &requests[1].params;
+        assert_eq!((&p["fingerprint.0"][..], &p["year.0"][..]), ("fp2", "2002"));
+    }
+
+    #[test]
+    fn retry() {
+        let s = StubServer::new(vec![
+            (503, "Service Unavailable".into()),
+            error(400, 14),
+            ok(json!({ "status": "ok", "results": [] })),
+            error(400, 3),
+            error(500, 5),
+            error(500, 5),
+            error(500, 5),
+        ]);
+        let mut client = Client::with_transport(ClientConfig {
+            max_retries: 2,
+            ..s.client().config
+        }, HttpTransport::default());
+        let fp = &Fingerprint::new(Algorithm::Test2, &[1], 1.0);
+        assert_eq!(client.lookup(fp).unwrap(), vec![]);
+
+        // Not retried.
+        match client.lookup(fp) {
+            Err(Error::Service { code: 3, message }) => assert_eq!(message, "oops"),
+            r => panic!("{:?}", r),
+        }
+
+        // Retried until giving up.
+        match client.lookup(fp) {
+            Err(Error::Service { code: 5, .. }) => {}
+            r => panic!("{:?}", r),
+        }
+
+        assert_eq!(s.finish().len(), 7);
+    }
+
+    #[test]
+    fn retry_io() {
+        let s = StubServer::new(vec![
+            (0, String::new()),
+            ok(json!({ "status": "ok", "results": [] })),
+            (0, String::new()),
+        ]);
+        let mut client = s.client();
+        let fp = &Fingerprint::new(Algorithm::Test2, &[1], 1.0);
+        assert_eq!(client.lookup(fp).unwrap(), vec![]);
+
+        let submission = Submission::new(fp.clone());
+        match client.submit("user key", &[submission]) {
+            Err(Error::Io(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(s.finish().len(), 3);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let s = StubServer::new(vec![ok(json!({ "status": "ok" })); 4]);
+        let mut client = Client::with_transport(ClientConfig {
+            max_requests_per_sec: 3,
+            ..s.client().config
+        }, HttpTransport::default());
+        let fp = &Fingerprint::new(Algorithm::Test2, &[1], 1.0);
+        let start = Instant::now();
+        for _ in 0..4 {
+            client.lookup(fp).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        s.finish();
+    }
+
+    #[test]
+    fn parse_response_() {
+        let r = parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n").unwrap();
+        assert_eq!(r, Response { status: 200, body: b"abcde".to_vec() });
+
+        let r = parse_response(b"HTTP/1.0 404 Not Found\r\ncontent-length: 2\r\n\r\nabc")
+            .unwrap();
+        assert_eq!(r, Response { status: 404, body: b"ab".to_vec() });
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nabc").is_err());
+    }
+
+    #[test]
+    fn transport_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v2", listener.local_addr().unwrap());
+        drop(listener);
+        let mut client = Client::new(ClientConfig {
+            base_url: url,
+            max_retries: 1,
+            retry_delay: Duration::from_millis(1),
+            ..ClientConfig::new("key")
+        });
+        let fp = &Fingerprint::new(Algorithm::Test2, &[1], 1.0);
+        match client.lookup(fp) {
+            Err(Error::Io(_)) => {}
+            r => panic!("{:?}", r),
+        }
+
+        match HttpTransport::default().post("ftp://api.acoustid.org/v2/lookup", "") {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn https() {
+        assert!(ClientConfig::new("key").base_url.starts_with("https://"));
+
+        // A plain HTTP server fails the TLS handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}/v2/lookup", listener.local_addr().unwrap());
+        let thread = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let _ = s.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+        });
+        match HttpTransport::default().post(&url, "") {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", e),
+            r => panic!("{:?}", r),
+        }
+        thread.join().unwrap();
+    }
+}
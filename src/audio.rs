@@ -1,7 +1,18 @@
+pub mod agc;
+pub mod biquad;
+pub mod dc_removal;
+pub mod denoise;
 pub mod downmix;
 pub mod fft;
+pub mod pre_emphasis;
 pub mod resample;
+pub mod wav;
 
+pub use agc::Agc;
+pub use biquad::Biquad;
+pub use dc_removal::DcRemoval;
+pub use denoise::{NoiseEstimator, NoiseProfile, SpectralSubtract};
 pub use downmix::Downmix;
 pub use fft::FFT;
+pub use pre_emphasis::PreEmphasis;
 pub use resample::Resample;
@@ -0,0 +1,100 @@
+use crate::pipeline::Inplace;
+use crate::util::saturate_i16;
+
+/// Automatic gain control.
+/// Tracks the signal envelope with separate attack and release times and scales the signal so
+/// the envelope approaches `target` (a fraction of full scale). The gain never exceeds
+/// `max_gain`, so silence and noise floors aren't blown up.
+/// Keeps a single state, so it expects mono input, see `FingerprinterOptions::preprocess`.
+pub struct Agc {
+    target: f64,
+    max_gain: f64,
+    attack: f64,
+    release: f64,
+    envelope: f64,
+}
+
+impl Agc {
+    pub fn new(sample_rate: u32, target: f64, max_gain: f64, attack_secs: f64, release_secs: f64)
+        -> Self
+    {
+        assert!(target > 0.0 && target <= 1.0);
+        assert!(max_gain >= 1.0);
+        Self {
+            target: target * i16::MAX as f64,
+            max_gain,
+            attack: smoothing_coef(sample_rate, attack_secs),
+            release: smoothing_coef(sample_rate, release_secs),
+            envelope: 0.0,
+        }
+    }
+
+    fn gain(&self) -> f64 {
+        if self.envelope * self.max_gain <= self.target {
+            self.max_gain
+        } else {
+            self.target / self.envelope
+        }
+    }
+}
+
+impl Inplace<i16> for Agc {
+    fn process(&mut self, in_out: &mut [i16]) {
+        for v in in_out.iter_mut() {
+            let x = *v as f64;
+            let level = x.abs();
+            let coef = if level > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = coef * self.envelope + (1.0 - coef) * level;
+            *v = saturate_i16(x * self.gain());
+        }
+    }
+}
+
+fn smoothing_coef(sample_rate: u32, secs: f64) -> f64 {
+    assert!(secs >= 0.0);
+    if secs == 0.0 {
+        0.0
+    } else {
+        (-1.0 / (secs * sample_rate as f64)).exp()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn sine(amplitude: f64, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|i| (amplitude * (2.0 * PI * 440.0 * i as f64 / 8000.0).sin()) as i16)
+            .collect()
+    }
+
+    fn peak(buf: &[i16]) -> i32 {
+        buf.iter().map(|&v| (v as i32).abs()).max().unwrap()
+    }
+
+    #[test]
+    fn test() {
+        for &amplitude in &[1000.0, 30000.0] {
+            let agc = &mut Agc::new(8000, 0.5, 100.0, 0.01, 0.1);
+            let buf = &mut sine(amplitude, 16000);
+            agc.process(buf);
+
+            let p = peak(&buf[8000..]);
+            assert!(p > 16000 && p < 32767, "{} {}", amplitude, p);
+        }
+    }
+
+    #[test]
+    fn max_gain() {
+        let agc = &mut Agc::new(8000, 0.5, 4.0, 0.01, 0.1);
+        let buf = &mut sine(100.0, 8000);
+        agc.process(buf);
+        assert!(peak(&buf[4000..]) <= 400);
+    }
+}
@@ -0,0 +1,109 @@
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+
+use crate::pipeline::Inplace;
+use crate::util::saturate_i16;
+
+/// Second order IIR filter with coefficients from the RBJ audio EQ cookbook.
+/// Keeps a single state, so it expects mono input, see `FingerprinterOptions::preprocess`.
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    /// Q giving the maximally flat (Butterworth) response.
+    pub const BUTTERWORTH_Q: f64 = FRAC_1_SQRT_2;
+
+    pub fn high_pass(sample_rate: u32, cutoff: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::params(sample_rate, cutoff, q);
+        Self::new(
+            (1.0 + cos) / 2.0,
+            -(1.0 + cos),
+            (1.0 + cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha)
+    }
+
+    pub fn low_pass(sample_rate: u32, cutoff: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::params(sample_rate, cutoff, q);
+        Self::new(
+            (1.0 - cos) / 2.0,
+            1.0 - cos,
+            (1.0 - cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha)
+    }
+
+    fn new(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn params(sample_rate: u32, cutoff: f64, q: f64) -> (f64, f64) {
+        assert!(cutoff > 0.0 && cutoff < sample_rate as f64 / 2.0);
+        assert!(q > 0.0);
+        let w0 = 2.0 * PI * cutoff / sample_rate as f64;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+}
+
+impl Inplace<i16> for Biquad {
+    fn process(&mut self, in_out: &mut [i16]) {
+        for v in in_out.iter_mut() {
+            let x = *v as f64;
+            let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+                - self.a1 * self.y1 - self.a2 * self.y2;
+            self.x2 = self.x1;
+            self.x1 = x;
+            self.y2 = self.y1;
+            self.y1 = y;
+            *v = saturate_i16(y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peak_after_settling(mut f: Biquad, freq: f64) -> i16 {
+        const SAMPLE_RATE: u32 = 8000;
+        let buf = &mut (0..SAMPLE_RATE)
+            .map(|i| (10000.0 * (2.0 * PI * freq * i as f64 / SAMPLE_RATE as f64).sin()) as i16)
+            .collect::<Vec<_>>();
+        f.process(buf);
+        buf[SAMPLE_RATE as usize / 2..].iter().map(|v| v.abs()).max().unwrap()
+    }
+
+    #[test]
+    fn high_pass() {
+        let f = || Biquad::high_pass(8000, 500.0, Biquad::BUTTERWORTH_Q);
+        assert!(peak_after_settling(f(), 50.0) < 200);
+        assert!(peak_after_settling(f(), 2000.0) > 9500);
+    }
+
+    #[test]
+    fn low_pass() {
+        let f = || Biquad::low_pass(8000, 500.0, Biquad::BUTTERWORTH_Q);
+        assert!(peak_after_settling(f(), 50.0) > 9500);
+        assert!(peak_after_settling(f(), 3000.0) < 200);
+    }
+}
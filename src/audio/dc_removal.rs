@@ -0,0 +1,60 @@
+use crate::pipeline::Inplace;
+use crate::util::saturate_i16;
+
+/// Removes the DC offset with a one-pole high-pass filter:
+/// `y[n] = x[n] - x[n - 1] + pole * y[n - 1]`.
+/// Keeps a single state, so it expects mono input, see `FingerprinterOptions::preprocess`.
+pub struct DcRemoval {
+    pole: f64,
+    last_in: f64,
+    last_out: f64,
+}
+
+impl DcRemoval {
+    pub const DEFAULT_POLE: f64 = 0.995;
+
+    pub fn new(pole: f64) -> Self {
+        assert!(pole > 0.0 && pole < 1.0);
+        Self {
+            pole,
+            last_in: 0.0,
+            last_out: 0.0,
+        }
+    }
+}
+
+impl Default for DcRemoval {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_POLE)
+    }
+}
+
+impl Inplace<i16> for DcRemoval {
+    fn process(&mut self, in_out: &mut [i16]) {
+        for v in in_out.iter_mut() {
+            let x = *v as f64;
+            let y = x - self.last_in + self.pole * self.last_out;
+            self.last_in = x;
+            self.last_out = y;
+            *v = saturate_i16(y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let f = &mut DcRemoval::default();
+
+        let buf = &mut vec![5000; 4000];
+        for chunk in buf.chunks_mut(1000) {
+            f.process(chunk);
+        }
+
+        assert_eq!(buf[0], 5000);
+        assert!(buf[3999].abs() <= 1, "{}", buf[3999]);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::pipeline::Inplace;
+
+/// Noise power spectrum in the same bin layout as the `FFT` output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoiseProfile {
+    power: Vec<f64>,
+}
+
+impl NoiseProfile {
+    pub fn new(power: Vec<f64>) -> Self {
+        assert!(!power.is_empty());
+        Self {
+            power,
+        }
+    }
+
+    pub fn power(&self) -> &[f64] {
+        &self.power
+    }
+}
+
+/// Learns a `NoiseProfile` from the quietest frames that pass through it.
+/// Frames are left untouched, so the estimator can sit anywhere after `FFT` in a pipeline.
+pub struct NoiseEstimator {
+    frame_count: usize,
+
+    /// Up to `frame_count` quietest frames seen so far along with their total energy.
+    frames: Vec<(f64, Vec<f64>)>,
+}
+
+impl NoiseEstimator {
+    pub fn new(frame_count: usize) -> Self {
+        assert!(frame_count > 0);
+        Self {
+            frame_count,
+            frames: Vec::with_capacity(frame_count),
+        }
+    }
+
+    /// Averages the quietest frames seen so far. Returns `None` if no frames have been seen.
+    pub fn profile(&self) -> Option<NoiseProfile> {
+        let (_, first) = self.frames.first()?;
+        let mut power = vec![0.0; first.len()];
+        for (_, frame) in &self.frames {
+            for (p, v) in power.iter_mut().zip(frame.iter()) {
+                *p += v;
+            }
+        }
+        let n = self.frames.len() as f64;
+        for p in power.iter_mut() {
+            *p /= n;
+        }
+        Some(NoiseProfile::new(power))
+    }
+
+    fn loudest(&self) -> Option<usize> {
+        self.frames.iter()
+            .enumerate()
+            .max_by(|(_, (a, _)), (_, (b, _))| a.total_cmp(b))
+            .map(|(i, _)| i)
+    }
+}
+
+impl Inplace<f64> for NoiseEstimator {
+    fn process(&mut self, in_out: &mut [f64]) {
+        let energy: f64 = in_out.iter().sum();
+        // Frames with NaN or infinite power would poison the profile.
+        if !energy.is_finite() {
+            return;
+        }
+        if self.frames.len() < self.frame_count {
+            self.frames.push((energy, in_out.to_vec()));
+            return;
+        }
+        let i = self.loudest().unwrap();
+        let (loudest_energy, frame) = &mut self.frames[i];
+        if energy < *loudest_energy {
+            *loudest_energy = energy;
+            frame.clear();
+            frame.extend_from_slice(in_out);
+        }
+    }
+}
+
+/// Subtracts the noise power from each `FFT` power frame.
+/// Every bin is reduced by `over_subtraction` times the noise power in that bin but is never
+/// pushed below `floor` times its original value, which keeps musical noise down.
+pub struct SpectralSubtract {
+    profile: NoiseProfile,
+    over_subtraction: f64,
+    floor: f64,
+}
+
+impl SpectralSubtract {
+    pub fn new(profile: NoiseProfile, over_subtraction: f64, floor: f64) -> Self {
+        assert!(over_subtraction >= 0.0);
+        assert!((0.0..=1.0).contains(&floor));
+        Self {
+            profile,
+            over_subtraction,
+            floor,
+        }
+    }
+
+    pub fn profile(&self) -> &NoiseProfile {
+        &self.profile
+    }
+}
+
+impl Inplace<f64> for SpectralSubtract {
+    fn process(&mut self, in_out: &mut [f64]) {
+        assert_eq!(in_out.len(), self.profile.power.len());
+        for (v, &noise) in in_out.iter_mut().zip(self.profile.power.iter()) {
+            let floor = *v * self.floor;
+            *v -= noise * self.over_subtraction;
+            if *v < floor {
+                *v = floor;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn estimator() {
+        let e = &mut NoiseEstimator::new(2);
+        assert_eq!(e.profile(), None);
+
+        for frame in &[
+            [5.0, 5.0, 5.0],
+            [1.0, 2.0, 3.0],
+            [9.0, 9.0, 9.0],
+            [3.0, 2.0, 1.0],
+            [4.0, 4.0, 4.0],
+        ] {
+            let buf = &mut frame.clone();
+            e.process(buf);
+            assert_eq!(buf, frame);
+        }
+
+        assert_eq!(e.profile(), Some(NoiseProfile::new(vec![2.0, 2.0, 2.0])));
+
+        let e = &mut NoiseEstimator::new(1);
+        e.process(&mut [f64::NAN, 1.0]);
+        e.process(&mut [2.0, 3.0]);
+        e.process(&mut [f64::NAN, 0.0]);
+        e.process(&mut [f64::NEG_INFINITY, 0.0]);
+        e.process(&mut [-f64::NAN, 0.0]);
+        assert_eq!(e.profile(), Some(NoiseProfile::new(vec![2.0, 3.0])));
+    }
+
+    #[test]
+    fn subtract() {
+        let s = &mut SpectralSubtract::new(NoiseProfile::new(vec![1.0, 1.0, 2.0, 0.0]), 2.0, 0.1);
+
+        let buf = &mut [10.0, 2.0, 3.0, 5.0];
+        s.process(buf);
+
+        for (a, e) in buf.iter().zip(&[8.0, 0.2, 0.3, 5.0]) {
+            assert_abs_diff_eq!(a, e, epsilon = 1e-10);
+        }
+    }
+
+    #[test]
+    fn noisy_matches_clean() {
+        let clean = &[0.0, 0.0, 4.0, 0.0, 1.0, 0.0];
+        let noise = &[0.5, 0.3, 0.2, 0.3, 0.1, 0.4];
+
+        let e = &mut NoiseEstimator::new(3);
+        for _ in 0..3 {
+            e.process(&mut noise.clone());
+        }
+        let s = &mut SpectralSubtract::new(e.profile().unwrap(), 1.0, 0.0);
+
+        let buf = &mut [0.0; 6];
+        for (b, (c, n)) in buf.iter_mut().zip(clean.iter().zip(noise)) {
+            *b = c + n;
+        }
+        s.process(buf);
+
+        for (a, e) in buf.iter().zip(clean) {
+            assert_abs_diff_eq!(a, e, epsilon = 1e-10);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::pipeline::Inplace;
+use crate::util::saturate_i16;
+
+/// First order pre-emphasis filter: `y[n] = x[n] - coef * x[n - 1]`.
+/// Keeps a single state, so it expects mono input, see `FingerprinterOptions::preprocess`.
+pub struct PreEmphasis {
+    coef: f64,
+    last: f64,
+}
+
+impl PreEmphasis {
+    pub const DEFAULT_COEF: f64 = 0.97;
+
+    pub fn new(coef: f64) -> Self {
+        assert!((0.0..1.0).contains(&coef));
+        Self {
+            coef,
+            last: 0.0,
+        }
+    }
+}
+
+impl Default for PreEmphasis {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_COEF)
+    }
+}
+
+impl Inplace<i16> for PreEmphasis {
+    fn process(&mut self, in_out: &mut [i16]) {
+        for v in in_out.iter_mut() {
+            let x = *v as f64;
+            *v = saturate_i16(x - self.coef * self.last);
+            self.last = x;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let f = &mut PreEmphasis::new(0.5);
+
+        let buf = &mut [100, 100, -100];
+        f.process(&mut buf[..1]);
+        f.process(&mut buf[1..]);
+        assert_eq!(buf, &[100, 50, -150]);
+
+        let buf = &mut [-32768, 32767];
+        PreEmphasis::new(0.9).process(buf);
+        assert_eq!(buf, &[-32768, 32767]);
+    }
+}
@@ -0,0 +1,188 @@
+use std::error;
+use std::fmt;
+
+const PCM: u16 = 1;
+const EXTENSIBLE: u16 = 0xfffe;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    UnexpectedEof,
+    NotWav,
+    MissingChunk(&'static str),
+    UnsupportedFormat { format: u16, bits_per_sample: u16 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnexpectedEof => write!(f, "unexpected end of WAV data"),
+            Error::NotWav => write!(f, "not a WAV file"),
+            Error::MissingChunk(id) => write!(f, "missing WAV {} chunk", id),
+            Error::UnsupportedFormat { format, bits_per_sample } =>
+                write!(f, "unsupported WAV format {} with {} bits per sample", format,
+                    bits_per_sample),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Wav {
+    pub sample_rate: u32,
+    pub channel_count: u32,
+
+    /// Interleaved samples.
+    pub samples: Vec<i16>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub sample_rate: u32,
+    pub channel_count: u32,
+
+    /// Offset of the samples in the file.
+    pub data_offset: usize,
+
+    /// Length of the samples in bytes as declared in the file.
+    pub data_len: usize,
+}
+
+/// Reads the header of a RIFF WAVE file with 16-bit PCM samples, up to the start of the samples.
+pub fn read_header(data: &[u8]) -> Result<Header> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(Error::NotWav);
+    }
+    let mut fmt = None;
+    let mut offset = 12;
+    while data.len() >= offset + 8 {
+        let chunk = &data[offset..];
+        let id = &chunk[..4];
+        let len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        let body = &chunk[8..];
+        match id {
+            b"fmt " => {
+                let body = body.get(..len).ok_or(Error::UnexpectedEof)?;
+                if body.len() < 16 {
+                    return Err(Error::UnexpectedEof);
+                }
+                let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+                let format = u16_at(0);
+                let channel_count = u16_at(2) as u32;
+                let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                let bits_per_sample = u16_at(14);
+                if (format != PCM && format != EXTENSIBLE) || bits_per_sample != 16 ||
+                    channel_count == 0 || sample_rate == 0
+                {
+                    return Err(Error::UnsupportedFormat { format, bits_per_sample });
+                }
+                fmt = Some((sample_rate, channel_count));
+            }
+            b"data" => {
+                let (sample_rate, channel_count) = fmt.ok_or(Error::MissingChunk("fmt"))?;
+                return Ok(Header {
+                    sample_rate,
+                    channel_count,
+                    data_offset: offset + 8,
+                    data_len: len,
+                });
+            }
+            _ => {}
+        }
+        // Chunks are padded to even length.
+        offset = offset.saturating_add(len.saturating_add(len & 1).saturating_add(8));
+    }
+    Err(Error::MissingChunk("data"))
+}
+
+/// Reads a RIFF WAVE file with 16-bit PCM samples. A truncated data chunk is read up to the
+/// last whole sample, as written by recorders that were interrupted.
+pub fn read(data: &[u8]) -> Result<Wav> {
+    let header = read_header(data)?;
+    let body = &data[header.data_offset..];
+    let body = &body[..header.data_len.min(body.len())];
+    let frame_len = 2 * header.channel_count as usize;
+    let body = &body[..body.len() / frame_len * frame_len];
+    Ok(Wav {
+        sample_rate: header.sample_rate,
+        channel_count: header.channel_count,
+        samples: body.chunks_exact(2)
+            .map(|v| i16::from_le_bytes([v[0], v[1]]))
+            .collect(),
+    })
+}
+
+/// Writes a RIFF WAVE file with 16-bit PCM samples.
+pub fn write(sample_rate: u32, channel_count: u32, samples: &[i16]) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+    let mut r = Vec::with_capacity(44 + data_len as usize);
+    r.extend_from_slice(b"RIFF");
+    r.extend_from_slice(&(36 + data_len).to_le_bytes());
+    r.extend_from_slice(b"WAVEfmt ");
+    r.extend_from_slice(&16u32.to_le_bytes());
+    r.extend_from_slice(&PCM.to_le_bytes());
+    r.extend_from_slice(&(channel_count as u16).to_le_bytes());
+    r.extend_from_slice(&sample_rate.to_le_bytes());
+    r.extend_from_slice(&(sample_rate * channel_count * 2).to_le_bytes());
+    r.extend_from_slice(&(channel_count as u16 * 2).to_le_bytes());
+    r.extend_from_slice(&16u16.to_le_bytes());
+    r.extend_from_slice(b"data");
+    r.extend_from_slice(&data_len.to_le_bytes());
+    for v in samples {
+        r.extend_from_slice(&v.to_le_bytes());
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let samples = &[1, -2, 3, -4, i16::MAX, i16::MIN];
+        let data = &write(44100, 2, samples);
+        assert_eq!(data.len(), 44 + 12);
+        let exp = Wav {
+            sample_rate: 44100,
+            channel_count: 2,
+            samples: samples.to_vec(),
+        };
+        assert_eq!(read(data), Ok(exp.clone()));
+
+        // Unknown chunk of odd length before the data.
+        let mut with_list = data[..36].to_vec();
+        with_list.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        with_list.extend_from_slice(&data[36..]);
+        assert_eq!(read(&with_list), Ok(exp));
+
+        assert_eq!(read_header(data), Ok(Header {
+            sample_rate: 44100,
+            channel_count: 2,
+            data_offset: 44,
+            data_len: 12,
+        }));
+
+        // Truncated in the middle of a frame.
+        let wav = read(&data[..data.len() - 3]).unwrap();
+        assert_eq!(wav.samples, &samples[..4]);
+    }
+
+    #[test]
+    fn invalid() {
+        let data = &write(8000, 1, &[1, 2]);
+        assert_eq!(read(&data[..10]), Err(Error::NotWav));
+        assert_eq!(read(&data[..36]), Err(Error::MissingChunk("data")));
+
+        let mut no_fmt = data[..12].to_vec();
+        no_fmt.extend_from_slice(&data[36..]);
+        assert_eq!(read(&no_fmt), Err(Error::MissingChunk("fmt")));
+
+        let mut float = data.clone();
+        float[20] = 3;
+        float[34] = 32;
+        assert_eq!(read(&float), Err(Error::UnsupportedFormat { format: 3, bits_per_sample: 32 }));
+    }
+}
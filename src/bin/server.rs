@@ -0,0 +1,55 @@
+use chromaprinter::{Server, ServerConfig};
+use std::env;
+use std::process;
+use std::str::FromStr;
+
+const USAGE: &str = "\
+Usage: chromaprinter-server [options]
+
+Options:
+    --addr ADDR             address to listen on [127.0.0.1:8080]
+    --index DIR             index directory [index]
+    --max-concurrency N     maximum number of requests handled at once [4]
+    --max-body-len BYTES    maximum request body size [67108864]
+    --max-results N         maximum number of lookup results [10]";
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+fn parse<T: FromStr>(name: &str, value: Option<String>) -> T {
+    value.and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| fail(&format!("bad or missing value of {}", name)))
+}
+
+fn main() {
+    let mut config = ServerConfig::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => config.addr = parse(&arg, args.next()),
+            "--index" => config.index_dir = parse::<String>(&arg, args.next()).into(),
+            "--max-concurrency" => config.max_concurrency = parse(&arg, args.next()),
+            "--max-body-len" => config.max_body_len = parse(&arg, args.next()),
+            "--max-results" => config.max_results = parse(&arg, args.next()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => fail(&format!("unknown option {}", arg)),
+        }
+    }
+    if config.max_concurrency == 0 {
+        fail("--max-concurrency must be positive");
+    }
+
+    let server = Server::new(config).unwrap_or_else(|e| {
+        eprintln!("failed to start: {}", e);
+        process::exit(1);
+    });
+    if let Some(addr) = server.addr() {
+        eprintln!("listening on {}", addr);
+    }
+    server.run();
+}
@@ -0,0 +1,320 @@
+use crate::{fingerprint, Algorithm};
+use crate::index::Search;
+use crate::util::hamming_distance;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundaryConfig {
+    /// Number of items in the frames compared for novelty, see `find_boundaries()`.
+    pub window: usize,
+
+    /// Minimum novelty of a boundary, `0..=1`.
+    pub min_novelty: f64,
+
+    /// Audio with RMS below this is silence.
+    pub silence_threshold: f64,
+
+    /// Minimum length of silence between tracks in items.
+    pub min_silence: usize,
+
+    /// Minimum length of a track in items. Weaker boundaries closer than this to a stronger one
+    /// are dropped.
+    pub min_track_len: usize,
+}
+
+impl Default for BoundaryConfig {
+    /// Looks for tracks of 30 seconds or longer.
+    fn default() -> Self {
+        Self {
+            window: 64,
+            min_novelty: 0.25,
+            silence_threshold: 100.0,
+            min_silence: 4,
+            min_track_len: 240,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BoundaryKind {
+    /// Tracks are separated with silence.
+    Silence,
+
+    /// Tracks change without silence, e.g. in a DJ mix.
+    Novelty,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Boundary {
+    /// Item of the recording's fingerprint the recording is split at.
+    pub pos: usize,
+
+    /// Time of the split in seconds, the middle of the audio the item at `pos` covers.
+    pub time: f64,
+
+    pub kind: BoundaryKind,
+
+    /// Novelty at the boundary, `1` for silence.
+    pub strength: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConfirmedBoundary {
+    pub boundary: Boundary,
+
+    /// Track identified before the boundary.
+    pub before: Option<u32>,
+
+    /// Track identified after the boundary.
+    pub after: Option<u32>,
+}
+
+/// Proposes track boundaries in a continuous recording like an unsplit album rip or a DJ mix.
+/// Silence between tracks is found from the audio level. Elsewhere boundaries are placed at
+/// jumps of the Hamming distance between successive frames of subfingerprints, see `novelty()`,
+/// and then moved to the biggest jump of Hamming distance between successive items near the peak.
+/// `input` is interleaved PCM. Returns boundaries in recording order.
+pub fn find_boundaries(
+    algorithm: Algorithm,
+    sample_rate: u32,
+    channel_count: u32,
+    input: &[i16],
+    config: &BoundaryConfig) -> Vec<Boundary>
+{
+    assert!(config.window > 0);
+    assert!(sample_rate > 0);
+    assert!(channel_count > 0);
+
+    let fp_config = algorithm.fp_config();
+    let item_duration = fp_config.item_duration_in_seconds();
+    // An item covers audio past its start, this is the middle of it.
+    let shift = (fp_config.delay_in_seconds() + item_duration) / 2.0;
+    let time = |pos: usize| pos as f64 * item_duration + shift;
+    let mut candidates = Vec::new();
+
+    // Silence.
+    let item_len = ((item_duration * sample_rate as f64) as usize).max(1) *
+        channel_count as usize;
+    let mut silence_start = None;
+    let chunks = input.chunks(item_len).map(Some).chain(Some(None));
+    for (i, chunk) in chunks.enumerate() {
+        let silent = chunk
+            .map(|v| {
+                let energy: f64 = v.iter().map(|&v| v as f64 * v as f64).sum();
+                (energy / v.len() as f64).sqrt() < config.silence_threshold
+            })
+            .unwrap_or(false);
+        match (silent, silence_start) {
+            (true, None) => silence_start = Some(i),
+            (false, Some(start)) => {
+                silence_start = None;
+                // Silence at the very start or end of the recording doesn't separate tracks.
+                if i - start >= config.min_silence && start > 0 && chunk.is_some() {
+                    // Chunks are the length of an item but aren't shifted like items.
+                    let middle = (start + (i - start) / 2) as f64 * item_duration;
+                    let pos = ((middle - shift) / item_duration).round().max(0.0) as usize;
+                    candidates.push(Boundary {
+                        pos,
+                        time: time(pos),
+                        kind: BoundaryKind::Silence,
+                        strength: 1.0,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Novelty.
+    let fp = &fingerprint(algorithm, sample_rate, channel_count, input);
+    // The novelty peaks when about half of the audio an item covers is in the next track.
+    let novelty = novelty(fp, config.window);
+    for i in 0..novelty.len() {
+        let peak = novelty[i.saturating_sub(config.window / 2)..(i + config.window / 2 + 1)
+            .min(novelty.len())].iter().all(|&v| v <= novelty[i]);
+        if !peak || novelty[i] < config.min_novelty {
+            continue;
+        }
+        let radius = config.window / 4;
+        let pos = (i.saturating_sub(radius).max(1)..(i + radius + 1).min(fp.len()))
+            .max_by_key(|&j| (hamming_distance(fp[j - 1], fp[j]), radius as isize -
+                (j as isize - i as isize).abs()))
+            .unwrap_or(i);
+        candidates.push(Boundary {
+            pos,
+            time: time(pos),
+            kind: BoundaryKind::Novelty,
+            strength: novelty[i],
+        });
+    }
+
+    candidates.sort_by(|a, b| b.strength.partial_cmp(&a.strength).unwrap()
+        .then(a.pos.cmp(&b.pos)));
+    let min_distance = config.min_track_len as f64 * item_duration;
+    let mut r: Vec<Boundary> = Vec::new();
+    for c in candidates {
+        if r.iter().all(|b| (b.time - c.time).abs() >= min_distance) {
+            r.push(c);
+        }
+    }
+    r.sort_by_key(|b| b.pos);
+    r
+}
+
+/// Novelty of each item of `fp`: the Hamming distance between the successive frames of `window`
+/// items before and starting at the item, `0..=1`. Each bit of a frame is the fraction of its
+/// items having the bit set, so the distance is fractional.
+/// Frames of single items aren't usable: successive items of a track differ by as many bits at
+/// every note change as at a change of track, frames of several seconds average the notes out.
+fn novelty(fp: &[u32], window: usize) -> Vec<f64> {
+    let mut r = vec![0.0; fp.len()];
+    if fp.len() < 2 * window {
+        return r;
+    }
+    let mut before = [0isize; 32];
+    let mut after = [0isize; 32];
+    let add = |counts: &mut [isize; 32], v: u32, d: isize| {
+        for (bit, c) in counts.iter_mut().enumerate() {
+            *c += (v >> bit & 1) as isize * d;
+        }
+    };
+    for &v in &fp[..window] {
+        add(&mut before, v, 1);
+    }
+    for &v in &fp[window..2 * window] {
+        add(&mut after, v, 1);
+    }
+    for i in window..=fp.len() - window {
+        let diff: isize = before.iter().zip(&after).map(|(a, b)| (a - b).abs()).sum();
+        r[i] = diff as f64 / (32 * window) as f64;
+        if i + window < fp.len() {
+            add(&mut before, fp[i - window], -1);
+            add(&mut before, fp[i], 1);
+            add(&mut after, fp[i], -1);
+            add(&mut after, fp[i + window], 1);
+        }
+    }
+    r
+}
+
+/// Identifies the tracks between `boundaries` in the `index`, searching each with its
+/// subfingerprints. A boundary between parts of the same track at consistent offsets isn't a
+/// track change and is dropped, the others are returned with the tracks around them.
+/// Tracks need at least `min_hits` hits to be identified. `boundaries` must be sorted by `pos`
+/// like the ones `find_boundaries()` returns.
+pub fn confirm_boundaries<S: Search>(
+    index: &S,
+    fp: &[u32],
+    boundaries: &[Boundary],
+    min_hits: usize) -> Vec<ConfirmedBoundary>
+{
+    assert!(boundaries.windows(2).all(|w| w[0].pos <= w[1].pos),
+        "boundaries must be sorted by pos");
+    let mut starts = vec![0];
+    starts.extend(boundaries.iter().map(|b| b.pos.min(fp.len())));
+    starts.push(fp.len());
+    let tracks: Vec<_> = starts.windows(2)
+        .map(|w| index.search(&fp[w[0]..w[1]], 1).into_iter()
+            .find(|c| c.hits >= min_hits))
+        .collect();
+
+    let mut r = Vec::new();
+    // Segment before the boundary. Segments around dropped boundaries are compared by their
+    // first part.
+    let mut before = 0;
+    for (i, &boundary) in boundaries.iter().enumerate() {
+        let after = i + 1;
+        if let (Some(b), Some(a)) = (tracks[before], tracks[after]) {
+            let expected = b.offset + (starts[after] - starts[before]) as isize;
+            if a.track_id == b.track_id && (a.offset - expected).abs() <= 2 {
+                continue;
+            }
+        }
+        r.push(ConfirmedBoundary {
+            boundary,
+            before: tracks[before].map(|c| c.track_id),
+            after: tracks[after].map(|c| c.track_id),
+        });
+        before = after;
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index::Index;
+    use crate::test_util::melody_in;
+
+    const SAMPLE_RATE: u32 = 11025;
+
+    /// Tracks of chords sharing no notes.
+    fn tracks() -> Vec<Vec<i16>> {
+        let c_major = &[60, 64, 67];
+        let f_sharp_major = &[61, 66, 70];
+        vec![
+            melody_in(1, SAMPLE_RATE, 25, c_major),
+            melody_in(2, SAMPLE_RATE, 25, f_sharp_major),
+            melody_in(3, SAMPLE_RATE, 25, c_major),
+        ]
+    }
+
+    fn config() -> BoundaryConfig {
+        BoundaryConfig {
+            min_track_len: 80,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test() {
+        let t = &tracks();
+        let silence = vec![0; 2 * SAMPLE_RATE as usize];
+        let input = [&t[0][..], &t[1], &silence, &t[2]].concat();
+        let act = find_boundaries(Algorithm::Test2, SAMPLE_RATE, 1, &input, &config());
+
+        assert_eq!(act.len(), 2, "{:?}", act);
+        assert_eq!(act[0].kind, BoundaryKind::Novelty);
+        assert!((act[0].time - 25.0).abs() < 1.5, "{:?}", act);
+        assert_eq!(act[1].kind, BoundaryKind::Silence);
+        assert!((act[1].time - 51.0).abs() < 0.5, "{:?}", act);
+        assert_eq!(act[1].strength, 1.0);
+
+        // Both kinds place the split at the same point of the items.
+        let fp_config = Algorithm::Test2.fp_config();
+        let shift = act[0].time - act[0].pos as f64 * fp_config.item_duration_in_seconds();
+        assert!((act[1].time - act[1].pos as f64 * fp_config.item_duration_in_seconds() - shift)
+            .abs() < 1e-9, "{:?}", act);
+
+        assert_eq!(find_boundaries(Algorithm::Test2, SAMPLE_RATE, 1, &t[0], &config()), &[]);
+    }
+
+    #[test]
+    fn confirm() {
+        let t = &tracks();
+        let input = [&t[0][..], &t[1], &t[2]].concat();
+        let fp = &fingerprint(Algorithm::Test2, SAMPLE_RATE, 1, &input);
+        let index = &mut Index::default();
+        for (i, t) in t.iter().enumerate() {
+            index.insert(i as u32 + 1, &fingerprint(Algorithm::Test2, SAMPLE_RATE, 1, t));
+        }
+
+        let item_duration = Algorithm::Test2.fp_config().item_duration_in_seconds();
+        let boundary = |secs: f64| Boundary {
+            pos: (secs / item_duration) as usize,
+            time: secs,
+            kind: BoundaryKind::Novelty,
+            strength: 0.5,
+        };
+        // A false split in the middle of the second track.
+        let boundaries = &[boundary(25.0), boundary(37.0), boundary(50.0)];
+        let act = confirm_boundaries(index, fp, boundaries, 10);
+        assert_eq!(act, &[
+            ConfirmedBoundary { boundary: boundaries[0], before: Some(1), after: Some(2) },
+            ConfirmedBoundary { boundary: boundaries[2], before: Some(2), after: Some(3) },
+        ]);
+
+        let act = confirm_boundaries(&Index::default(), fp, boundaries, 10);
+        assert_eq!(act.len(), 3);
+        assert_eq!(act[0].before, None);
+    }
+}
@@ -0,0 +1,529 @@
+use std::ffi::c_void;
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use crate::{Algorithm, Chromaprint};
+use crate::fingerprint::{compress, decompress, hash};
+use crate::fingerprint::codec::base64;
+
+// Memory handed to C is allocated with `malloc()`, so it can be freed with `free()` as well as
+// with `chromaprint_dealloc()`.
+extern "C" {
+    fn malloc(size: usize) -> *mut c_void;
+    fn free(ptr: *mut c_void);
+}
+
+const VERSION: &[u8] = b"1.5.1\0";
+
+/// Runs `f` returning `1` if it succeeds and `0` if it fails or panics. Panics must not unwind
+/// into C.
+fn guard(f: impl FnOnce() -> bool) -> c_int {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(false) as c_int
+}
+
+/// Copies `v` to memory allocated with `malloc()`, with `extra` zeroed items after it.
+unsafe fn alloc_copy<T: Copy>(v: &[T], extra: usize) -> *mut T {
+    let len = v.len() + extra;
+    let r = malloc(len.max(1) * mem::size_of::<T>()) as *mut T;
+    if !r.is_null() {
+        ptr::copy_nonoverlapping(v.as_ptr(), r, v.len());
+        ptr::write_bytes(r.add(v.len()), 0, extra);
+    }
+    r
+}
+
+unsafe fn slice_from<'a, T>(data: *const T, len: c_int) -> Option<&'a [T]> {
+    if len < 0 || (data.is_null() && len > 0) {
+        None
+    } else if len == 0 {
+        Some(&[])
+    } else {
+        Some(slice::from_raw_parts(data, len as usize))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn chromaprint_get_version() -> *const c_char {
+    VERSION.as_ptr() as *const c_char
+}
+
+/// Returns null if the algorithm isn't supported.
+#[no_mangle]
+pub extern "C" fn chromaprint_new(algorithm: c_int) -> *mut Chromaprint {
+    if algorithm < 0 || algorithm > u8::MAX as c_int {
+        return ptr::null_mut();
+    }
+    match Algorithm::from_id(algorithm as u8) {
+        Some(a) => Box::into_raw(Box::new(Chromaprint::new(a))),
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_free(ctx: *mut Chromaprint) {
+    if !ctx.is_null() {
+        drop(Box::from_raw(ctx));
+    }
+}
+
+/// Getters taking a context return `0` if it's null.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_algorithm(ctx: *mut Chromaprint) -> c_int {
+    if ctx.is_null() {
+        return 0;
+    }
+    (*ctx).algorithm().id() as c_int
+}
+
+/// No options are supported.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_set_option(
+    _ctx: *mut Chromaprint,
+    _name: *const c_char,
+    _value: c_int) -> c_int
+{
+    0
+}
+
+/// Number of channels audio is downmixed to.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_num_channels(_ctx: *mut Chromaprint) -> c_int {
+    1
+}
+
+/// Sample rate audio is resampled to.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_sample_rate(ctx: *mut Chromaprint) -> c_int {
+    if ctx.is_null() {
+        return 0;
+    }
+    (*ctx).algorithm().fp_config().sample_rate() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_item_duration(ctx: *mut Chromaprint) -> c_int {
+    if ctx.is_null() {
+        return 0;
+    }
+    (*ctx).algorithm().fp_config().item_duration() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_item_duration_ms(ctx: *mut Chromaprint) -> c_int {
+    if ctx.is_null() {
+        return 0;
+    }
+    ((*ctx).algorithm().fp_config().item_duration_in_seconds() * 1000.0).round() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_delay(ctx: *mut Chromaprint) -> c_int {
+    if ctx.is_null() {
+        return 0;
+    }
+    (*ctx).algorithm().fp_config().delay() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_delay_ms(ctx: *mut Chromaprint) -> c_int {
+    if ctx.is_null() {
+        return 0;
+    }
+    ((*ctx).algorithm().fp_config().delay_in_seconds() * 1000.0).round() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_start(
+    ctx: *mut Chromaprint,
+    sample_rate: c_int,
+    num_channels: c_int) -> c_int
+{
+    if ctx.is_null() || sample_rate <= 0 || num_channels <= 0 {
+        return 0;
+    }
+    guard(|| {
+        (*ctx).start(sample_rate as u32, num_channels as u32);
+        true
+    })
+}
+
+/// `size` is the number of samples in `data` counting all channels.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_feed(
+    ctx: *mut Chromaprint,
+    data: *const i16,
+    size: c_int) -> c_int
+{
+    let data = match slice_from(data, size) {
+        Some(v) if !ctx.is_null() => v,
+        _ => return 0,
+    };
+    guard(|| (*ctx).feed(data))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_finish(ctx: *mut Chromaprint) -> c_int {
+    if ctx.is_null() {
+        return 0;
+    }
+    guard(|| (*ctx).finish())
+}
+
+/// Returns the compressed fingerprint as a base64 string.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_fingerprint(
+    ctx: *mut Chromaprint,
+    fingerprint: *mut *mut c_char) -> c_int
+{
+    if ctx.is_null() || fingerprint.is_null() {
+        return 0;
+    }
+    let ctx = &*ctx;
+    let encoded = base64::encode(&compress(ctx.fingerprint(), ctx.algorithm().id()));
+    *fingerprint = alloc_copy(encoded.as_bytes(), 1) as *mut c_char;
+    !(*fingerprint).is_null() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_raw_fingerprint(
+    ctx: *mut Chromaprint,
+    fingerprint: *mut *mut u32,
+    size: *mut c_int) -> c_int
+{
+    if ctx.is_null() || fingerprint.is_null() || size.is_null() {
+        return 0;
+    }
+    let fp = (*ctx).fingerprint();
+    *fingerprint = alloc_copy(fp, 0);
+    *size = fp.len() as c_int;
+    !(*fingerprint).is_null() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_raw_fingerprint_size(
+    ctx: *mut Chromaprint,
+    size: *mut c_int) -> c_int
+{
+    if ctx.is_null() || size.is_null() {
+        return 0;
+    }
+    *size = (*ctx).fingerprint().len() as c_int;
+    1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_get_fingerprint_hash(
+    ctx: *mut Chromaprint,
+    hash: *mut u32) -> c_int
+{
+    if ctx.is_null() {
+        return 0;
+    }
+    chromaprint_hash_fingerprint((*ctx).fingerprint().as_ptr(),
+        (*ctx).fingerprint().len() as c_int, hash)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_clear_fingerprint(ctx: *mut Chromaprint) -> c_int {
+    if ctx.is_null() {
+        return 0;
+    }
+    (*ctx).clear_fingerprint();
+    1
+}
+
+/// Compresses a raw fingerprint, optionally encoding it with base64. A base64 result is
+/// terminated with a zero which `encoded_size` doesn't count.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_encode_fingerprint(
+    fp: *const u32,
+    size: c_int,
+    algorithm: c_int,
+    encoded_fp: *mut *mut c_char,
+    encoded_size: *mut c_int,
+    base64: c_int) -> c_int
+{
+    let fp = match slice_from(fp, size) {
+        Some(v) if !encoded_fp.is_null() && !encoded_size.is_null() => v,
+        _ => return 0,
+    };
+    let mut encoded = compress(fp, algorithm as u8);
+    let extra = if base64 != 0 {
+        encoded = base64::encode(&encoded).into_bytes();
+        1
+    } else {
+        0
+    };
+    *encoded_fp = alloc_copy(&encoded, extra) as *mut c_char;
+    *encoded_size = encoded.len() as c_int;
+    !(*encoded_fp).is_null() as c_int
+}
+
+/// Reverses `chromaprint_encode_fingerprint()`. `algorithm` may be null.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_decode_fingerprint(
+    encoded_fp: *const c_char,
+    encoded_size: c_int,
+    fp: *mut *mut u32,
+    size: *mut c_int,
+    algorithm: *mut c_int,
+    base64: c_int) -> c_int
+{
+    let encoded = match slice_from(encoded_fp as *const u8, encoded_size) {
+        Some(v) if !fp.is_null() && !size.is_null() => v,
+        _ => return 0,
+    };
+    let decoded = if base64 != 0 {
+        base64::decode(encoded).and_then(|v| decompress(&v))
+    } else {
+        decompress(encoded)
+    };
+    let (decoded, version) = match decoded {
+        Some(v) => v,
+        None => return 0,
+    };
+    *fp = alloc_copy(&decoded, 0);
+    *size = decoded.len() as c_int;
+    if !algorithm.is_null() {
+        *algorithm = version as c_int;
+    }
+    !(*fp).is_null() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_hash_fingerprint(
+    fp: *const u32,
+    size: c_int,
+    hash_out: *mut u32) -> c_int
+{
+    match slice_from(fp, size) {
+        Some(fp) if !hash_out.is_null() => {
+            *hash_out = hash(fp);
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// Frees memory returned by the other functions.
+#[no_mangle]
+pub unsafe extern "C" fn chromaprint_dealloc(ptr: *mut c_void) {
+    free(ptr);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::ffi::CStr;
+    use crate::fingerprint;
+    use crate::test_util::*;
+
+    /// C types of the Rust FFI types used in the exported functions.
+    fn c_type(rust: &str) -> String {
+        let mut t = rust.trim();
+        let mut is_const = false;
+        let mut stars = 0;
+        loop {
+            if let Some(v) = t.strip_prefix("*const ") {
+                is_const = true;
+                t = v;
+            } else if let Some(v) = t.strip_prefix("*mut ") {
+                t = v;
+            } else {
+                break;
+            }
+            stars += 1;
+        }
+        let base = match t {
+            "c_char" => "char",
+            "c_int" => "int",
+            "c_void" => "void",
+            "i16" => "int16_t",
+            "u32" => "uint32_t",
+            "Chromaprint" => "ChromaprintContext",
+            _ => panic!("unknown type {}", t),
+        };
+        format!("{}{}{}{}", if is_const { "const " } else { "" }, base,
+            if stars > 0 { " " } else { "" }, "*".repeat(stars))
+    }
+
+    /// C signatures of the functions exported from `capi.rs` by name, without parameter names.
+    fn exported() -> BTreeMap<String, String> {
+        let source = include_str!("capi.rs");
+        let prefix = "extern \"C\" fn chromaprint_";
+        source.match_indices(prefix)
+            .map(|(i, _)| {
+                let s = &source[i + prefix.len() - "chromaprint_".len()..];
+                let s = &s[..s.find('{').unwrap()];
+                let (name, rest) = s.split_at(s.find('(').unwrap());
+                let (params, ret) = rest[1..].split_at(rest.find(')').unwrap() - 1);
+                let params: Vec<_> = params.split(',')
+                    .filter(|p| !p.trim().is_empty())
+                    .map(|p| c_type(p.split(':').nth(1).unwrap()))
+                    .collect();
+                let ret = match ret[1..].trim().strip_prefix("->") {
+                    Some(v) => c_type(v),
+                    None => "void".into(),
+                };
+                (name.to_string(), format!("{} {}({})", ret, name, params.join(", ")))
+            })
+            .collect()
+    }
+
+    /// Same as `exported()` for the functions declared in `chromaprint.h`.
+    fn declared() -> BTreeMap<String, String> {
+        let strip_name = |s: &str| s.trim()
+            .trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            .trim()
+            .to_string();
+        include_str!("../include/chromaprint.h").lines()
+            .filter_map(|l| l.strip_prefix("CHROMAPRINT_API "))
+            .map(|decl| {
+                let (ret_name, rest) = decl.split_at(decl.find('(').unwrap());
+                let name = &ret_name[strip_name(ret_name).len()..].trim();
+                let params: Vec<_> = rest[1..rest.find(')').unwrap()].split(',')
+                    .filter(|p| p.trim() != "void")
+                    .map(strip_name)
+                    .collect();
+                (name.to_string(),
+                    format!("{} {}({})", strip_name(ret_name), name, params.join(", ")))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn header() {
+        let exported = exported();
+        assert_eq!(declared(), exported);
+        assert_eq!(exported.len(), 23);
+        assert_eq!(exported["chromaprint_get_raw_fingerprint"],
+            "int chromaprint_get_raw_fingerprint(ChromaprintContext *, uint32_t **, int *)");
+    }
+
+    #[test]
+    fn context() {
+        let inp = &read_audio_raw(include_bytes!("../tests/data/test_stereo_44100.raw")).repeat(5);
+        let expected = fingerprint(Algorithm::Test2, 44100, 2, inp);
+        unsafe {
+            assert!(chromaprint_new(0).is_null());
+            let ctx = chromaprint_new(1);
+            assert_eq!(chromaprint_get_algorithm(ctx), 1);
+            assert_eq!(chromaprint_get_sample_rate(ctx), 11025);
+            assert_eq!(chromaprint_get_item_duration(ctx), 1365);
+            assert_eq!(chromaprint_get_item_duration_ms(ctx), 124);
+
+            assert_eq!(chromaprint_feed(ctx, inp.as_ptr(), 2), 0);
+            assert_eq!(chromaprint_start(ctx, 0, 2), 0);
+            assert_eq!(chromaprint_start(ctx, 44100, 2), 1);
+            for chunk in inp.chunks(1000) {
+                assert_eq!(chromaprint_feed(ctx, chunk.as_ptr(), chunk.len() as c_int), 1);
+            }
+            assert_eq!(chromaprint_finish(ctx), 1);
+
+            let raw = &mut ptr::null_mut();
+            let size = &mut 0;
+            assert_eq!(chromaprint_get_raw_fingerprint(ctx, raw, size), 1);
+            assert_eq!(slice::from_raw_parts(*raw, *size as usize), &expected[..]);
+            chromaprint_dealloc(*raw as *mut c_void);
+
+            let encoded = &mut ptr::null_mut();
+            assert_eq!(chromaprint_get_fingerprint(ctx, encoded), 1);
+            let s = CStr::from_ptr(*encoded).to_str().unwrap();
+            assert_eq!(s, base64::encode(&compress(&expected, 1)));
+            chromaprint_dealloc(*encoded as *mut c_void);
+
+            let h = &mut 0;
+            assert_eq!(chromaprint_get_fingerprint_hash(ctx, h), 1);
+            assert_eq!(*h, hash(&expected));
+
+            assert_eq!(chromaprint_clear_fingerprint(ctx), 1);
+            assert_eq!(chromaprint_get_raw_fingerprint_size(ctx, size), 1);
+            assert_eq!(*size, 0);
+            chromaprint_free(ctx);
+
+            let null = ptr::null_mut();
+            assert_eq!(chromaprint_get_algorithm(null), 0);
+            assert_eq!(chromaprint_get_sample_rate(null), 0);
+            assert_eq!(chromaprint_get_item_duration(null), 0);
+            assert_eq!(chromaprint_get_item_duration_ms(null), 0);
+            assert_eq!(chromaprint_get_delay(null), 0);
+            assert_eq!(chromaprint_get_delay_ms(null), 0);
+            assert_eq!(chromaprint_finish(null), 0);
+        }
+    }
+
+    /// Compares with the output of libchromaprint for the same calls, from its `test_api.cpp`.
+    /// It resamples with a different resampler, so a few bits of each item may differ.
+    #[test]
+    fn golden() {
+        const EXPECTED: &str = "AQAAC0kkZUqYREkUnFAXHk8uuMZl6EfO4zu-4ABKFGESWIIMEQE";
+        const EXPECTED_HASH: u32 = 3732003127;
+
+        let (expected, algorithm) = decompress(&base64::decode(EXPECTED.as_bytes()).unwrap())
+            .unwrap();
+        assert_eq!(algorithm, 1);
+        assert_eq!(hash(&expected), EXPECTED_HASH);
+
+        // Stereo data fed as mono, like libchromaprint's test does.
+        let inp = &read_audio_raw(include_bytes!("../tests/data/test_stereo_44100.raw"));
+        let act = unsafe {
+            let ctx = chromaprint_new(1);
+            assert_eq!(chromaprint_start(ctx, 44100, 1), 1);
+            assert_eq!(chromaprint_feed(ctx, inp.as_ptr(), inp.len() as c_int), 1);
+            assert_eq!(chromaprint_finish(ctx), 1);
+            let raw = &mut ptr::null_mut();
+            let size = &mut 0;
+            assert_eq!(chromaprint_get_raw_fingerprint(ctx, raw, size), 1);
+            let r = slice::from_raw_parts(*raw, *size as usize).to_vec();
+            chromaprint_dealloc(*raw as *mut c_void);
+            chromaprint_free(ctx);
+            r
+        };
+
+        assert!((act.len() as isize - expected.len() as isize).abs() <= 1, "{:?}", act);
+        let bits: u32 = act.iter().zip(&expected).map(|(a, e)| (a ^ e).count_ones()).sum();
+        assert!(bits as usize <= 4 * expected.len(), "{} {:?}", bits, act);
+    }
+
+    #[test]
+    fn encode_decode() {
+        unsafe {
+            let fp = [1u32, 0];
+            let encoded = &mut ptr::null_mut();
+            let encoded_size = &mut 0;
+            assert_eq!(chromaprint_encode_fingerprint(fp.as_ptr(), 2, 55, encoded, encoded_size,
+                0), 1);
+            let bytes = slice::from_raw_parts(*encoded as *const u8, *encoded_size as usize);
+            assert_eq!(bytes, &[55, 0, 0, 2, 65, 0]);
+            chromaprint_dealloc(*encoded as *mut c_void);
+
+            assert_eq!(chromaprint_encode_fingerprint(fp.as_ptr(), 2, 55, encoded, encoded_size,
+                1), 1);
+            assert_eq!(*encoded_size, 8);
+            assert_eq!(CStr::from_ptr(*encoded).to_str().unwrap(), "NwAAAkEA");
+
+            let decoded = &mut ptr::null_mut();
+            let size = &mut 0;
+            let algorithm = &mut 0;
+            assert_eq!(chromaprint_decode_fingerprint(*encoded, *encoded_size, decoded, size,
+                algorithm, 1), 1);
+            assert_eq!(slice::from_raw_parts(*decoded, *size as usize), &fp);
+            assert_eq!(*algorithm, 55);
+            chromaprint_dealloc(*decoded as *mut c_void);
+            chromaprint_dealloc(*encoded as *mut c_void);
+
+            let bad = b"NwAAAkE";
+            assert_eq!(chromaprint_decode_fingerprint(bad.as_ptr() as *const c_char, 7, decoded,
+                size, ptr::null_mut(), 1), 0);
+
+            let h = &mut 0;
+            let fp = [19681u32, 22345, 312312, 453425];
+            assert_eq!(chromaprint_hash_fingerprint(fp.as_ptr(), 4, h), 1);
+            assert_eq!(*h, 17249);
+        }
+    }
+}
@@ -1,13 +1,25 @@
+pub mod cens;
+pub mod chord;
+pub mod dtw;
 pub mod filter;
+pub mod key;
 pub mod normalize;
+pub mod structure;
+pub mod version;
 
 use std::cmp;
 
 use crate::pipeline::Step;
 use crate::util::*;
 
+pub use cens::{Cens, Quantize};
+pub use chord::{ChordLabel, ChordQuality, ChordRecognizer, ChordSegment};
+pub use dtw::{Dtw, StepPattern, WarpingPath};
 pub use filter::Filter;
+pub use key::{Key, KeyEstimate, KeyEstimator, KeyProfile, KeySegment, Mode};
 pub use normalize::Normalize;
+pub use structure::{Section, SectionLabel, SelfSimilarity, StructureAnalyzer};
+pub use version::{AlignedRegion, VersionMatch, VersionMatcher};
 
 pub const BAND_COUNT: usize = 12;
 
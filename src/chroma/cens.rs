@@ -0,0 +1,186 @@
+use std::f64::consts::PI;
+
+use super::*;
+use crate::pipeline::{Inplace, Then, ThenInplace};
+
+/// Quantization thresholds applied to L1-normalized chroma, as in Müller's CENS.
+/// A band gets one point per threshold it reaches.
+const QUANTIZE_THRESHOLDS: [f64; 4] = [0.05, 0.1, 0.2, 0.4];
+
+/// Normalizes a chroma frame to unit L1 norm and quantizes each band to `0..=4`
+/// using `QUANTIZE_THRESHOLDS`.
+pub struct Quantize;
+
+impl Inplace<f64> for Quantize {
+    fn process(&mut self, in_out: &mut [f64]) {
+        let sum: f64 = in_out.iter().map(|v| v.abs()).sum();
+        for v in in_out.iter_mut() {
+            *v = if sum > 0.0 {
+                let v = v.abs() / sum;
+                QUANTIZE_THRESHOLDS.iter().filter(|&&t| v >= t).count() as f64
+            } else {
+                0.0
+            };
+        }
+    }
+}
+
+/// Chroma Energy Normalized Statistics.
+/// Quantizes chroma frames, smooths them over `window_len` frames with a Hann window, keeps every
+/// `downsample`-th smoothed frame and normalizes it to unit Euclidean norm.
+/// With 10 Hz chroma input `Cens::new(41, 10)` gives the classic 1 Hz CENS features.
+pub struct Cens(ThenInplace<f64, f64, Smoothed, Normalize>);
+
+type Smoothed = Then<f64, f64, f64, Then<f64, f64, f64, Quantized, Filter>, Downsample>;
+
+impl Cens {
+    pub fn new(window_len: usize, downsample: usize) -> Self {
+        Self(Quantized::new()
+            .then(Filter::new(&hann_window(window_len)))
+            .then(Downsample::new(downsample))
+            .then_inplace(Normalize::new(1e-3)))
+    }
+}
+
+impl Step<f64, f64> for Cens {
+    fn process<F>(&mut self, input: &[f64], output: F)
+        where F: FnMut(&[f64])
+    {
+        self.0.process(input, output);
+    }
+
+    fn finish<F>(&mut self, output: F)
+        where F: FnMut(&[f64])
+    {
+        self.0.finish(output);
+    }
+}
+
+struct Quantized {
+    buf: Vec<f64>,
+}
+
+impl Quantized {
+    fn new() -> Self {
+        Self {
+            buf: vec![0.0; BAND_COUNT],
+        }
+    }
+}
+
+impl Step<f64, f64> for Quantized {
+    fn process<F>(&mut self, input: &[f64], mut output: F)
+        where F: FnMut(&[f64])
+    {
+        self.buf.copy_from_slice(input);
+        Quantize.process(&mut self.buf);
+        output(&self.buf);
+    }
+
+    fn finish<F>(&mut self, _output: F)
+        where F: FnMut(&[f64])
+    {
+    }
+}
+
+/// Passes through every `factor`-th frame, starting with the first one.
+struct Downsample {
+    factor: usize,
+    pos: usize,
+}
+
+impl Downsample {
+    fn new(factor: usize) -> Self {
+        assert!(factor > 0);
+        Self {
+            factor,
+            pos: 0,
+        }
+    }
+}
+
+impl Step<f64, f64> for Downsample {
+    fn process<F>(&mut self, input: &[f64], mut output: F)
+        where F: FnMut(&[f64])
+    {
+        if self.pos == 0 {
+            output(input);
+        }
+        self.pos = (self.pos + 1) % self.factor;
+    }
+
+    fn finish<F>(&mut self, _output: F)
+        where F: FnMut(&[f64])
+    {
+    }
+}
+
+/// Hann window without the zero end points, normalized to unit sum.
+fn hann_window(len: usize) -> Vec<f64> {
+    assert!(len > 0);
+    let mut r: Vec<_> = (0..len)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * (i + 1) as f64 / (len + 1) as f64).cos())
+        .collect();
+    let sum: f64 = r.iter().sum();
+    for v in r.iter_mut() {
+        *v /= sum;
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+    use crate::pipeline::test_util::*;
+
+    #[test]
+    fn quantize() {
+        let buf = &mut [
+            0.5, 0.25, 0.12, 0.07, 0.03, 0.03,
+            0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+        ];
+        Quantize.process(buf);
+        assert_eq!(buf, &[
+            4.0, 3.0, 2.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+        ]);
+
+        let buf = &mut [0.0; BAND_COUNT];
+        Quantize.process(buf);
+        assert_eq!(buf, &[0.0; BAND_COUNT]);
+    }
+
+    #[test]
+    fn hann() {
+        let w = hann_window(3);
+        assert_abs_diff_eq!(w[0], 0.25, epsilon = 1e-10);
+        assert_abs_diff_eq!(w[1], 0.5, epsilon = 1e-10);
+        assert_abs_diff_eq!(w[2], 0.25, epsilon = 1e-10);
+    }
+
+    #[test]
+    fn cens() {
+        let cens = &mut Cens::new(3, 2);
+
+        let actual = &mut Vec::new();
+        for &band in &[0, 0, 0, 1, 1, 1] {
+            let mut frame = [0.0; BAND_COUNT];
+            frame[band] = 0.3;
+            cens.process(&frame, collect(actual));
+        }
+        cens.finish(collect(actual));
+
+        // Smoothing yields 4 frames: [1, 0], [0.75, 0.25], [0.25, 0.75], [0, 1].
+        // Every second one is kept.
+        let norm = (0.25f64 * 0.25 + 0.75 * 0.75).sqrt();
+        let expected = &[[1.0, 0.0], [0.25 / norm, 0.75 / norm]];
+
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert_abs_diff_eq!(a[0], e[0], epsilon = 1e-10);
+            assert_abs_diff_eq!(a[1], e[1], epsilon = 1e-10);
+            assert!(a[2..].iter().all(|&v| v == 0.0));
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+
+use super::*;
+use super::key::NOTE_NAMES;
+use crate::pipeline::Inplace;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Seventh,
+    Diminished,
+    Augmented,
+}
+
+impl ChordQuality {
+    const ALL: [ChordQuality; 5] = [
+        ChordQuality::Major,
+        ChordQuality::Minor,
+        ChordQuality::Seventh,
+        ChordQuality::Diminished,
+        ChordQuality::Augmented,
+    ];
+
+    fn intervals(self) -> &'static [usize] {
+        use ChordQuality::*;
+        match self {
+            Major => &[0, 4, 7],
+            Minor => &[0, 3, 7],
+            Seventh => &[0, 4, 7, 10],
+            Diminished => &[0, 3, 6],
+            Augmented => &[0, 4, 8],
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        use ChordQuality::*;
+        match self {
+            Major => "",
+            Minor => "m",
+            Seventh => "7",
+            Diminished => "dim",
+            Augmented => "aug",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChordLabel {
+    NoChord,
+    Chord {
+        /// Chroma band of the root.
+        root: u8,
+        quality: ChordQuality,
+    },
+}
+
+impl fmt::Display for ChordLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChordLabel::NoChord => write!(f, "N"),
+            ChordLabel::Chord { root, quality } =>
+                write!(f, "{}{}", NOTE_NAMES[*root as usize], quality.suffix()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChordSegment {
+    /// Start time in seconds.
+    pub start: f64,
+
+    /// End time in seconds.
+    pub end: f64,
+
+    pub label: ChordLabel,
+}
+
+/// Recognizes chords in normalized chroma frames, e.g. the output of `Chroma -> Normalize`.
+/// Each frame is matched against binary chord templates and the resulting label sequence is
+/// smoothed with Viterbi decoding over an HMM that favors staying on the same chord.
+/// Frames pass through untouched so the recognizer can be chained with `then_inplace`.
+pub struct ChordRecognizer {
+    frame_duration: f64,
+    self_transition: f64,
+    sharpness: f64,
+    labels: Vec<ChordLabel>,
+    templates: Vec<[f64; BAND_COUNT]>,
+
+    /// Per frame log emission score for each label.
+    emissions: Vec<Vec<f64>>,
+}
+
+impl ChordRecognizer {
+    pub const DEFAULT_SELF_TRANSITION: f64 = 0.9;
+    pub const DEFAULT_SHARPNESS: f64 = 20.0;
+
+    /// `frame_duration` is the hop between chroma frames in seconds.
+    /// `self_transition` is the HMM probability of staying on the same chord between frames.
+    /// `sharpness` scales template similarities into log emission scores, higher values trust
+    /// individual frames more.
+    pub fn new(frame_duration: f64, self_transition: f64, sharpness: f64) -> Self {
+        assert!(frame_duration > 0.0);
+        assert!(self_transition > 0.0 && self_transition < 1.0);
+        assert!(sharpness > 0.0);
+
+        let mut labels = vec![ChordLabel::NoChord];
+        let mut templates = vec![[1.0; BAND_COUNT]];
+        for &quality in &ChordQuality::ALL {
+            for root in 0..BAND_COUNT {
+                let mut t = [0.0; BAND_COUNT];
+                for &i in quality.intervals() {
+                    t[(root + i) % BAND_COUNT] = 1.0;
+                }
+                labels.push(ChordLabel::Chord { root: root as u8, quality });
+                templates.push(t);
+            }
+        }
+        for t in templates.iter_mut() {
+            let norm = t.iter().map(|v| v * v).sum::<f64>().sqrt();
+            for v in t.iter_mut() {
+                *v /= norm;
+            }
+        }
+
+        Self {
+            frame_duration,
+            self_transition,
+            sharpness,
+            labels,
+            templates,
+            emissions: Vec::new(),
+        }
+    }
+
+    /// Decodes the most likely chord sequence of all frames seen so far and merges runs of
+    /// the same label into segments.
+    pub fn segments(&self) -> Vec<ChordSegment> {
+        let path = self.viterbi();
+        let mut r: Vec<ChordSegment> = Vec::new();
+        for (i, &state) in path.iter().enumerate() {
+            let label = self.labels[state];
+            let end = (i + 1) as f64 * self.frame_duration;
+            match r.last_mut() {
+                Some(last) if last.label == label => last.end = end,
+                _ => r.push(ChordSegment {
+                    start: i as f64 * self.frame_duration,
+                    end,
+                    label,
+                }),
+            }
+        }
+        r
+    }
+
+    fn viterbi(&self) -> Vec<usize> {
+        let n = self.labels.len();
+        if self.emissions.is_empty() {
+            return Vec::new();
+        }
+
+        let stay = self.self_transition.ln();
+        let switch = ((1.0 - self.self_transition) / (n - 1) as f64).ln();
+
+        let mut back = Vec::with_capacity(self.emissions.len());
+        let mut score = self.emissions[0].clone();
+        let mut next = vec![0.0; n];
+        for emission in &self.emissions[1..] {
+            // With uniform switch probability the best predecessor is either the state itself
+            // or the overall best state.
+            let best = argmax(&score);
+            let mut ptr = vec![0; n];
+            for j in 0..n {
+                let (s, from) = if score[j] + stay >= score[best] + switch {
+                    (score[j] + stay, j)
+                } else {
+                    (score[best] + switch, best)
+                };
+                next[j] = s + emission[j];
+                ptr[j] = from;
+            }
+            back.push(ptr);
+            std::mem::swap(&mut score, &mut next);
+        }
+
+        let mut path = vec![argmax(&score)];
+        for ptr in back.iter().rev() {
+            let prev = ptr[*path.last().unwrap()];
+            path.push(prev);
+        }
+        path.reverse();
+        path
+    }
+}
+
+impl Default for ChordRecognizer {
+    /// Recognizer for the default fingerprinting frame rate.
+    fn default() -> Self {
+        let frame_duration = (crate::DEFAULT_FRAME_SIZE - crate::DEFAULT_FRAME_OVERLAP) as f64 /
+            crate::DEFAULT_SAMPLE_RATE as f64;
+        Self::new(frame_duration, Self::DEFAULT_SELF_TRANSITION, Self::DEFAULT_SHARPNESS)
+    }
+}
+
+impl Inplace<f64> for ChordRecognizer {
+    fn process(&mut self, in_out: &mut [f64]) {
+        assert_eq!(in_out.len(), BAND_COUNT);
+        let norm = in_out.iter().map(|v| v * v).sum::<f64>().sqrt();
+        let emission = self.templates.iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let similarity = if norm > 0.0 {
+                    t.iter().zip(in_out.iter()).map(|(t, v)| t * v).sum::<f64>() / norm
+                } else if i == 0 {
+                    // Silence.
+                    1.0
+                } else {
+                    0.0
+                };
+                self.sharpness * similarity
+            })
+            .collect();
+        self.emissions.push(emission);
+    }
+}
+
+fn argmax(v: &[f64]) -> usize {
+    let mut r = 0;
+    for (i, &x) in v.iter().enumerate() {
+        if x > v[r] {
+            r = i;
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const C: usize = 3;
+    const A: usize = 0;
+    const G: usize = 10;
+
+    fn frame(bands: &[usize]) -> [f64; BAND_COUNT] {
+        let mut r = [0.0; BAND_COUNT];
+        for &b in bands {
+            r[b % BAND_COUNT] = 1.0;
+        }
+        r
+    }
+
+    fn labels(r: &ChordRecognizer) -> Vec<(usize, usize, String)> {
+        r.segments().iter()
+            .map(|s| ((s.start * 10.0).round() as usize, (s.end * 10.0).round() as usize,
+                s.label.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn templates() {
+        let data = &[
+            (frame(&[C, C + 4, C + 7]), "C"),
+            (frame(&[A, A + 3, A + 7]), "Am"),
+            (frame(&[G, G + 4, G + 7, G + 10]), "G7"),
+            (frame(&[A + 2, A + 5, A + 8]), "Bdim"),
+            (frame(&[C, C + 4, C + 8]), "Caug"),
+            ([0.0; BAND_COUNT], "N"),
+            ([1.0; BAND_COUNT], "N"),
+        ];
+        for (f, exp) in data {
+            let r = &mut ChordRecognizer::new(0.1, 0.5, 20.0);
+            r.process(&mut f.clone());
+            assert_eq!(&labels(r)[0].2, exp);
+        }
+    }
+
+    #[test]
+    fn smoothing() {
+        let r = &mut ChordRecognizer::new(0.1, 0.9, 5.0);
+        for i in 0..10 {
+            // A single stray frame mustn't break the chord.
+            let mut f = if i == 4 {
+                frame(&[G, G + 4, G + 7])
+            } else {
+                frame(&[C, C + 4, C + 7])
+            };
+            r.process(&mut f);
+        }
+        for _ in 0..10 {
+            r.process(&mut frame(&[A, A + 3, A + 7]));
+        }
+
+        assert_eq!(labels(r), &[
+            (0, 10, "C".to_string()),
+            (10, 20, "Am".to_string()),
+        ]);
+    }
+}
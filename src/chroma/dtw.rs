@@ -0,0 +1,282 @@
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StepPattern {
+    /// Steps `(1, 0)`, `(0, 1)` and `(1, 1)`, with the diagonal step weighted twice so paths
+    /// aren't biased towards the diagonal.
+    Symmetric,
+
+    /// Steps `(1, 1)`, `(2, 1)` and `(1, 2)`. Limits the local tempo ratio to `1/2..=2`.
+    SlopeLimited,
+}
+
+impl StepPattern {
+    /// Steps as `(a frames, b frames, local cost weight)`.
+    fn steps(self) -> &'static [(usize, usize, f64)] {
+        match self {
+            StepPattern::Symmetric => &[(1, 1, 2.0), (1, 0, 1.0), (0, 1, 1.0)],
+            StepPattern::SlopeLimited => &[(1, 1, 1.0), (2, 1, 1.0), (1, 2, 1.0)],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WarpingPath {
+    /// Aligned frame pairs `(a, b)` from `(0, 0)` to the last frames of both sequences.
+    pub pairs: Vec<(usize, usize)>,
+
+    /// Total accumulated cost of the path.
+    pub cost: f64,
+}
+
+impl WarpingPath {
+    /// Maps a (fractional) frame of the first sequence to the second one, interpolating between
+    /// path points. Frames convert to time by multiplying with the hop duration of each sequence.
+    pub fn map(&self, a: f64) -> f64 {
+        let pairs = &self.pairs;
+        let i = pairs.iter().position(|&(pa, _)| pa as f64 >= a).unwrap_or(pairs.len() - 1);
+        let (a1, b1) = pairs[i];
+        if i == 0 || a1 as f64 <= a {
+            // Several b frames may map to the same a frame, use the middle one.
+            let same: Vec<_> = pairs.iter().filter(|p| p.0 == a1).map(|p| p.1).collect();
+            return (same[0] + same[same.len() - 1]) as f64 / 2.0;
+        }
+        let (a0, b0) = pairs[i - 1];
+        b0 as f64 + (b1 as f64 - b0 as f64) * (a - a0 as f64) / (a1 - a0) as f64
+    }
+}
+
+/// Dynamic time warping of chroma sequences with cosine distance as the local cost.
+/// Frames are expected to be normalized, e.g. the output of `Normalize`.
+pub struct Dtw {
+    pattern: StepPattern,
+}
+
+impl Dtw {
+    pub fn new(pattern: StepPattern) -> Self {
+        Self {
+            pattern,
+        }
+    }
+
+    /// Aligns the sequences using the full cost matrix. Needs `a.len() * b.len()` memory.
+    /// Returns `None` if either sequence is empty or the step pattern can't connect the ends.
+    pub fn align(&self, a: &[Vec<f64>], b: &[Vec<f64>]) -> Option<WarpingPath> {
+        let window = vec![(0, b.len()); a.len()];
+        self.align_window(a, b, &window)
+    }
+
+    /// Multiscale alignment: the path is found on sequences downsampled by 2 recursively and
+    /// each level only evaluates cells within `radius` frames of the path projected from the
+    /// coarser level. Memory and time grow linearly with the sequence lengths but the result
+    /// may be slightly worse than the one of `align()`.
+    /// Coarser levels always use `StepPattern::Symmetric` since they only need to locate the
+    /// path and downsampling may leave other patterns unable to connect the ends.
+    pub fn align_multiscale(&self, a: &[Vec<f64>], b: &[Vec<f64>], radius: usize)
+        -> Option<WarpingPath>
+    {
+        let min_len = radius + 2;
+        if a.len() <= min_len || b.len() <= min_len {
+            return self.align(a, b);
+        }
+
+        let coarse = Dtw::new(StepPattern::Symmetric)
+            .align_multiscale(&halve(a), &halve(b), radius)?;
+        let window = project(&coarse, a.len(), b.len(), radius);
+        self.align_window(a, b, &window)
+    }
+
+    /// `window[i]` is the range of `b` frames that may be aligned with `a[i]`.
+    fn align_window(&self, a: &[Vec<f64>], b: &[Vec<f64>], window: &[(usize, usize)])
+        -> Option<WarpingPath>
+    {
+        let (n, m) = (a.len(), b.len());
+        if n == 0 || m == 0 {
+            return None;
+        }
+        let steps = self.pattern.steps();
+
+        let get = |d: &[Vec<f64>], i: usize, j: usize| {
+            let (lo, hi) = window[i];
+            if j >= lo && j < hi {
+                d[i][j - lo]
+            } else {
+                f64::INFINITY
+            }
+        };
+
+        let mut d: Vec<Vec<f64>> = Vec::with_capacity(n);
+        let mut from: Vec<Vec<u8>> = Vec::with_capacity(n);
+        for i in 0..n {
+            let (lo, hi) = window[i];
+            let mut row = vec![f64::INFINITY; hi - lo];
+            let mut row_from = vec![0; hi - lo];
+            for j in lo..hi {
+                let c = cost(&a[i], &b[j]);
+                if i == 0 && j == 0 {
+                    row[0] = c;
+                    continue;
+                }
+                for (k, &(di, dj, w)) in steps.iter().enumerate() {
+                    if i < di || j < dj {
+                        continue;
+                    }
+                    let prev = if di == 0 {
+                        if j - dj >= lo { row[j - dj - lo] } else { f64::INFINITY }
+                    } else {
+                        get(&d, i - di, j - dj)
+                    };
+                    let v = prev + w * c;
+                    if v < row[j - lo] {
+                        row[j - lo] = v;
+                        row_from[j - lo] = k as u8;
+                    }
+                }
+            }
+            d.push(row);
+            from.push(row_from);
+        }
+
+        let cost = get(&d, n - 1, m - 1);
+        if cost == f64::INFINITY {
+            return None;
+        }
+
+        let mut pairs = vec![(n - 1, m - 1)];
+        let (mut i, mut j) = (n - 1, m - 1);
+        while i > 0 || j > 0 {
+            let (di, dj, _) = steps[from[i][j - window[i].0] as usize];
+            i -= di;
+            j -= dj;
+            pairs.push((i, j));
+        }
+        pairs.reverse();
+
+        Some(WarpingPath {
+            pairs,
+            cost,
+        })
+    }
+}
+
+/// Cosine distance between normalized frames. Zero frames only match other zero frames.
+fn cost(a: &[f64], b: &[f64]) -> f64 {
+    let zero_a = a.iter().all(|&v| v == 0.0);
+    let zero_b = b.iter().all(|&v| v == 0.0);
+    if zero_a || zero_b {
+        return if zero_a == zero_b { 0.0 } else { 1.0 };
+    }
+    1.0 - a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>()
+}
+
+/// Averages pairs of consecutive frames.
+fn halve(s: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    s.chunks(2)
+        .map(|c| {
+            let mut r = c[0].clone();
+            if let Some(second) = c.get(1) {
+                for (r, v) in r.iter_mut().zip(second) {
+                    *r = (*r + v) / 2.0;
+                }
+            }
+            r
+        })
+        .collect()
+}
+
+/// Window around a path found on sequences downsampled by 2.
+fn project(coarse: &WarpingPath, n: usize, m: usize, radius: usize) -> Vec<(usize, usize)> {
+    let mut window = vec![(usize::MAX, 0); n];
+    for &(ci, cj) in &coarse.pairs {
+        let lo = (2 * cj).saturating_sub(radius);
+        let hi = (2 * cj + 2 + radius).min(m);
+        for w in &mut window[2 * ci..(2 * ci + 2).min(n)] {
+            w.0 = w.0.min(lo);
+            w.1 = w.1.max(hi);
+        }
+    }
+
+    // Rows skipped by steps longer than 1 take the span of their neighbors.
+    for i in 0..n {
+        if window[i].0 > window[i].1 {
+            let prev = window[..i].iter().rev().find(|w| w.0 <= w.1).map(|w| w.0).unwrap_or(0);
+            let next = window[i + 1..].iter().find(|w| w.0 <= w.1).map(|w| w.1).unwrap_or(m);
+            window[i] = (prev, next);
+        }
+    }
+    window
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chroma::BAND_COUNT;
+
+    fn sequence(len: usize) -> Vec<Vec<f64>> {
+        (0..len)
+            .map(|i| {
+                let mut f = [0.0; BAND_COUNT];
+                f[(i / 2) % BAND_COUNT] = 0.8;
+                f[(i / 2 * 5 + 3) % BAND_COUNT] += 0.6;
+                let norm = f.iter().map(|v| v * v).sum::<f64>().sqrt();
+                f.iter().map(|v| v / norm).collect()
+            })
+            .collect()
+    }
+
+    /// Plays `s` at half the tempo. The last frame isn't repeated so the slope limited
+    /// pattern can connect the ends.
+    fn stretch(s: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        let mut r: Vec<_> = s.iter().flat_map(|f| vec![f.clone(), f.clone()]).collect();
+        r.pop();
+        r
+    }
+
+    #[test]
+    fn align() {
+        let a = sequence(40);
+        let b = stretch(&a);
+
+        for &pattern in &[StepPattern::Symmetric, StepPattern::SlopeLimited] {
+            let p = Dtw::new(pattern).align(&a, &b).unwrap();
+            assert_eq!(p.pairs[0], (0, 0));
+            assert_eq!(*p.pairs.last().unwrap(), (39, 78));
+            assert!(p.cost < 1e-9, "{}", p.cost);
+            for &(i, j) in &p.pairs {
+                assert!(j / 2 == i || a[j / 2] == a[i], "{:?} {} {}", pattern, i, j);
+            }
+            assert!((p.map(10.0) - 20.0).abs() <= 1.5, "{}", p.map(10.0));
+        }
+    }
+
+    #[test]
+    fn slope_limited_unreachable() {
+        let a = sequence(10);
+        let b = sequence(30);
+        assert_eq!(Dtw::new(StepPattern::SlopeLimited).align(&a, &b), None);
+        assert!(Dtw::new(StepPattern::Symmetric).align(&a, &b).is_some());
+    }
+
+    #[test]
+    fn multiscale() {
+        let a = sequence(200);
+        let b = stretch(&a);
+
+        for &pattern in &[StepPattern::Symmetric, StepPattern::SlopeLimited] {
+            let dtw = Dtw::new(pattern);
+            let full = dtw.align(&a, &b).unwrap();
+            let multi = dtw.align_multiscale(&a, &b, 4).unwrap();
+            assert!((multi.cost - full.cost).abs() < 1e-9, "{:?}", pattern);
+            assert_eq!(*multi.pairs.last().unwrap(), (199, 398));
+        }
+    }
+
+    #[test]
+    fn project_fn() {
+        let coarse = WarpingPath {
+            pairs: vec![(0, 0), (1, 2), (2, 3)],
+            cost: 0.0,
+        };
+        assert_eq!(project(&coarse, 6, 8, 1), &[
+            (0, 3), (0, 3), (3, 7), (3, 7), (5, 8), (5, 8),
+        ]);
+    }
+}
@@ -2,7 +2,7 @@ use super::*;
 use crate::pipeline::Step;
 
 pub struct Filter {
-    coefs: &'static [f64],
+    coefs: Vec<f64>,
     buf: Vec<Vec<f64>>,
     buf_pos: usize,
     buf_ready: usize,
@@ -10,14 +10,14 @@ pub struct Filter {
 }
 
 impl Filter {
-    pub fn new(coefs: &'static [f64]) -> Self {
+    pub fn new(coefs: &[f64]) -> Self {
         let mut buf = Vec::with_capacity(coefs.len());
         for _ in 0..coefs.len() {
             buf.push(vec![0.0; BAND_COUNT])
         }
 
         Self {
-            coefs,
+            coefs: coefs.to_vec(),
             buf,
             buf_pos: 0,
             buf_ready: 1,
@@ -0,0 +1,272 @@
+use std::fmt;
+
+use super::*;
+use crate::pipeline::Inplace;
+
+/// Pitch class names in chroma band order. Band 0 is A.
+pub const NOTE_NAMES: [&str; BAND_COUNT] =
+    ["A", "A#", "B", "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#"];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Key {
+    /// Chroma band of the tonic.
+    pub tonic: u8,
+    pub mode: Mode,
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode {
+            Mode::Major => "major",
+            Mode::Minor => "minor",
+        };
+        write!(f, "{} {}", NOTE_NAMES[self.tonic as usize], mode)
+    }
+}
+
+/// Key profiles, indexed by the interval from the tonic in semitones.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyProfile {
+    /// Krumhansl-Kessler probe tone ratings.
+    Krumhansl,
+
+    /// Temperley's profiles derived from the Kostka-Payne corpus.
+    Temperley,
+}
+
+impl KeyProfile {
+    fn weights(self, mode: Mode) -> &'static [f64; BAND_COUNT] {
+        use KeyProfile::*;
+        use Mode::*;
+        match (self, mode) {
+            (Krumhansl, Major) => &[6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88],
+            (Krumhansl, Minor) => &[6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17],
+            (Temperley, Major) => &[0.748, 0.060, 0.488, 0.082, 0.670, 0.460, 0.096, 0.715, 0.104, 0.366, 0.057, 0.400],
+            (Temperley, Minor) => &[0.712, 0.084, 0.474, 0.618, 0.049, 0.460, 0.105, 0.747, 0.404, 0.067, 0.133, 0.330],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEstimate {
+    pub key: Key,
+
+    /// Pearson correlation between the accumulated chroma and the key profile.
+    pub correlation: f64,
+
+    /// Difference between the correlation of the best and the second best key.
+    /// Close to zero when the estimate is ambiguous.
+    pub confidence: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeySegment {
+    /// First chroma frame of the segment.
+    pub start: usize,
+
+    /// Chroma frame after the last one of the segment.
+    pub end: usize,
+
+    pub estimate: KeyEstimate,
+}
+
+/// Estimates the musical key by correlating accumulated chroma against key profiles.
+/// Frames pass through untouched so the estimator can be chained after `Chroma` with
+/// `then_inplace`. Besides the key of everything seen so far, a key is estimated for each
+/// consecutive run of `segment_len` frames.
+pub struct KeyEstimator {
+    profile: KeyProfile,
+    segment_len: usize,
+    total: Vec<f64>,
+    segment: Vec<f64>,
+    segment_frame_count: usize,
+    frame_count: usize,
+    segments: Vec<KeySegment>,
+}
+
+impl KeyEstimator {
+    pub fn new(profile: KeyProfile, segment_len: usize) -> Self {
+        assert!(segment_len > 0);
+        Self {
+            profile,
+            segment_len,
+            total: vec![0.0; BAND_COUNT],
+            segment: vec![0.0; BAND_COUNT],
+            segment_frame_count: 0,
+            frame_count: 0,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Key of all frames seen so far. Returns `None` if no frames with energy have been seen.
+    pub fn key(&self) -> Option<KeyEstimate> {
+        estimate(self.profile, &self.total)
+    }
+
+    /// Keys of completed segments followed by the key of the incomplete trailing segment, if any.
+    pub fn key_track(&self) -> Vec<KeySegment> {
+        let mut r = self.segments.clone();
+        if self.segment_frame_count > 0 {
+            if let Some(estimate) = estimate(self.profile, &self.segment) {
+                r.push(KeySegment {
+                    start: self.frame_count - self.segment_frame_count,
+                    end: self.frame_count,
+                    estimate,
+                });
+            }
+        }
+        r
+    }
+}
+
+impl Inplace<f64> for KeyEstimator {
+    fn process(&mut self, in_out: &mut [f64]) {
+        assert_eq!(in_out.len(), BAND_COUNT);
+        for ((t, s), &v) in self.total.iter_mut().zip(self.segment.iter_mut()).zip(in_out.iter()) {
+            *t += v;
+            *s += v;
+        }
+        self.frame_count += 1;
+        self.segment_frame_count += 1;
+
+        if self.segment_frame_count == self.segment_len {
+            if let Some(estimate) = estimate(self.profile, &self.segment) {
+                self.segments.push(KeySegment {
+                    start: self.frame_count - self.segment_len,
+                    end: self.frame_count,
+                    estimate,
+                });
+            }
+            for v in self.segment.iter_mut() {
+                *v = 0.0;
+            }
+            self.segment_frame_count = 0;
+        }
+    }
+}
+
+fn estimate(profile: KeyProfile, chroma: &[f64]) -> Option<KeyEstimate> {
+    if chroma.iter().all(|&v| v == 0.0) {
+        return None;
+    }
+
+    let mut best: Option<(Key, f64)> = None;
+    let mut second = f64::NEG_INFINITY;
+    for &mode in &[Mode::Major, Mode::Minor] {
+        let weights = profile.weights(mode);
+        for tonic in 0..BAND_COUNT {
+            let rotated: Vec<_> = (0..BAND_COUNT)
+                .map(|i| weights[(i + BAND_COUNT - tonic) % BAND_COUNT])
+                .collect();
+            let c = correlation(chroma, &rotated);
+            match best {
+                Some((_, b)) if c <= b => second = second.max(c),
+                _ => {
+                    if let Some((_, b)) = best {
+                        second = b;
+                    }
+                    best = Some((Key { tonic: tonic as u8, mode }, c));
+                }
+            }
+        }
+    }
+
+    best.map(|(key, correlation)| KeyEstimate {
+        key,
+        correlation,
+        confidence: correlation - second,
+    })
+}
+
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let mut cov = 0.0;
+    let mut var_a = 0.0;
+    let mut var_b = 0.0;
+    for (&a, &b) in a.iter().zip(b) {
+        cov += (a - mean_a) * (b - mean_b);
+        var_a += (a - mean_a) * (a - mean_a);
+        var_b += (b - mean_b) * (b - mean_b);
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        0.0
+    } else {
+        cov / (var_a * var_b).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const C: usize = 3;
+    const A: usize = 0;
+
+    /// Chroma with energy on the given bands.
+    fn frame(bands: &[usize]) -> [f64; BAND_COUNT] {
+        let mut r = [0.0; BAND_COUNT];
+        for &b in bands {
+            r[b % BAND_COUNT] += 1.0;
+        }
+        r
+    }
+
+    /// Tonic, subdominant and dominant triads.
+    fn cadence(tonic: usize, third: usize) -> Vec<[f64; BAND_COUNT]> {
+        vec![
+            frame(&[tonic, tonic + third, tonic + 7]),
+            frame(&[tonic + 5, tonic + 5 + third, tonic + 12]),
+            frame(&[tonic + 7, tonic + 11, tonic + 14]),
+            frame(&[tonic, tonic + third, tonic + 7]),
+        ]
+    }
+
+    #[test]
+    fn key() {
+        for &profile in &[KeyProfile::Krumhansl, KeyProfile::Temperley] {
+            for &(tonic, third, exp) in &[
+                (C, 4, "C major"),
+                (A, 3, "A minor"),
+                (C + 4, 4, "E major"),
+            ] {
+                let e = &mut KeyEstimator::new(profile, 4);
+                assert_eq!(e.key(), None);
+                for mut f in cadence(tonic, third) {
+                    e.process(&mut f);
+                }
+                let key = e.key().unwrap();
+                assert_eq!(key.key.to_string(), exp, "{:?}", profile);
+                assert!(key.confidence > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn key_track() {
+        let e = &mut KeyEstimator::new(KeyProfile::Krumhansl, 4);
+        let frames = cadence(C, 4).into_iter()
+            .chain(cadence(A, 3))
+            .chain(cadence(C + 7, 4).into_iter().take(2));
+        for mut f in frames {
+            e.process(&mut f);
+        }
+
+        let track = e.key_track();
+        let act: Vec<_> = track.iter()
+            .map(|s| (s.start, s.end, s.estimate.key.to_string()))
+            .collect();
+        assert_eq!(&act[..2], &[
+            (0, 4, "C major".to_string()),
+            (4, 8, "A minor".to_string()),
+        ]);
+        assert_eq!((act[2].0, act[2].1), (8, 10));
+    }
+}
@@ -0,0 +1,315 @@
+use super::*;
+use crate::pipeline::Inplace;
+
+/// Cosine self-similarity matrix of a sequence of chroma frames.
+pub struct SelfSimilarity {
+    len: usize,
+    data: Vec<f64>,
+}
+
+impl SelfSimilarity {
+    pub fn new(frames: &[Vec<f64>]) -> Self {
+        let len = frames.len();
+        let norms: Vec<f64> = frames.iter()
+            .map(|f| f.iter().map(|v| v * v).sum::<f64>().sqrt())
+            .collect();
+        let mut data = vec![0.0; len * len];
+        for i in 0..len {
+            for j in i..len {
+                let s = if norms[i] > 0.0 && norms[j] > 0.0 {
+                    frames[i].iter().zip(frames[j].iter()).map(|(a, b)| a * b).sum::<f64>() /
+                        (norms[i] * norms[j])
+                } else {
+                    0.0
+                };
+                data[i * len + j] = s;
+                data[j * len + i] = s;
+            }
+        }
+        Self {
+            len,
+            data,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        self.data[i * self.len + j]
+    }
+
+    /// Foote novelty: correlation of the matrix diagonal with a Gaussian tapered checkerboard
+    /// kernel spanning `half_len` frames on each side.
+    pub fn novelty(&self, half_len: usize) -> Vec<f64> {
+        assert!(half_len > 0);
+        let l = half_len as isize;
+        let sigma = half_len as f64 / 2.0;
+        let mut r = vec![0.0; self.len];
+        for (i, n) in r.iter_mut().enumerate() {
+            let i = i as isize;
+            for a in -l..l {
+                for b in -l..l {
+                    let (x, y) = (i + a, i + b);
+                    if x < 0 || y < 0 || x >= self.len as isize || y >= self.len as isize {
+                        continue;
+                    }
+                    let sign = if (a < 0) == (b < 0) { 1.0 } else { -1.0 };
+                    let (fa, fb) = (a as f64 + 0.5, b as f64 + 0.5);
+                    let taper = (-(fa * fa + fb * fb) / (2.0 * sigma * sigma)).exp();
+                    *n += sign * taper * self.get(x as usize, y as usize);
+                }
+            }
+        }
+        r
+    }
+
+    /// Mean similarity along the diagonal starting at the given frames.
+    fn diagonal_mean(&self, i: usize, j: usize, len: usize) -> f64 {
+        if len == 0 {
+            return 0.0;
+        }
+        (0..len).map(|k| self.get(i + k, j + k)).sum::<f64>() / len as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SectionLabel {
+    Intro,
+    Verse,
+    Chorus,
+    Bridge,
+    Outro,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Section {
+    /// Start time in seconds.
+    pub start: f64,
+
+    /// End time in seconds.
+    pub end: f64,
+
+    pub label: SectionLabel,
+
+    /// Sections with the same group repeat the same material.
+    pub group: usize,
+}
+
+/// Finds song structure in chroma frames.
+/// Frames (e.g. `Chroma -> Filter` output) are averaged in blocks of `downsample` to keep the
+/// self-similarity matrix small. Section boundaries are peaks of the novelty curve. Sections
+/// whose frames are similar along the diagonal are grouped as repetitions. The most repeated
+/// group becomes the chorus, other repeated groups become verses and unique sections become
+/// intro, outro or bridge depending on their position.
+/// Frames pass through untouched so the analyzer can be chained with `then_inplace`.
+pub struct StructureAnalyzer {
+    frame_duration: f64,
+    downsample: usize,
+    kernel_half_len: usize,
+    min_section_len: usize,
+    repeat_threshold: f64,
+
+    acc: Vec<f64>,
+    acc_count: usize,
+    frames: Vec<Vec<f64>>,
+}
+
+impl StructureAnalyzer {
+    /// `hop` and `sample_rate` define the time between incoming frames, as in `FFT`.
+    /// `kernel_half_len` and `min_section_len` are in downsampled frames.
+    /// Sections repeat each other if their mean diagonal similarity reaches `repeat_threshold`.
+    pub fn new(
+        hop: usize,
+        sample_rate: u32,
+        downsample: usize,
+        kernel_half_len: usize,
+        min_section_len: usize,
+        repeat_threshold: f64) -> Self
+    {
+        assert!(hop > 0);
+        assert!(downsample > 0);
+        assert!(kernel_half_len > 0);
+        assert!(min_section_len > 0);
+        Self {
+            frame_duration: (hop * downsample) as f64 / sample_rate as f64,
+            downsample,
+            kernel_half_len,
+            min_section_len,
+            repeat_threshold,
+            acc: vec![0.0; BAND_COUNT],
+            acc_count: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn self_similarity(&self) -> SelfSimilarity {
+        SelfSimilarity::new(&self.frames)
+    }
+
+    /// Boundaries between sections in downsampled frames, including 0 and the frame count.
+    pub fn boundaries(&self, ssm: &SelfSimilarity) -> Vec<usize> {
+        let n = ssm.len();
+        let mut r = vec![0];
+        if n == 0 {
+            return r;
+        }
+
+        let novelty = ssm.novelty(self.kernel_half_len);
+        let mean = novelty.iter().sum::<f64>() / n as f64;
+        let d = self.min_section_len;
+        let mut peaks: Vec<usize> = (d..n.saturating_sub(d - 1))
+            .filter(|&i| {
+                let lo = i.saturating_sub(d - 1);
+                let hi = (i + d).min(n);
+                novelty[i] > mean && (lo..hi).all(|j| novelty[j] < novelty[i] ||
+                    (novelty[j] == novelty[i] && j >= i))
+            })
+            .collect();
+        r.append(&mut peaks);
+        r.push(n);
+        r
+    }
+
+    pub fn sections(&self) -> Vec<Section> {
+        let ssm = self.self_similarity();
+        let bounds = self.boundaries(&ssm);
+        let spans: Vec<(usize, usize)> = bounds.windows(2).map(|w| (w[0], w[1])).collect();
+
+        // Group repeated sections.
+        let mut groups: Vec<usize> = (0..spans.len()).collect();
+        for i in 0..spans.len() {
+            for j in 0..i {
+                if groups[j] != j {
+                    continue;
+                }
+                let (si, ei) = spans[i];
+                let (sj, ej) = spans[j];
+                let len = (ei - si).min(ej - sj);
+                if ssm.diagonal_mean(sj, si, len) >= self.repeat_threshold {
+                    groups[i] = j;
+                    break;
+                }
+            }
+        }
+
+        let count = |g: usize| groups.iter().filter(|&&v| v == g).count();
+
+        // The most repeated group is the chorus. When there's a tie the group that appears
+        // later wins since songs usually start with a verse.
+        let chorus = groups.iter()
+            .cloned()
+            .filter(|&g| count(g) > 1)
+            .max_by_key(|&g| (count(g), g));
+
+        let last = spans.len().saturating_sub(1);
+        spans.iter()
+            .zip(groups.iter())
+            .enumerate()
+            .map(|(i, (&(start, end), &group))| {
+                let label = if Some(group) == chorus {
+                    SectionLabel::Chorus
+                } else if count(group) > 1 {
+                    SectionLabel::Verse
+                } else if i == 0 {
+                    SectionLabel::Intro
+                } else if i == last {
+                    SectionLabel::Outro
+                } else {
+                    SectionLabel::Bridge
+                };
+                Section {
+                    start: start as f64 * self.frame_duration,
+                    end: end as f64 * self.frame_duration,
+                    label,
+                    group,
+                }
+            })
+            .collect()
+    }
+
+    /// Time span of the first chorus, suitable as a preview thumbnail.
+    pub fn chorus_thumbnail(&self) -> Option<(f64, f64)> {
+        self.sections().iter()
+            .find(|s| s.label == SectionLabel::Chorus)
+            .map(|s| (s.start, s.end))
+    }
+}
+
+impl Inplace<f64> for StructureAnalyzer {
+    fn process(&mut self, in_out: &mut [f64]) {
+        assert_eq!(in_out.len(), BAND_COUNT);
+        for (a, v) in self.acc.iter_mut().zip(in_out.iter()) {
+            *a += v;
+        }
+        self.acc_count += 1;
+        if self.acc_count == self.downsample {
+            let frame = self.acc.iter().map(|v| v / self.downsample as f64).collect();
+            self.frames.push(frame);
+            for v in self.acc.iter_mut() {
+                *v = 0.0;
+            }
+            self.acc_count = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use SectionLabel::*;
+
+    fn frame(bands: &[usize]) -> [f64; BAND_COUNT] {
+        let mut r = [0.0; BAND_COUNT];
+        for &b in bands {
+            r[b] = 1.0;
+        }
+        r
+    }
+
+    #[test]
+    fn self_similarity() {
+        let ssm = SelfSimilarity::new(&[
+            frame(&[0]).to_vec(),
+            frame(&[0, 1]).to_vec(),
+            vec![0.0; BAND_COUNT],
+        ]);
+        assert_eq!(ssm.len(), 3);
+        assert!((ssm.get(0, 0) - 1.0).abs() < 1e-10);
+        assert!((ssm.get(0, 1) - 0.5f64.sqrt()).abs() < 1e-10);
+        assert_eq!(ssm.get(1, 0), ssm.get(0, 1));
+        assert_eq!(ssm.get(2, 2), 0.0);
+    }
+
+    #[test]
+    fn sections() {
+        let intro = frame(&[2, 6]);
+        let verse = frame(&[5, 9, 0]);
+        let chorus = frame(&[0, 4, 7]);
+        let outro = frame(&[1, 11]);
+
+        // Each downsampled frame is 2 input frames of 0.5 s.
+        let a = &mut StructureAnalyzer::new(4, 8, 2, 4, 4, 0.9);
+        for f in &[intro, verse, chorus, verse, chorus, outro] {
+            for _ in 0..16 {
+                a.process(&mut f.clone());
+            }
+        }
+
+        let act: Vec<_> = a.sections().iter().map(|s| (s.start, s.end, s.label)).collect();
+        assert_eq!(act, &[
+            (0.0, 8.0, Intro),
+            (8.0, 16.0, Verse),
+            (16.0, 24.0, Chorus),
+            (24.0, 32.0, Verse),
+            (32.0, 40.0, Chorus),
+            (40.0, 48.0, Outro),
+        ]);
+        assert_eq!(a.chorus_thumbnail(), Some((16.0, 24.0)));
+    }
+}
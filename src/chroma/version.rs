@@ -0,0 +1,286 @@
+use std::ops::Range;
+
+use super::*;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlignedRegion {
+    /// Frames of the first sequence.
+    pub a: Range<usize>,
+
+    /// Frames of the second sequence.
+    pub b: Range<usize>,
+
+    /// Local alignment score of the region.
+    pub score: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionMatch {
+    /// Best local alignment score normalized by the length of the shorter sequence, `0..=1`.
+    pub score: f64,
+
+    /// How many semitones the second sequence is above the first one.
+    pub transposition: u8,
+
+    /// Non-overlapping aligned regions, best first.
+    pub regions: Vec<AlignedRegion>,
+}
+
+/// Version (cover song) similarity of two CENS-like chroma sequences.
+/// The second sequence is first transposed to the key of the first one using the optimal
+/// transposition index of their global chroma. A binary cross-recurrence plot is then built
+/// from mutual nearest neighbors and locally aligned with Smith-Waterman.
+pub struct VersionMatcher {
+    neighbor_fraction: f64,
+    mismatch_penalty: f64,
+    gap_penalty: f64,
+    max_regions: usize,
+}
+
+impl VersionMatcher {
+    /// `neighbor_fraction` is the fraction of the closest frames of the other sequence that count
+    /// as recurrences. `mismatch_penalty` and `gap_penalty` are subtracted from the alignment
+    /// score for non-recurrent frame pairs and skipped frames respectively.
+    pub fn new(
+        neighbor_fraction: f64,
+        mismatch_penalty: f64,
+        gap_penalty: f64,
+        max_regions: usize) -> Self
+    {
+        assert!(neighbor_fraction > 0.0 && neighbor_fraction <= 1.0);
+        assert!(mismatch_penalty >= 0.0);
+        assert!(gap_penalty >= 0.0);
+        Self {
+            neighbor_fraction,
+            mismatch_penalty,
+            gap_penalty,
+            max_regions,
+        }
+    }
+
+    pub fn compare(&self, a: &[Vec<f64>], b: &[Vec<f64>]) -> VersionMatch {
+        let transposition = optimal_transposition(a, b);
+        let b: Vec<Vec<f64>> = b.iter()
+            .map(|f| (0..BAND_COUNT).map(|i| f[(i + transposition) % BAND_COUNT]).collect())
+            .collect();
+
+        let recurrence = self.cross_recurrence(a, &b);
+        let regions = self.align(&recurrence, a.len(), b.len());
+
+        let min_len = a.len().min(b.len());
+        let score = if min_len > 0 {
+            regions.first().map(|r| r.score / min_len as f64).unwrap_or(0.0)
+        } else {
+            0.0
+        };
+
+        VersionMatch {
+            score,
+            transposition: transposition as u8,
+            regions,
+        }
+    }
+
+    /// Row major `a.len() x b.len()` matrix with `true` where frames are mutual neighbors.
+    fn cross_recurrence(&self, a: &[Vec<f64>], b: &[Vec<f64>]) -> Vec<bool> {
+        let (n, m) = (a.len(), b.len());
+        let mut dist = vec![0.0; n * m];
+        for (i, fa) in a.iter().enumerate() {
+            for (j, fb) in b.iter().enumerate() {
+                dist[i * m + j] = cosine_distance(fa, fb);
+            }
+        }
+
+        let kth = |mut v: Vec<f64>, fraction: f64| -> f64 {
+            if v.is_empty() {
+                return 0.0;
+            }
+            let k = ((v.len() as f64 * fraction).ceil() as usize).clamp(1, v.len());
+            v.sort_by(|a, b| a.total_cmp(b));
+            v[k - 1]
+        };
+        let row_thresholds: Vec<f64> = (0..n)
+            .map(|i| kth(dist[i * m..(i + 1) * m].to_vec(), self.neighbor_fraction))
+            .collect();
+        let col_thresholds: Vec<f64> = (0..m)
+            .map(|j| kth((0..n).map(|i| dist[i * m + j]).collect(), self.neighbor_fraction))
+            .collect();
+
+        let mut r = vec![false; n * m];
+        for i in 0..n {
+            for j in 0..m {
+                let d = dist[i * m + j];
+                r[i * m + j] = d < 1.0 && d <= row_thresholds[i] && d <= col_thresholds[j];
+            }
+        }
+        r
+    }
+
+    fn align(&self, recurrence: &[bool], n: usize, m: usize) -> Vec<AlignedRegion> {
+        // Scores of alignments ending at each cell, with a zero border row and column.
+        let w = m + 1;
+        let mut h = vec![0.0; (n + 1) * w];
+        for i in 1..=n {
+            for j in 1..=m {
+                let diag = h[(i - 1) * w + j - 1] + if recurrence[(i - 1) * m + j - 1] {
+                    1.0
+                } else {
+                    -self.mismatch_penalty
+                };
+                let up = h[(i - 1) * w + j] - self.gap_penalty;
+                let left = h[i * w + j - 1] - self.gap_penalty;
+                h[i * w + j] = diag.max(up).max(left).max(0.0);
+            }
+        }
+
+        let mut cells: Vec<usize> = (0..h.len()).filter(|&c| h[c] > 0.0).collect();
+        cells.sort_by(|&x, &y| h[y].total_cmp(&h[x]));
+
+        let mut regions: Vec<AlignedRegion> = Vec::new();
+        for c in cells {
+            if regions.len() == self.max_regions {
+                break;
+            }
+            let (end_i, end_j) = (c / w, c % w);
+            let overlaps = |r: &AlignedRegion|
+                r.a.contains(&(end_i - 1)) || r.b.contains(&(end_j - 1));
+            if regions.iter().any(overlaps) {
+                continue;
+            }
+            let (start_i, start_j) = self.trace_back(&h, recurrence, m, end_i, end_j);
+            let region = AlignedRegion {
+                a: start_i..end_i,
+                b: start_j..end_j,
+                score: h[c],
+            };
+            let intersects = regions.iter().any(|r|
+                r.a.start < region.a.end && region.a.start < r.a.end ||
+                r.b.start < region.b.end && region.b.start < r.b.end);
+            if !intersects {
+                regions.push(region);
+            }
+        }
+        regions
+    }
+
+    /// Follows the alignment ending at `(i, j)` back to where it starts.
+    fn trace_back(&self, h: &[f64], recurrence: &[bool], m: usize, mut i: usize, mut j: usize)
+        -> (usize, usize)
+    {
+        let w = m + 1;
+        let (mut start_i, mut start_j) = (i, j);
+        while i > 0 && j > 0 && h[i * w + j] > 0.0 {
+            start_i = i - 1;
+            start_j = j - 1;
+            let v = h[i * w + j];
+            let diag = h[(i - 1) * w + j - 1] + if recurrence[(i - 1) * m + j - 1] {
+                1.0
+            } else {
+                -self.mismatch_penalty
+            };
+            if v == diag {
+                i -= 1;
+                j -= 1;
+            } else if v == h[(i - 1) * w + j] - self.gap_penalty {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+        }
+        (start_i, start_j)
+    }
+}
+
+impl Default for VersionMatcher {
+    fn default() -> Self {
+        Self::new(0.1, 0.5, 0.5, 3)
+    }
+}
+
+/// Shift of the second sequence's bands that best matches its global chroma to the first one.
+fn optimal_transposition(a: &[Vec<f64>], b: &[Vec<f64>]) -> usize {
+    let global = |s: &[Vec<f64>]| {
+        let mut r = [0.0; BAND_COUNT];
+        for f in s {
+            for (r, v) in r.iter_mut().zip(f.iter()) {
+                *r += v;
+            }
+        }
+        r
+    };
+    let ga = global(a);
+    let gb = global(b);
+    (0..BAND_COUNT)
+        .map(|shift| {
+            let dot: f64 = (0..BAND_COUNT).map(|i| ga[i] * gb[(i + shift) % BAND_COUNT]).sum();
+            (shift, dot)
+        })
+        .fold((0, f64::NEG_INFINITY), |best, v| if v.1 > best.1 { v } else { best })
+        .0
+}
+
+fn cosine_distance(a: &[f64], b: &[f64]) -> f64 {
+    let na = a.iter().map(|v| v * v).sum::<f64>().sqrt();
+    let nb = b.iter().map(|v| v * v).sum::<f64>().sqrt();
+    if na == 0.0 || nb == 0.0 {
+        return 1.0;
+    }
+    1.0 - a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>() / (na * nb)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_sequence(rng: &mut StdRng, len: usize) -> Vec<Vec<f64>> {
+        (0..len)
+            .map(|_| (0..BAND_COUNT).map(|_| rng.gen::<f64>().powi(4)).collect())
+            .collect()
+    }
+
+    fn transpose(s: &[Vec<f64>], semitones: usize) -> Vec<Vec<f64>> {
+        s.iter()
+            .map(|f| (0..BAND_COUNT)
+                .map(|i| f[(i + BAND_COUNT - semitones) % BAND_COUNT])
+                .collect())
+            .collect()
+    }
+
+    #[test]
+    fn cover() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let a = random_sequence(rng, 40);
+
+        // Cover in another key with a different intro.
+        let mut b = random_sequence(rng, 10);
+        b.extend(transpose(&a[5..35], 3));
+
+        let m = VersionMatcher::default().compare(&a, &b);
+        assert_eq!(m.transposition, 3);
+        assert!(m.score > 0.6, "{}", m.score);
+
+        let r = &m.regions[0];
+        assert!(r.a.start >= 4 && r.a.start <= 6, "{:?}", r);
+        assert!(r.a.end >= 34 && r.a.end <= 36, "{:?}", r);
+        assert_eq!(r.b.start as isize - r.a.start as isize, 5);
+    }
+
+    #[test]
+    fn unrelated() {
+        let rng = &mut StdRng::seed_from_u64(2);
+        let a = random_sequence(rng, 40);
+        let b = random_sequence(rng, 40);
+
+        let m = VersionMatcher::default().compare(&a, &b);
+        assert!(m.score < 0.2, "{}", m.score);
+    }
+
+    #[test]
+    fn empty() {
+        let m = VersionMatcher::default().compare(&[], &[vec![1.0; BAND_COUNT]]);
+        assert_eq!(m.score, 0.0);
+        assert!(m.regions.is_empty());
+    }
+}
@@ -1,2 +1,16 @@
-mod codec;
-mod rolling_image;
\ No newline at end of file
+pub mod calculator;
+pub mod clip;
+pub mod codec;
+pub mod compare;
+pub mod dedup;
+pub mod repeats;
+pub mod rolling_image;
+pub mod transposition;
+
+pub use calculator::{Calculator, ReliabilityCalculator, ReliableSubfingerprint};
+pub use clip::{find_clip, ClipMatch};
+pub use codec::{compress, decompress};
+pub use compare::{acoustid_compare2, best_match, hash, Match};
+pub use dedup::{find_duplicates, DedupConfig, DedupStats, DuplicateCluster};
+pub use repeats::{find_repeats, Occurrence, RepeatConfig, RepeatedSegment};
+pub use transposition::{match_transposed, TransposedMatch};
@@ -0,0 +1,205 @@
+use super::rolling_image::RollingImage;
+use crate::{Algorithm, Classifier};
+use crate::chroma::BAND_COUNT;
+use crate::pipeline::Step;
+
+const GRAY_CODE: [u32; 4] = [0, 1, 3, 2];
+
+/// Turns normalized chroma frames into subfingerprints by running the algorithm's classifiers
+/// over a rolling integral image of the frames.
+/// Outputs a slice with one subfingerprint per chroma rotation for every frame once the widest
+/// classifier filter fits into the image.
+pub struct Calculator {
+    classifiers: &'static [Classifier],
+    max_filter_width: usize,
+    shift_count: usize,
+    image: RollingImage,
+    out: Vec<u32>,
+}
+
+impl Calculator {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self::with_shift_count(algorithm, 1)
+    }
+
+    /// Calculator that outputs subfingerprints for all `BAND_COUNT` circular rotations of the
+    /// chroma bands, i.e. for the input transposed by 0 to 11 semitones.
+    /// Output slice index is the number of semitones the band rows are rotated down by.
+    pub fn transposed(algorithm: Algorithm) -> Self {
+        Self::with_shift_count(algorithm, BAND_COUNT)
+    }
+
+    fn with_shift_count(algorithm: Algorithm, shift_count: usize) -> Self {
+        let config = algorithm.fp_config();
+        let max_filter_width = config.max_filter_width as usize;
+        Self {
+            classifiers: config.classifiers,
+            max_filter_width,
+            shift_count,
+            image: RollingImage::new(BAND_COUNT, max_filter_width),
+            out: vec![0; shift_count],
+        }
+    }
+
+    fn subfingerprint(&self, offset: usize, shift: usize) -> u32 {
+        self.classifiers.iter().fold(0, |bits, c| {
+            (bits << 2) | GRAY_CODE[c.classify(&self.image, offset, shift) as usize]
+        })
+    }
+}
+
+impl Step<f64, u32> for Calculator {
+    fn process<F>(&mut self, input: &[f64], mut output: F)
+        where F: FnMut(&[u32])
+    {
+        self.image.push(input);
+        if self.image.height() >= self.max_filter_width {
+            let offset = self.image.height() - self.max_filter_width;
+            for shift in 0..self.shift_count {
+                self.out[shift] = self.subfingerprint(offset, shift);
+            }
+            output(&self.out);
+        }
+    }
+
+    fn finish<F>(&mut self, _output: F)
+        where F: FnMut(&[u32])
+    {
+    }
+}
+
+/// Subfingerprint along with how reliable each of its bits is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReliableSubfingerprint {
+    pub value: u32,
+
+    /// Distance of the filter response from the quantizer threshold that would flip each bit,
+    /// relative to the quantizer range and indexed by bit number. Bits with low reliability are
+    /// the first to flip in noisy audio. Bits no classifier produces are `f32::INFINITY`.
+    pub reliability: [f32; 32],
+}
+
+/// Same as `Calculator::new()` but also outputs the bit reliability.
+pub struct ReliabilityCalculator {
+    calculator: Calculator,
+    out: Vec<ReliableSubfingerprint>,
+}
+
+impl ReliabilityCalculator {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self {
+            calculator: Calculator::new(algorithm),
+            out: Vec::with_capacity(1),
+        }
+    }
+
+    fn subfingerprint(&self, offset: usize) -> ReliableSubfingerprint {
+        let c = &self.calculator;
+        let mut r = ReliableSubfingerprint {
+            value: 0,
+            reliability: [f32::INFINITY; 32],
+        };
+        for (i, classifier) in c.classifiers.iter().enumerate() {
+            let (v, (high, low)) = classifier.classify_with_reliability(&c.image, offset, 0);
+            r.value = (r.value << 2) | GRAY_CODE[v as usize];
+            let bit = 2 * (c.classifiers.len() - 1 - i);
+            r.reliability[bit + 1] = high as f32;
+            r.reliability[bit] = low as f32;
+        }
+        r
+    }
+}
+
+impl Step<f64, ReliableSubfingerprint> for ReliabilityCalculator {
+    fn process<F>(&mut self, input: &[f64], mut output: F)
+        where F: FnMut(&[ReliableSubfingerprint])
+    {
+        let image = &mut self.calculator.image;
+        image.push(input);
+        let max_filter_width = self.calculator.max_filter_width;
+        if image.height() >= max_filter_width {
+            let offset = image.height() - max_filter_width;
+            let item = self.subfingerprint(offset);
+            self.out.clear();
+            self.out.push(item);
+            output(&self.out);
+        }
+    }
+
+    fn finish<F>(&mut self, _output: F)
+        where F: FnMut(&[ReliableSubfingerprint])
+    {
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pipeline::test_util::*;
+
+    fn frames() -> Vec<Vec<f64>> {
+        (0..40)
+            .map(|i| (0..BAND_COUNT)
+                .map(|b| ((i * 7 + b * b * 3) % 11) as f64 / 10.0)
+                .collect())
+            .collect()
+    }
+
+    #[test]
+    fn warm_up() {
+        let c = &mut Calculator::new(Algorithm::Test2);
+        let frames = frames();
+        let max_filter_width = Algorithm::Test2.fp_config().max_filter_width as usize;
+
+        let mut act = Vec::new();
+        for frame in &frames {
+            act.append(&mut process(c, frame));
+        }
+
+        assert_eq!(act.len(), frames.len() - max_filter_width + 1);
+        assert!(act.iter().all(|v| v.len() == 1));
+    }
+
+    #[test]
+    fn transposed() {
+        let frames = frames();
+        let t = &mut Calculator::transposed(Algorithm::Test2);
+
+        let mut transposed = Vec::new();
+        for frame in &frames {
+            transposed.append(&mut process(t, frame));
+        }
+
+        for shift in 0..BAND_COUNT {
+            let c = &mut Calculator::new(Algorithm::Test2);
+            let mut exp = Vec::new();
+            for frame in &frames {
+                let rotated: Vec<_> = (0..BAND_COUNT)
+                    .map(|b| frame[(b + shift) % BAND_COUNT])
+                    .collect();
+                exp.extend(process_flat(c, &rotated));
+            }
+
+            let act: Vec<_> = transposed.iter().map(|v| v[shift]).collect();
+            assert_eq!(act, exp, "{}", shift);
+        }
+    }
+
+    #[test]
+    fn reliability() {
+        let frames = frames();
+        let c = &mut Calculator::new(Algorithm::Test2);
+        let r = &mut ReliabilityCalculator::new(Algorithm::Test2);
+        let mut exp = Vec::new();
+        let mut act = Vec::new();
+        for frame in &frames {
+            exp.extend(process_flat(c, frame));
+            act.extend(process_flat(r, frame));
+        }
+
+        assert_eq!(act.iter().map(|v| v.value).collect::<Vec<_>>(), exp);
+        for v in &act {
+            assert!(v.reliability.iter().all(|&r| r >= 0.0 && r.is_finite()));
+        }
+    }
+}
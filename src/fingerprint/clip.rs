@@ -0,0 +1,112 @@
+use crate::Algorithm;
+use crate::util::hamming_distance;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClipMatch {
+    /// Item of the reference aligned with the first item of the query.
+    pub offset: usize,
+
+    /// Start time in the reference in seconds.
+    pub start: f64,
+
+    /// End time in the reference in seconds.
+    pub end: f64,
+
+    /// Fraction of matching bits, `0..=1`.
+    pub score: f64,
+}
+
+/// Finds every occurrence of a short query, e.g. a 10 second snippet, in a long reference
+/// recording. The query is slid over the whole reference and compared item by item. Most
+/// offsets don't match at all and comparing them stops as soon as the bit errors make
+/// `min_score` unreachable, so long references are scanned quickly.
+/// Occurrences overlapping a better one are dropped. Returned in reference order.
+pub fn find_clip(algorithm: Algorithm, query: &[u32], reference: &[u32], min_score: f64)
+    -> Vec<ClipMatch>
+{
+    assert!((0.0..=1.0).contains(&min_score));
+    if query.is_empty() || query.len() > reference.len() {
+        return Vec::new();
+    }
+
+    let max_errors = ((1.0 - min_score) * (32 * query.len()) as f64).floor() as u32;
+    let mut found: Vec<(usize, u32)> = Vec::new();
+    for offset in 0..=reference.len() - query.len() {
+        let mut errors = 0;
+        for (&q, &r) in query.iter().zip(&reference[offset..]) {
+            errors += hamming_distance(q, r);
+            if errors > max_errors {
+                break;
+            }
+        }
+        if errors <= max_errors {
+            found.push((offset, errors));
+        }
+    }
+
+    // Neighbouring offsets of an occurrence tend to match too, keep the best ones.
+    found.sort_by_key(|&(offset, errors)| (errors, offset));
+    let mut kept: Vec<(usize, u32)> = Vec::new();
+    for (offset, errors) in found {
+        if kept.iter().all(|&(o, _)| o + query.len() <= offset || offset + query.len() <= o) {
+            kept.push((offset, errors));
+        }
+    }
+    kept.sort_unstable();
+
+    let item_duration = algorithm.fp_config().item_duration_in_seconds();
+    kept.into_iter()
+        .map(|(offset, errors)| ClipMatch {
+            offset,
+            start: offset as f64 * item_duration,
+            end: (offset + query.len()) as f64 * item_duration,
+            score: 1.0 - errors as f64 / (32 * query.len()) as f64,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let mut reference: Vec<u32> = (0..5000).map(|_| rng.gen()).collect();
+        let query: Vec<u32> = reference[1000..1080].to_vec();
+        // Second occurrence with some noise.
+        for (i, &v) in query.iter().enumerate() {
+            reference[4000 + i] = v ^ if i % 2 == 0 { 0b101 } else { 0 };
+        }
+
+        let item_duration = Algorithm::Test2.fp_config().item_duration_in_seconds();
+        let act = find_clip(Algorithm::Test2, &query, &reference, 0.9);
+        assert_eq!(act, &[
+            ClipMatch {
+                offset: 1000,
+                start: 1000.0 * item_duration,
+                end: 1080.0 * item_duration,
+                score: 1.0,
+            },
+            ClipMatch {
+                offset: 4000,
+                start: 4000.0 * item_duration,
+                end: 4080.0 * item_duration,
+                score: 1.0 - 80.0 / (32.0 * 80.0),
+            },
+        ]);
+
+        assert_eq!(find_clip(Algorithm::Test2, &query, &reference, 0.99).len(), 1);
+        assert_eq!(find_clip(Algorithm::Test2, &query, &reference[..1050], 0.8), &[]);
+        assert_eq!(find_clip(Algorithm::Test2, &[], &reference, 0.5), &[]);
+    }
+
+    #[test]
+    fn overlapping() {
+        // Constant query matches everywhere in a constant reference.
+        let act = find_clip(Algorithm::Test2, &[7; 10], &[7; 35], 0.9);
+        assert_eq!(act.iter().map(|m| m.offset).collect::<Vec<_>>(), &[0, 10, 20]);
+    }
+}
@@ -1,4 +1,24 @@
+pub mod base64;
+mod decoder;
 mod encoder;
 
+pub use decoder::Decoder;
+pub use encoder::Encoder;
+
 const NORMAL_BITS: u8 = 3;
-const MAX_NORMAL_VALUE: u8 = (1 << NORMAL_BITS) - 1;
\ No newline at end of file
+const MAX_NORMAL_VALUE: u8 = (1 << NORMAL_BITS) - 1;
+const EXCEPTIONAL_BITS: u8 = 5;
+
+/// Compresses a fingerprint in the libchromaprint format. `version` is usually the id of the
+/// algorithm, see `Algorithm::id()`.
+pub fn compress(fingerprint: &[u32], version: u8) -> Vec<u8> {
+    let mut r = Vec::new();
+    Encoder::new().encode(fingerprint, version, &mut r);
+    r
+}
+
+/// Decompresses a fingerprint in the libchromaprint format, returning it along with the
+/// version. Returns `None` if the data is truncated or corrupted.
+pub fn decompress(data: &[u8]) -> Option<(Vec<u32>, u8)> {
+    Decoder::new().decode(data)
+}
@@ -0,0 +1,72 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encodes with the URL-safe alphabet and without padding, like libchromaprint does for
+/// compressed fingerprints.
+pub fn encode(inp: &[u8]) -> String {
+    let mut r = String::with_capacity(inp.len() * 4 / 3 + 3);
+    for chunk in inp.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let v = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        for i in 0..=chunk.len() {
+            r.push(ALPHABET[v >> (18 - 6 * i) & 0x3f] as char);
+        }
+    }
+    r
+}
+
+/// Decodes what `encode()` produces. Returns `None` on characters outside of the alphabet or
+/// a truncated last group.
+pub fn decode(inp: &[u8]) -> Option<Vec<u8>> {
+    if inp.len() % 4 == 1 {
+        return None;
+    }
+    let mut r = Vec::with_capacity(inp.len() * 3 / 4);
+    for chunk in inp.chunks(4) {
+        let mut v = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            v |= value(c)? << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            r.push((v >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(r)
+}
+
+fn value(c: u8) -> Option<u32> {
+    Some(match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'-' => 62,
+        b'_' => 63,
+        _ => return None,
+    } as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let data: &[(&[u8], &str)] = &[
+            (b"", ""),
+            (b"x", "eA"),
+            (b"xx", "eHg"),
+            (b"xxx", "eHh4"),
+            (b"xxxx", "eHh4eA"),
+            (b"Man", "TWFu"),
+            (&[0xfb, 0xff, 0xfe], "-__-"),
+            (&[55, 0, 0, 2, 65, 0], "NwAAAkEA"),
+        ];
+        for &(bytes, text) in data {
+            assert_eq!(encode(bytes), text);
+            assert_eq!(decode(text.as_bytes()).as_deref(), Some(bytes));
+        }
+
+        assert_eq!(decode(b"eHh4e"), None);
+        assert_eq!(decode(b"eH=="), None);
+        assert_eq!(decode(b"eH+/"), None);
+    }
+}
@@ -0,0 +1,154 @@
+use super::*;
+
+pub struct Decoder {
+    normal_bits: Vec<u8>,
+    exceptional_bits: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            normal_bits: Vec::new(),
+            exceptional_bits: Vec::new(),
+        }
+    }
+
+    /// Returns the fingerprint and version or `None` if `inp` isn't a valid compressed
+    /// fingerprint.
+    pub fn decode(&mut self, inp: &[u8]) -> Option<(Vec<u32>, u8)> {
+        if inp.len() < 4 {
+            return None;
+        }
+        let version = inp[0];
+        let len = (inp[1] as usize) << 16 | (inp[2] as usize) << 8 | inp[3] as usize;
+        let inp = &inp[4..];
+
+        // Normal values of each item end with a zero.
+        self.normal_bits.clear();
+        let mut items = 0;
+        let mut exceptional_count = 0;
+        let mut reader = BitReader::new(inp);
+        while items < len {
+            let v = reader.read(NORMAL_BITS)?;
+            if v == 0 {
+                items += 1;
+            } else if v == MAX_NORMAL_VALUE {
+                exceptional_count += 1;
+            }
+            self.normal_bits.push(v);
+        }
+
+        self.exceptional_bits.clear();
+        let mut reader = BitReader::new(&inp[reader.bytes_read..]);
+        for _ in 0..exceptional_count {
+            self.exceptional_bits.push(reader.read(EXCEPTIONAL_BITS)?);
+        }
+
+        let mut r = Vec::with_capacity(len);
+        let mut exceptional = self.exceptional_bits.iter();
+        let mut x = 0u32;
+        let mut last_bit = 0;
+        for &v in &self.normal_bits {
+            if v == 0 {
+                let prev = r.last().cloned().unwrap_or(0);
+                r.push(x ^ prev);
+                x = 0;
+                last_bit = 0;
+                continue;
+            }
+            let mut v = v as u32;
+            if v == MAX_NORMAL_VALUE as u32 {
+                v += *exceptional.next().unwrap() as u32;
+            }
+            last_bit += v;
+            if last_bit > 32 {
+                return None;
+            }
+            x |= 1 << (last_bit - 1);
+        }
+        Some((r, version))
+    }
+}
+
+/// Reads packed values starting from the least significant bits.
+struct BitReader<'a> {
+    inp: &'a [u8],
+    pos: usize,
+
+    /// Number of bytes the values read so far occupy.
+    bytes_read: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(inp: &'a [u8]) -> Self {
+        Self {
+            inp,
+            pos: 0,
+            bytes_read: 0,
+        }
+    }
+
+    fn read(&mut self, bits: u8) -> Option<u8> {
+        let mut r = 0;
+        for i in 0..bits {
+            let byte = *self.inp.get(self.pos / 8)?;
+            self.bytes_read = self.pos / 8 + 1;
+            r |= (byte >> (self.pos % 8) & 1) << i;
+            self.pos += 1;
+        }
+        Some(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test() {
+        // expected, input
+        let data = &[
+            (&[1][..], &[0, 0, 0, 1, 1][..]),
+            (&[7][..], &[0, 0, 0, 1, 73, 0][..]),
+            (&[1 << 6][..], &[0, 0, 0, 1, 7, 0][..]),
+            (&[1 << 8][..], &[0, 0, 0, 1, 7, 2][..]),
+            (&[1, 0][..], &[0, 0, 0, 2, 65, 0][..]),
+            (&[1, 1][..], &[0, 0, 0, 2, 1, 0][..]),
+            (&[][..], &[55, 0, 0, 0][..]),
+        ];
+        let mut d = Decoder::new();
+        for &(exp, inp) in data {
+            assert_eq!(d.decode(inp), Some((exp.to_vec(), inp[0])));
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let mut d = Decoder::new();
+        assert_eq!(d.decode(&[]), None);
+        assert_eq!(d.decode(&[0, 0, 0]), None);
+        // Missing items.
+        assert_eq!(d.decode(&[0, 0, 0, 3, 1]), None);
+        // Missing exceptional bits.
+        assert_eq!(d.decode(&[0, 0, 0, 1, 7]), None);
+        // Bit past the end of the subfingerprint.
+        assert_eq!(d.decode(&[0, 0, 0, 1, 7, 31]), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let mut e = Encoder::new();
+        let mut d = Decoder::new();
+        for len in 0..50 {
+            let fp: Vec<u32> = (0..len)
+                .map(|_| rng.gen::<u32>() >> rng.gen_range(0, 32))
+                .collect();
+            let data = &mut Vec::new();
+            e.encode(&fp, 1, data);
+            assert_eq!(d.decode(data), Some((fp, 1)));
+        }
+    }
+}
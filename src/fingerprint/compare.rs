@@ -0,0 +1,254 @@
+use crate::util::hamming_distance;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Match {
+    /// Item in the second fingerprint aligned with the first item of the first fingerprint.
+    /// Negative if the first fingerprint starts earlier.
+    pub offset: isize,
+
+    /// Fraction of matching bits in the overlapping items, `0..=1`.
+    pub score: f64,
+}
+
+/// Finds the offset at which the two fingerprints match best, checking all offsets with
+/// absolute value up to `max_offset` where at least half of the shorter fingerprint overlaps.
+pub fn best_match(a: &[u32], b: &[u32], max_offset: usize) -> Option<Match> {
+    let min_len = a.len().min(b.len());
+    let min_overlap = min_len - min_len / 2;
+    if min_overlap == 0 {
+        return None;
+    }
+
+    let max_offset = max_offset as isize;
+    let lo = (min_overlap as isize - a.len() as isize).max(-max_offset);
+    let hi = (b.len() as isize - min_overlap as isize).min(max_offset);
+
+    let mut best: Option<Match> = None;
+    for offset in lo..=hi {
+        let score = score_at(a, b, offset);
+        if best.map(|m| score > m.score).unwrap_or(true) {
+            best = Some(Match {
+                offset,
+                score,
+            });
+        }
+    }
+    best
+}
+
+/// Fraction of matching bits where `a` overlaps `b` when aligned at `offset`.
+pub fn score_at(a: &[u32], b: &[u32], offset: isize) -> f64 {
+    let (a, b) = if offset >= 0 {
+        (a, &b[(offset as usize).min(b.len())..])
+    } else {
+        (&a[((-offset) as usize).min(a.len())..], b)
+    };
+    let len = a.len().min(b.len());
+    if len == 0 {
+        return 0.0;
+    }
+    let errors: u32 = a.iter().zip(b).map(|(&a, &b)| hamming_distance(a, b)).sum();
+    1.0 - errors as f64 / (32 * len) as f64
+}
+
+/// SimHash of a fingerprint as computed by libchromaprint: each bit is set if it's set in more
+/// than half of the subfingerprints. Similar fingerprints have hashes differing in few bits.
+pub fn hash(fp: &[u32]) -> u32 {
+    let mut counts = [0isize; 32];
+    for &v in fp {
+        for (bit, c) in counts.iter_mut().enumerate() {
+            *c += if v >> bit & 1 != 0 { 1 } else { -1 };
+        }
+    }
+    counts.iter()
+        .enumerate()
+        .filter(|&(_, &c)| c > 0)
+        .fold(0, |r, (bit, _)| r | 1 << bit)
+}
+
+const MATCH_BITS: u32 = 14;
+const MATCH_MASK: usize = (1 << MATCH_BITS) - 1;
+
+fn match_strip(v: u32) -> usize {
+    (v >> (32 - MATCH_BITS)) as usize
+}
+
+/// Similarity score as computed by the `acoustid_compare2()` function of the AcoustID server,
+/// `0..=1`. `max_offset` of zero means no offset limit.
+///
+/// This mirrors the reference implementation including its quirks, so the results are identical
+/// to the server's: the best offset is voted for only by the last occurrence of each top
+/// 14-bit key, keys first seen at index 0 don't vote, and the `seen` table reuses the memory of
+/// the offset table without fully clearing it. Intermediate values are rounded to `f32` where
+/// the reference stores them in `float` variables.
+pub fn acoustid_compare2(a: &[u32], b: &[u32], max_offset: usize) -> f32 {
+    let mut a_offsets = vec![0u16; MATCH_MASK + 1];
+    let mut b_offsets = vec![0u16; MATCH_MASK + 1];
+    for (i, &v) in a.iter().enumerate() {
+        a_offsets[match_strip(v)] = i as u16;
+    }
+    for (i, &v) in b.iter().enumerate() {
+        b_offsets[match_strip(v)] = i as u16;
+    }
+
+    let max_offset = max_offset as isize;
+    let mut counts = vec![0u16; a.len() + b.len() + 1];
+    let mut top_count = 0;
+    let mut top_offset = 0;
+    for (&ao, &bo) in a_offsets.iter().zip(&b_offsets).take(MATCH_MASK) {
+        if ao == 0 || bo == 0 {
+            continue;
+        }
+        let offset = ao as isize - bo as isize;
+        if max_offset == 0 || (-max_offset..=max_offset).contains(&offset) {
+            let i = (offset + b.len() as isize) as usize;
+            counts[i] = counts[i].wrapping_add(1);
+            if counts[i] > top_count {
+                top_count = counts[i];
+                top_offset = i as isize;
+            }
+        }
+    }
+    top_offset -= b.len() as isize;
+
+    let min_size = a.len().min(b.len()) & !1;
+    let (a, b) = if top_offset < 0 {
+        (a, &b[((-top_offset) as usize).min(b.len())..])
+    } else {
+        (&a[(top_offset as usize).min(a.len())..], b)
+    };
+    let size = a.len().min(b.len()) / 2;
+    if size == 0 || min_size == 0 {
+        return 0.0;
+    }
+
+    // The reference aliases `seen` with the bytes of the offset table and clears all of it but
+    // the last byte, which keeps the high byte of the offset stored for key 8191.
+    let mut seen = vec![false; MATCH_MASK + 1];
+    seen[MATCH_MASK] = a_offsets[MATCH_MASK / 2] >> 8 != 0;
+    let mut unique_count = |v: &[u32]| {
+        for s in &mut seen[..MATCH_MASK] {
+            *s = false;
+        }
+        let mut r = 0;
+        for &v in v {
+            let key = match_strip(v);
+            if !seen[key] {
+                r += 1;
+                seen[key] = true;
+            }
+        }
+        r
+    };
+    let a_unique = unique_count(a);
+    let b_unique = unique_count(b);
+
+    let key_diversity = |unique: usize, len: usize| {
+        1f64.min(((unique + 10) as f32 / len as f32) as f64 + 0.5)
+    };
+    let diversity = key_diversity(a_unique, a.len()).min(key_diversity(b_unique, b.len())) as f32;
+
+    if (top_count as f64) < a_unique.max(b_unique) as f64 * 0.02 {
+        return 0.0;
+    }
+
+    let bit_errors: u32 = a[..size * 2].iter()
+        .zip(&b[..size * 2])
+        .map(|(&a, &b)| hamming_distance(a, b))
+        .sum();
+    let mut score = ((size as f64 * 2.0 / min_size as f64) *
+        (1.0 - 2.0 * (bit_errors as f32) as f64 / (64 * size) as f64)) as f32;
+    if score < 0.0 {
+        score = 0.0;
+    }
+    if diversity < 1.0 {
+        score = (score as f64).powf(8.0 - 7.0 * diversity as f64) as f32;
+    }
+    score
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let b: Vec<u32> = (0..50u32).map(|i| i.wrapping_mul(2654435761)).collect();
+
+        let a = &b[10..30];
+        assert_eq!(best_match(a, &b, 100), Some(Match { offset: 10, score: 1.0 }));
+        assert_eq!(best_match(&b, a, 100), Some(Match { offset: -10, score: 1.0 }));
+
+        let mut noisy = a.to_vec();
+        noisy[0] ^= 0xff;
+        let m = best_match(&noisy, &b, 100).unwrap();
+        assert_eq!(m.offset, 10);
+        assert_eq!(m.score, 1.0 - 8.0 / (32.0 * 20.0));
+
+        assert_eq!(best_match(a, &b, 5).map(|m| m.offset == 10), Some(false));
+        assert_eq!(best_match(&[], &b, 5), None);
+    }
+
+    #[test]
+    fn hash_fn() {
+        assert_eq!(hash(&[]), 0);
+        assert_eq!(hash(&[0]), 0);
+        assert_eq!(hash(&[1]), 1);
+        assert_eq!(hash(&[7, 1]), 1);
+        assert_eq!(hash(&[7, 3, 1]), 3);
+        assert_eq!(hash(&[19681, 22345, 312312, 453425]), 17249);
+    }
+
+    /// Fingerprint-like sequence: each item differs from the previous one in a few bits.
+    fn walk(seed: u32, len: usize, flips: u32) -> Vec<u32> {
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let mut v = next();
+        (0..len)
+            .map(|_| {
+                for _ in 0..flips {
+                    v ^= 1 << (next() % 32);
+                }
+                v
+            })
+            .collect()
+    }
+
+    fn flip_bits(v: &mut [u32], every: usize) {
+        for (i, v) in v.iter_mut().enumerate().step_by(every) {
+            *v ^= 1 << (i % 32);
+        }
+    }
+
+    #[test]
+    fn acoustid_compare2_fn() {
+        let base = walk(1, 600, 2);
+
+        // Expected values are computed by the reference implementation.
+        let a = &base[100..400];
+        assert_eq!(acoustid_compare2(a, a, 0), 1.0);
+        assert_eq!(acoustid_compare2(a, &base, 0), 1.0);
+        assert_eq!(acoustid_compare2(&base, a, 120), 1.0);
+        assert_eq!(acoustid_compare2(&base, a, 50), 0.0);
+
+        let noisy = &mut base[150..551].to_vec();
+        flip_bits(noisy, 3);
+        assert_eq!(acoustid_compare2(a, noisy, 0).to_bits(), 0x3f50da74);
+        assert_eq!(acoustid_compare2(noisy, a, 0).to_bits(), 0x3f50da74);
+
+        // Low key diversity.
+        let slow = &walk(2, 300, 1);
+        let slow_noisy = &mut slow[7..].to_vec();
+        flip_bits(slow_noisy, 2);
+        assert_eq!(acoustid_compare2(slow, slow_noisy, 0).to_bits(), 0x3f74bad8);
+
+        assert_eq!(acoustid_compare2(a, &walk(3, 300, 2), 0), 0.0);
+        assert_eq!(acoustid_compare2(&[], a, 0), 0.0);
+        assert_eq!(acoustid_compare2(&a[..1], a, 0), 0.0);
+    }
+}
@@ -0,0 +1,223 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::collections::{HashMap, HashSet};
+
+use crate::util::DisjointSet;
+use super::compare::acoustid_compare2;
+
+/// Number of features: each of the 16 2-bit classifier values in a subfingerprint.
+const FEATURE_COUNT: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DedupConfig {
+    /// Number of LSH bands. Fingerprints sharing the hash of any band are compared.
+    pub bands: usize,
+
+    /// Number of SimHash bits per band, up to 64.
+    pub band_bits: usize,
+
+    /// Buckets with more fingerprints than this are skipped, usually these are near silent files.
+    pub max_bucket_len: usize,
+
+    /// Minimum `acoustid_compare2()` score of duplicates.
+    pub min_score: f32,
+
+    /// Maximum offset between duplicates in items, zero means no limit.
+    pub max_offset: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            bands: 8,
+            band_bits: 16,
+            max_bucket_len: 1000,
+            min_score: 0.6,
+            max_offset: 80,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateCluster {
+    /// Indexes of the fingerprints, ascending.
+    pub members: Vec<usize>,
+
+    /// Scores of every pair of members as `(a, b, score)` with `a < b`.
+    pub scores: Vec<(usize, usize, f32)>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DedupStats {
+    /// Number of pairs compared with the full matcher.
+    pub comparisons: usize,
+
+    /// Number of buckets skipped for being too large.
+    pub skipped_buckets: usize,
+}
+
+/// Groups duplicates among many fingerprints, e.g. of a whole music library, without comparing
+/// every pair.
+/// Each fingerprint is summarized by how often each classifier value occurs in it, which
+/// doesn't depend on offsets and changes little with encoding noise. SimHash of the summary is
+/// split into bands and only fingerprints sharing a band are compared with
+/// `acoustid_compare2()`. Pairs scoring at least `min_score` are joined into clusters.
+/// Returns clusters of two or more fingerprints ordered by their first member.
+pub fn find_duplicates(fingerprints: &[Vec<u32>], config: &DedupConfig)
+    -> (Vec<DuplicateCluster>, DedupStats)
+{
+    assert!(config.bands > 0);
+    assert!(config.band_bits > 0 && config.band_bits <= 64);
+
+    // Same hyperplanes on every call so hashes are comparable between runs.
+    let rng = &mut StdRng::seed_from_u64(0);
+    let planes: Vec<Vec<Vec<f64>>> = (0..config.bands)
+        .map(|_| (0..config.band_bits)
+            .map(|_| (0..FEATURE_COUNT).map(|_| rng.gen_range(-1.0, 1.0)).collect())
+            .collect())
+        .collect();
+
+    let mut buckets: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+    for (i, fp) in fingerprints.iter().enumerate() {
+        if fp.is_empty() {
+            continue;
+        }
+        let features = features(fp);
+        for (band, planes) in planes.iter().enumerate() {
+            buckets.entry((band, simhash(&features, planes))).or_default().push(i);
+        }
+    }
+
+    let mut stats = DedupStats::default();
+    let mut candidates = HashSet::new();
+    for bucket in buckets.values() {
+        if bucket.len() > config.max_bucket_len {
+            stats.skipped_buckets += 1;
+            continue;
+        }
+        for (k, &a) in bucket.iter().enumerate() {
+            for &b in &bucket[k + 1..] {
+                candidates.insert((a, b));
+            }
+        }
+    }
+
+    let compare = |a: usize, b: usize| {
+        acoustid_compare2(&fingerprints[a], &fingerprints[b], config.max_offset)
+    };
+    let sets = &mut DisjointSet::new(fingerprints.len());
+    let mut scores = HashMap::new();
+    for (a, b) in candidates {
+        let score = compare(a, b);
+        stats.comparisons += 1;
+        scores.insert((a, b), score);
+        if score >= config.min_score {
+            sets.union(a, b);
+        }
+    }
+
+    let mut clusters: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..fingerprints.len() {
+        clusters.entry(sets.find(i)).or_default().push(i);
+    }
+    let mut r: Vec<_> = clusters.into_values()
+        .filter(|members| members.len() > 1)
+        .map(|members| {
+            // Members can be linked through others without being compared directly.
+            let mut pair_scores = Vec::new();
+            for (k, &a) in members.iter().enumerate() {
+                for &b in &members[k + 1..] {
+                    let score = scores.get(&(a, b)).cloned().unwrap_or_else(|| {
+                        stats.comparisons += 1;
+                        compare(a, b)
+                    });
+                    pair_scores.push((a, b, score));
+                }
+            }
+            DuplicateCluster {
+                members,
+                scores: pair_scores,
+            }
+        })
+        .collect();
+    r.sort_by_key(|c| c.members[0]);
+    (r, stats)
+}
+
+/// Fraction of subfingerprints having each value of each classifier, centered around zero.
+fn features(fp: &[u32]) -> Vec<f64> {
+    let mut r = vec![0.0; FEATURE_COUNT];
+    for &v in fp {
+        for classifier in 0..FEATURE_COUNT / 4 {
+            r[classifier * 4 + (v >> (2 * classifier) & 3) as usize] += 1.0;
+        }
+    }
+    for v in &mut r {
+        *v = *v / fp.len() as f64 - 0.25;
+    }
+    r
+}
+
+/// One bit per hyperplane: whether `features` lie on its positive side.
+fn simhash(features: &[f64], planes: &[Vec<f64>]) -> u64 {
+    planes.iter()
+        .enumerate()
+        .filter(|(_, p)| p.iter().zip(features).map(|(p, f)| p * f).sum::<f64>() > 0.0)
+        .fold(0, |r, (i, _)| r | 1 << i)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        // Biased bits like in real fingerprints, so the features differ between tracks.
+        let mut track = |len: usize| -> Vec<u32> {
+            let bias: Vec<f64> = (0..32).map(|_| rng.gen_range(0.1, 0.9)).collect();
+            (0..len)
+                .map(|_| (0..32).filter(|&b| rng.gen_bool(bias[b])).fold(0, |r, b| r | 1 << b))
+                .collect()
+        };
+        let mut fingerprints: Vec<Vec<u32>> = (0..300).map(|_| track(600)).collect();
+
+        // Duplicates with other offsets, lengths and some noise.
+        let rng = &mut StdRng::seed_from_u64(2);
+        let groups = [(3, vec![150, 220]), (40, vec![7]), (100, vec![10, 11, 290])];
+        for (original, copies) in &groups {
+            for (k, &copy) in copies.iter().enumerate() {
+                let offset = 5 * (k + 1);
+                fingerprints[copy] = fingerprints[*original][offset..]
+                    .iter()
+                    .map(|&v| if rng.gen_bool(0.1) { v ^ 1 << rng.gen_range(0, 32) } else { v })
+                    .collect();
+            }
+        }
+        fingerprints[50].clear();
+
+        let (clusters, stats) = find_duplicates(&fingerprints, &DedupConfig::default());
+        let members: Vec<_> = clusters.iter().map(|c| c.members.clone()).collect();
+        assert_eq!(members, &[
+            vec![3, 150, 220],
+            vec![7, 40],
+            vec![10, 11, 100, 290],
+        ]);
+        assert!(stats.comparisons < 300 * 299 / 20, "{:?}", stats);
+
+        let c = &clusters[2];
+        assert_eq!(c.scores.len(), 6);
+        for &(a, b, score) in &c.scores {
+            assert!(a < b);
+            assert!(score > 0.6, "{}", score);
+            assert_eq!(score, acoustid_compare2(&fingerprints[a], &fingerprints[b], 80));
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let (clusters, stats) = find_duplicates(&[], &DedupConfig::default());
+        assert_eq!(clusters, &[]);
+        assert_eq!(stats, DedupStats::default());
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use crate::Algorithm;
+use crate::index::DEFAULT_MASK;
+use crate::util::{hamming_distance, DisjointSet};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RepeatConfig {
+    /// Minimum length of a repeated segment in items.
+    pub min_len: usize,
+
+    /// Maximum number of items between exact matches of the same repetition.
+    pub max_gap: usize,
+
+    /// Minimum fraction of matching bits between two occurrences, `0..=1`.
+    pub min_score: f64,
+
+    /// Subfingerprints occurring more often than this are ignored. These are usually silence or
+    /// noise and would make the number of compared pairs explode.
+    pub max_key_count: usize,
+}
+
+impl Default for RepeatConfig {
+    /// Finds segments of ~5 seconds or longer.
+    fn default() -> Self {
+        Self {
+            min_len: 40,
+            max_gap: 16,
+            min_score: 0.75,
+            max_key_count: 64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Occurrence {
+    /// First item of the occurrence.
+    pub offset: usize,
+
+    /// Number of items.
+    pub len: usize,
+
+    /// Start time in seconds.
+    pub start: f64,
+
+    /// End time in seconds.
+    pub end: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepeatedSegment {
+    /// At least two, in recording order.
+    pub occurrences: Vec<Occurrence>,
+}
+
+/// Finds segments occurring more than once in a recording, e.g. ads, jingles and station idents
+/// in a broadcast, without any reference fingerprints.
+/// The recording is matched against itself: subfingerprints are indexed and every pair of equal
+/// ones adds a hit on the diagonal of their distance. Runs of hits on a diagonal are verified
+/// and extended item by item, and the resulting pairs of occurrences are grouped into segments.
+/// Returned in the order of first occurrence.
+pub fn find_repeats(algorithm: Algorithm, fingerprint: &[u32], config: &RepeatConfig)
+    -> Vec<RepeatedSegment>
+{
+    assert!(config.min_len > 0);
+    assert!((0.0..=1.0).contains(&config.min_score));
+
+    let mut positions: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (i, &v) in fingerprint.iter().enumerate() {
+        positions.entry(v & DEFAULT_MASK).or_default().push(i as u32);
+    }
+
+    // Diagonal (distance between the occurrences) -> positions of the first occurrence.
+    // Occurrences may not overlap so diagonals shorter than `min_len` are skipped.
+    let mut diagonals: HashMap<u32, Vec<u32>> = HashMap::new();
+    for pos in positions.values().filter(|v| v.len() <= config.max_key_count) {
+        for (i, &a) in pos.iter().enumerate() {
+            for &b in pos[i + 1..].iter().filter(|&&b| b - a >= config.min_len as u32) {
+                diagonals.entry(b - a).or_default().push(a);
+            }
+        }
+    }
+
+    let max_item_errors = ((1.0 - config.min_score) * 32.0).floor() as u32;
+    let mut pairs = Vec::new();
+    for (lag, mut hits) in diagonals {
+        let lag = lag as usize;
+        hits.sort_unstable();
+        let mut run_start = 0;
+        for i in 1..=hits.len() {
+            if i < hits.len() && (hits[i] - hits[i - 1]) as usize <= config.max_gap {
+                continue;
+            }
+            let first = hits[run_start] as usize;
+            let last = hits[i - 1] as usize;
+            run_start = i;
+
+            // Exact matches are sparse in noisy occurrences, extend to the similar items around.
+            let similar = |a: usize| hamming_distance(fingerprint[a], fingerprint[a + lag]) <=
+                max_item_errors;
+            let mut start = first;
+            while start > 0 && similar(start - 1) {
+                start -= 1;
+            }
+            let mut end = last + 1;
+            while end < start + lag && end + lag < fingerprint.len() && similar(end) {
+                end += 1;
+            }
+            let len = end - start;
+            if len < config.min_len {
+                continue;
+            }
+            let errors: u32 = (start..end)
+                .map(|a| hamming_distance(fingerprint[a], fingerprint[a + lag]))
+                .sum();
+            if 1.0 - errors as f64 / (32 * len) as f64 >= config.min_score {
+                pairs.push((start, start + lag, len));
+            }
+        }
+    }
+    pairs.sort_unstable();
+
+    // Each pair links two occurrences. Occurrences overlapping by more than half are the same
+    // occurrence found through different pairs or neighbouring diagonals.
+    let spans: Vec<(usize, usize)> = pairs.iter()
+        .flat_map(|&(a, b, len)| vec![(a, a + len), (b, b + len)])
+        .collect();
+    let sets = &mut DisjointSet::new(spans.len());
+    for i in 0..pairs.len() {
+        sets.union(2 * i, 2 * i + 1);
+    }
+    let mut order: Vec<usize> = (0..spans.len()).collect();
+    order.sort_by_key(|&i| spans[i]);
+    for (k, &i) in order.iter().enumerate() {
+        for &j in &order[k + 1..] {
+            if spans[j].0 >= spans[i].1 {
+                break;
+            }
+            let overlap = spans[i].1.min(spans[j].1) - spans[j].0;
+            let shorter = (spans[i].1 - spans[i].0).min(spans[j].1 - spans[j].0);
+            if 2 * overlap > shorter {
+                sets.union(i, j);
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for &i in &order {
+        groups.entry(sets.find(i)).or_default().push(spans[i]);
+    }
+    let item_duration = algorithm.fp_config().item_duration_in_seconds();
+    let mut r: Vec<_> = groups.into_values()
+        .map(|spans| {
+            // Spans are sorted, merge the overlapping ones into occurrences.
+            let mut merged: Vec<(usize, usize)> = Vec::new();
+            for (start, end) in spans {
+                match merged.last_mut() {
+                    Some(last) if start < last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            RepeatedSegment {
+                occurrences: merged.into_iter()
+                    .map(|(start, end)| Occurrence {
+                        offset: start,
+                        len: end - start,
+                        start: start as f64 * item_duration,
+                        end: end as f64 * item_duration,
+                    })
+                    .collect(),
+            }
+        })
+        .filter(|s| s.occurrences.len() > 1)
+        .collect();
+    r.sort_by_key(|s| s.occurrences[0].offset);
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn offsets(s: &RepeatedSegment) -> Vec<(usize, usize)> {
+        s.occurrences.iter().map(|o| (o.offset, o.len)).collect()
+    }
+
+    #[test]
+    fn test() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let mut recording: Vec<u32> = (0..20000).map(|_| rng.gen()).collect();
+        let jingle: Vec<u32> = (0..60).map(|_| rng.gen()).collect();
+        let ad: Vec<u32> = (0..250).map(|_| rng.gen()).collect();
+        for &offset in &[1000, 7000, 15000] {
+            recording[offset..offset + jingle.len()].copy_from_slice(&jingle);
+        }
+        recording[3000..3000 + ad.len()].copy_from_slice(&ad);
+        // Noisy second occurrence of the ad.
+        for (i, &v) in ad.iter().enumerate() {
+            recording[12000 + i] = v ^ if i % 4 == 0 { 0b10101 << (i % 27) } else { 1 << (i % 4) };
+        }
+        // Silence repeats too but isn't reported.
+        for v in &mut recording[18000..19000] {
+            *v = 0x1234_5678;
+        }
+
+        let act = find_repeats(Algorithm::Test2, &recording, &RepeatConfig::default());
+        assert_eq!(act.len(), 2, "{:?}", act);
+        assert_eq!(offsets(&act[0]), &[(1000, 60), (7000, 60), (15000, 60)]);
+        assert_eq!(offsets(&act[1]), &[(3000, 250), (12000, 250)]);
+
+        let item_duration = Algorithm::Test2.fp_config().item_duration_in_seconds();
+        assert_eq!(act[0].occurrences[1].start, 7000.0 * item_duration);
+        assert_eq!(act[0].occurrences[1].end, 7060.0 * item_duration);
+
+        let config = RepeatConfig { min_len: 100, ..Default::default() };
+        let act = find_repeats(Algorithm::Test2, &recording, &config);
+        assert_eq!(act.len(), 1);
+        assert_eq!(offsets(&act[0]), &[(3000, 250), (12000, 250)]);
+    }
+
+    #[test]
+    fn back_to_back() {
+        let rng = &mut StdRng::seed_from_u64(2);
+        let jingle: Vec<u32> = (0..50).map(|_| rng.gen()).collect();
+        let mut recording: Vec<u32> = (0..100).map(|_| rng.gen()).collect();
+        recording.extend_from_slice(&jingle);
+        recording.extend_from_slice(&jingle);
+        recording.extend((0..100).map(|_| rng.gen::<u32>()));
+
+        let act = find_repeats(Algorithm::Test2, &recording, &RepeatConfig::default());
+        assert_eq!(act.len(), 1, "{:?}", act);
+        assert_eq!(offsets(&act[0]), &[(100, 50), (150, 50)]);
+
+        assert_eq!(find_repeats(Algorithm::Test2, &[], &RepeatConfig::default()), &[]);
+    }
+}
@@ -6,14 +6,16 @@ pub struct RollingImage {
     max_height: usize,
 
     /// Total number of rows pushed.
-    /// `row_count % max_height * width` points to where the next row will be written in `data`.
+    /// `row_count % (max_height + 1) * width` points to where the next row will be written in
+    /// `data`. One row more than `max_height` is kept so areas starting at the first row can
+    /// subtract the sums of the row before it.
     row_count: usize,
 }
 
 impl RollingImage {
     pub fn new(width: usize, max_height: usize) -> Self {
         Self {
-            data: vec![0.0; width * max_height],
+            data: vec![0.0; width * (max_height + 1)],
             width,
             row_count: 0,
             max_height,
@@ -45,21 +47,34 @@ impl RollingImage {
         assert!(r2 > r1);
         assert!(c2 > c1);
 
-        if r1 == 0 {
-            let row = self.row(r2 - 1);
-            if c1 == 0 {
-                row[c2 - 1]
+        let first = self.row_count - self.height();
+        let sum = |r: usize, c: usize| {
+            // Sum of all pushed rows before `r` in columns before `c`.
+            if first + r == 0 || c == 0 {
+                0.0
             } else {
-                row[c2 - 1] - row[c1 - 1]
+                self.row(first + r - 1)[c - 1]
             }
+        };
+
+        sum(r2, c2) - sum(r1, c2) - sum(r2, c1) + sum(r1, c1)
+    }
+
+    /// Same as `area()` but on the image with columns circularly rotated left by `shift`,
+    /// i.e. column `c` of the rotated image is column `(c + shift) % width` of this one.
+    pub fn rotated_area(&self, r1: usize, c1: usize, r2: usize, c2: usize, shift: usize) -> f64 {
+        assert!(shift < self.width());
+        assert!(c1 <= c2);
+        assert!(c2 <= self.width());
+
+        let (c1, c2) = (c1 + shift, c2 + shift);
+        let w = self.width();
+        if c2 <= w {
+            self.area(r1, c1, r2, c2)
+        } else if c1 >= w {
+            self.area(r1, c1 - w, r2, c2 - w)
         } else {
-            let row1 = self.row(r1 - 1);
-            let row2 = self.row(r2 - 1);
-            if c1 == 0 {
-                row2[c2 - 1] - row1[c2 - 1]
-            } else {
-                row2[c2 - 1] - row1[c2 - 1] - row2[c1 - 1] + row1[c1 - 1]
-            }
+            self.area(r1, c1, r2, w) + self.area(r1, 0, r2, c2 - w)
         }
     }
 
@@ -88,11 +103,11 @@ impl RollingImage {
     }
 
     fn row_offset(&self, abs_idx: usize) -> usize {
-        (abs_idx % self.max_height) * self.width
+        (abs_idx % (self.max_height + 1)) * self.width
     }
 
-    fn row(&self, rel_idx: usize) -> &[f64] {
-        let i = (self.row_count - self.height() + rel_idx) % self.max_height * self.width;
+    fn row(&self, abs_idx: usize) -> &[f64] {
+        let i = self.row_offset(abs_idx);
         &self.data[i..i + self.width]
     }
 }
@@ -150,6 +165,8 @@ mod test {
 
             (&[16.0, 17.0, 18.0][..],
             &[
+                ((0, 0, 1, 3), 4.0 + 5.0 + 6.0),
+                ((0, 1, 2, 2), 5.0 + 8.0),
                 ((1, 0, 2, 1), 7.0),
                 ((1, 1, 2, 2), 8.0),
                 ((1, 2, 2, 3), 9.0),
@@ -179,4 +196,25 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn rotated_area() {
+        let mut im = RollingImage::new(4, 3);
+        im.push(&[1.0, 2.0, 3.0, 4.0]);
+        im.push(&[10.0, 20.0, 30.0, 40.0]);
+
+        let data = &[
+            // area input, shift, expected
+            ((0, 0, 1, 4), 0, 1.0 + 2.0 + 3.0 + 4.0),
+            ((0, 0, 1, 1), 1, 2.0),
+            ((0, 0, 2, 2), 1, 2.0 + 3.0 + 20.0 + 30.0),
+            ((0, 2, 2, 4), 1, 4.0 + 1.0 + 40.0 + 10.0),
+            ((1, 3, 2, 4), 2, 20.0),
+            ((1, 2, 2, 3), 3, 20.0),
+            ((0, 1, 1, 1), 3, 0.0),
+        ];
+        for &((r1, c1, r2, c2), shift, exp) in data {
+            assert_eq!(im.rotated_area(r1, c1, r2, c2, shift), exp);
+        }
+    }
 }
\ No newline at end of file
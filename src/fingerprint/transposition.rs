@@ -0,0 +1,77 @@
+use super::compare::{best_match, Match};
+use crate::chroma::BAND_COUNT;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransposedMatch {
+    /// How many semitones the query is above the reference.
+    pub semitones: u8,
+
+    pub offset: isize,
+    pub score: f64,
+}
+
+/// Matches a transposed query fingerprint against a reference fingerprint.
+/// `query` is the output of `Fingerprinter::transposed()` or `Calculator::transposed()`:
+/// `BAND_COUNT` subfingerprints per item, one per chroma rotation. Every rotation is matched
+/// against `reference` and the best one is returned.
+pub fn match_transposed(query: &[u32], reference: &[u32], max_offset: usize)
+    -> Option<TransposedMatch>
+{
+    assert_eq!(query.len() % BAND_COUNT, 0);
+
+    let mut best: Option<TransposedMatch> = None;
+    for shift in 0..BAND_COUNT {
+        let q: Vec<u32> = query.chunks(BAND_COUNT).map(|v| v[shift]).collect();
+        if let Some(Match { offset, score }) = best_match(&q, reference, max_offset) {
+            if best.map(|b| score > b.score).unwrap_or(true) {
+                best = Some(TransposedMatch {
+                    semitones: shift as u8,
+                    offset,
+                    score,
+                });
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Algorithm;
+    use crate::fingerprint::Calculator;
+    use crate::pipeline::Step;
+    use crate::pipeline::test_util::*;
+
+    #[test]
+    fn test() {
+        let frames: Vec<Vec<f64>> = (0..80)
+            .map(|i| (0..BAND_COUNT)
+                .map(|b| (((i / 3) * 5 + b * b * 7) % 13) as f64 / 12.0)
+                .collect())
+            .collect();
+
+        let reference = &mut Vec::new();
+        let c = &mut Calculator::new(Algorithm::Test2);
+        for f in &frames {
+            c.process(f, collect_flat(reference));
+        }
+
+        // Query is a part of the reference pitch shifted up by 5 semitones.
+        let query = &mut Vec::new();
+        let c = &mut Calculator::transposed(Algorithm::Test2);
+        for f in &frames[20..60] {
+            let shifted: Vec<_> = (0..BAND_COUNT)
+                .map(|b| f[(b + BAND_COUNT - 5) % BAND_COUNT])
+                .collect();
+            c.process(&shifted, collect_flat(query));
+        }
+
+        let m = match_transposed(query, reference, 100).unwrap();
+        assert_eq!(m, TransposedMatch {
+            semitones: 5,
+            offset: 20,
+            score: 1.0,
+        });
+    }
+}
@@ -0,0 +1,363 @@
+pub mod probe;
+pub mod segment;
+pub mod store;
+mod wal;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+
+pub use probe::{probe_keys, recall, MultiProbe, ProbeStats};
+pub use store::Store;
+
+use crate::fingerprint::ReliableSubfingerprint;
+
+/// Ignores the 4 least significant bits of subfingerprints, like AcoustID does.
+pub const DEFAULT_MASK: u32 = 0xffff_fff0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Posting {
+    pub track_id: u32,
+
+    /// Item of the track fingerprint.
+    pub pos: u32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Candidate {
+    pub track_id: u32,
+
+    /// Number of query items matching the track at `offset`.
+    pub hits: usize,
+
+    /// Item of the track aligned with the first item of the query, see `compare::Match`.
+    pub offset: isize,
+}
+
+/// Inverted index of subfingerprints. Each subfingerprint is masked with the index mask and
+/// maps to the tracks and positions it occurs at.
+pub struct Index {
+    mask: u32,
+    postings: HashMap<u32, Vec<Posting>>,
+    tracks: HashMap<u32, Track>,
+}
+
+/// What's needed to remove a track from the postings.
+struct Track {
+    /// Number of items.
+    len: u32,
+
+    /// Distinct masked keys, see `encode_keys()`.
+    keys: Box<[u8]>,
+}
+
+impl Index {
+    pub fn new(mask: u32) -> Self {
+        assert!(mask != 0);
+        Self {
+            mask,
+            postings: HashMap::new(),
+            tracks: HashMap::new(),
+        }
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub fn contains(&self, track_id: u32) -> bool {
+        self.tracks.contains_key(&track_id)
+    }
+
+    /// Track ids in no particular order.
+    pub fn track_ids(&self) -> impl Iterator<Item=u32> + '_ {
+        self.tracks.keys().cloned()
+    }
+
+    /// Adds the fingerprint of a track, replacing the existing one if any.
+    pub fn insert(&mut self, track_id: u32, fingerprint: &[u32]) {
+        self.remove(track_id);
+        let mut keys: Vec<_> = fingerprint.iter().map(|&v| v & self.mask).collect();
+        for (pos, &key) in keys.iter().enumerate() {
+            self.postings.entry(key).or_default().push(Posting {
+                track_id,
+                pos: pos as u32,
+            });
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        self.tracks.insert(track_id, Track {
+            len: fingerprint.len() as u32,
+            keys: encode_keys(self.mask, &keys),
+        });
+    }
+
+    /// Returns `false` if the track isn't in the index.
+    pub fn remove(&mut self, track_id: u32) -> bool {
+        let track = if let Some(v) = self.tracks.remove(&track_id) {
+            v
+        } else {
+            return false;
+        };
+        for key in decode_keys(self.mask, &track.keys) {
+            if let Entry::Occupied(mut e) = self.postings.entry(key) {
+                e.get_mut().retain(|p| p.track_id != track_id);
+                if e.get().is_empty() {
+                    e.remove();
+                }
+            }
+        }
+        true
+    }
+
+    pub fn postings(&self, key: u32) -> &[Posting] {
+        self.postings.get(&(key & self.mask)).map(|v| &v[..]).unwrap_or(&[])
+    }
+
+    /// Finds tracks that share subfingerprints with the query. For each track only hits at the
+    /// same relative offset are counted and the offset with the most hits is reported.
+    /// Returns up to `max_results` candidates, most hits first.
+    pub fn search(&self, query: &[u32], max_results: usize) -> Vec<Candidate> {
+        search_postings(self, query, max_results)
+    }
+
+    /// Same as `search()` but also looks up keys near each query subfingerprint, see `probe`.
+    pub fn search_multiprobe(
+        &self,
+        query: &[ReliableSubfingerprint],
+        max_results: usize,
+        probe: &MultiProbe) -> (Vec<Candidate>, ProbeStats)
+    {
+        probe::search(self, query, max_results, probe)
+    }
+}
+
+impl Postings for Index {
+    fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    fn for_each_posting<F: FnMut(Posting)>(&self, key: u32, mut f: F) {
+        for &p in self.postings(key) {
+            f(p);
+        }
+    }
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new(DEFAULT_MASK)
+    }
+}
+
+/// Fingerprint collections that can be searched, see `Index::search()`.
+pub trait Search {
+    fn search(&self, query: &[u32], max_results: usize) -> Vec<Candidate>;
+}
+
+impl Search for Index {
+    fn search(&self, query: &[u32], max_results: usize) -> Vec<Candidate> {
+        Index::search(self, query, max_results)
+    }
+}
+
+impl Search for Store {
+    fn search(&self, query: &[u32], max_results: usize) -> Vec<Candidate> {
+        Store::search(self, query, max_results)
+    }
+}
+
+impl<T: Search + ?Sized> Search for &T {
+    fn search(&self, query: &[u32], max_results: usize) -> Vec<Candidate> {
+        (**self).search(query, max_results)
+    }
+}
+
+impl<T: Search + ?Sized> Search for Arc<T> {
+    fn search(&self, query: &[u32], max_results: usize) -> Vec<Candidate> {
+        (**self).search(query, max_results)
+    }
+}
+
+/// Encodes sorted distinct masked keys as LEB128 varints of the differences between them, with
+/// the bits cleared by the mask shifted out. Takes about 3 bytes per key for the default mask.
+fn encode_keys(mask: u32, keys: &[u32]) -> Box<[u8]> {
+    let shift = mask.trailing_zeros();
+    let mut r = Vec::with_capacity(keys.len() * 3);
+    let mut prev = 0;
+    for &key in keys {
+        let mut v = (key >> shift) - prev;
+        prev = key >> shift;
+        while v >= 0x80 {
+            r.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        r.push(v as u8);
+    }
+    r.into_boxed_slice()
+}
+
+fn decode_keys(mask: u32, data: &[u8]) -> impl Iterator<Item=u32> + '_ {
+    let shift = mask.trailing_zeros();
+    let mut bytes = data.iter();
+    let mut prev = 0u32;
+    std::iter::from_fn(move || {
+        let mut v = 0;
+        let mut bit = 0;
+        loop {
+            let b = *bytes.next()?;
+            v |= ((b & 0x7f) as u32) << bit;
+            if b < 0x80 {
+                break;
+            }
+            bit += 7;
+        }
+        prev += v;
+        Some(prev << shift)
+    })
+}
+
+/// Postings searches run against.
+trait Postings {
+    fn mask(&self) -> u32;
+
+    /// Calls `f` with the live postings of the `key`.
+    fn for_each_posting<F: FnMut(Posting)>(&self, key: u32, f: F);
+}
+
+fn search_postings<P: Postings>(postings: &P, query: &[u32], max_results: usize)
+    -> Vec<Candidate>
+{
+    let mut hits: HashMap<(u32, isize), usize> = HashMap::new();
+    for (query_pos, &v) in query.iter().enumerate() {
+        postings.for_each_posting(v, |p| {
+            *hits.entry((p.track_id, p.pos as isize - query_pos as isize)).or_default() += 1;
+        });
+    }
+    rank(hits, max_results)
+}
+
+/// Picks the offset with the most hits for each track from hit counts by track and offset.
+/// Returns up to `max_results` candidates, most hits first.
+fn rank(hits: HashMap<(u32, isize), usize>, max_results: usize) -> Vec<Candidate> {
+    let mut best: HashMap<u32, Candidate> = HashMap::new();
+    for ((track_id, offset), hits) in hits {
+        let c = best.entry(track_id).or_insert(Candidate {
+            track_id,
+            hits: 0,
+            offset,
+        });
+        // Ties go to the smallest offset so results don't depend on hash order.
+        if hits > c.hits || hits == c.hits && offset < c.offset {
+            c.hits = hits;
+            c.offset = offset;
+        }
+    }
+
+    let mut r: Vec<_> = best.into_values().collect();
+    r.sort_by(|a, b| b.hits.cmp(&a.hits).then(a.track_id.cmp(&b.track_id)));
+    r.truncate(max_results);
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_fingerprint(rng: &mut StdRng, len: usize) -> Vec<u32> {
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn search() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let idx = &mut Index::default();
+        let tracks: Vec<_> = (0..100).map(|_| random_fingerprint(rng, 200)).collect();
+        for (i, fp) in tracks.iter().enumerate() {
+            idx.insert(i as u32 * 10, fp);
+        }
+        assert_eq!(idx.len(), 100);
+
+        // Lowest bits are masked out.
+        let query: Vec<_> = tracks[42][50..150].iter()
+            .enumerate()
+            .map(|(i, &v)| if i % 3 == 0 { v ^ 0xf } else if i % 3 == 1 { rng.gen() } else { v })
+            .collect();
+        let r = idx.search(&query, 5);
+        assert_eq!(r[0], Candidate { track_id: 420, hits: 67, offset: 50 });
+        assert!(r.len() == 1 || r[1].hits < 3);
+
+        // Query starting before the track.
+        let mut query = random_fingerprint(rng, 10);
+        query.extend_from_slice(&tracks[7][..50]);
+        assert_eq!(idx.search(&query, 1), &[Candidate { track_id: 70, hits: 50, offset: -10 }]);
+
+        assert!(idx.search(&random_fingerprint(rng, 100), 5).iter().all(|c| c.hits < 3));
+        assert_eq!(idx.search(&query, 0), &[]);
+    }
+
+    #[test]
+    fn offset_consistency() {
+        let idx = &mut Index::new(!0);
+        // Track 1 has all query values but scattered, track 2 has half of them in order.
+        idx.insert(1, &[4, 3, 2, 1, 8, 7, 6, 5]);
+        idx.insert(2, &[0, 0, 1, 2, 3, 4, 0, 0]);
+        let r = idx.search(&[1, 2, 3, 4, 5, 6, 7, 8], 10);
+        assert_eq!(r[0], Candidate { track_id: 2, hits: 4, offset: 2 });
+        assert_eq!(r[1].track_id, 1);
+        assert!(r[1].hits < 4);
+    }
+
+    #[test]
+    fn insert_remove() {
+        let idx = &mut Index::new(!0);
+        idx.insert(1, &[1, 2, 3]);
+        idx.insert(2, &[2, 3, 4]);
+        assert_eq!(idx.postings(2),
+            &[Posting { track_id: 1, pos: 1 }, Posting { track_id: 2, pos: 0 }]);
+
+        // Replace.
+        idx.insert(1, &[5, 6]);
+        assert_eq!(idx.len(), 2);
+        assert_eq!(idx.postings(1), &[]);
+        assert_eq!(idx.postings(2), &[Posting { track_id: 2, pos: 0 }]);
+
+        assert!(idx.remove(2));
+        assert!(!idx.remove(2));
+        assert!(!idx.contains(2));
+        assert_eq!(idx.postings(3), &[]);
+        assert!(idx.postings.keys().all(|&k| k == 5 || k == 6));
+        assert_eq!(idx.search(&[5, 6], 10), &[Candidate { track_id: 1, hits: 2, offset: 0 }]);
+
+        // Repeated keys.
+        idx.insert(3, &[7, 5, 7, 7]);
+        assert_eq!(idx.postings(7).len(), 3);
+        assert!(idx.remove(3));
+        assert_eq!(idx.postings(7), &[]);
+        assert_eq!(idx.postings(5), &[Posting { track_id: 1, pos: 0 }]);
+    }
+
+    #[test]
+    fn keys() {
+        for &mask in &[!0, DEFAULT_MASK, 0x8000_0000] {
+            let mut keys: Vec<_> = [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 0xdead_beef, !0].iter()
+                .map(|&v| v & mask)
+                .collect();
+            keys.dedup();
+            let data = &encode_keys(mask, &keys);
+            assert_eq!(decode_keys(mask, data).collect::<Vec<_>>(), keys);
+        }
+        let data = &encode_keys(DEFAULT_MASK, &[0x10, 0x20, 0x800]);
+        assert_eq!(&data[..], &[1, 1, 0x7e]);
+    }
+}
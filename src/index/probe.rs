@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::{rank, Candidate, Postings};
+use crate::fingerprint::ReliableSubfingerprint;
+
+/// Multi-probe lookup settings. Noise tends to flip a few bits in every subfingerprint, so
+/// exact key lookups miss. Besides the query key itself, keys with up to `radius` bits flipped
+/// are looked up too, starting with the bits whose filter responses are closest to the quantizer
+/// thresholds. At most `budget` keys are looked up per query subfingerprint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MultiProbe {
+    radius: u32,
+    budget: usize,
+}
+
+impl MultiProbe {
+    pub fn new(radius: u32, budget: usize) -> Self {
+        assert!(radius <= 2);
+        assert!(budget > 0);
+        Self {
+            radius,
+            budget,
+        }
+    }
+
+    pub fn radius(&self) -> u32 {
+        self.radius
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+}
+
+impl Default for MultiProbe {
+    fn default() -> Self {
+        Self::new(2, 16)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ProbeStats {
+    /// Keys looked up.
+    pub probes: usize,
+
+    /// Postings visited.
+    pub postings: usize,
+
+    pub elapsed: Duration,
+}
+
+/// Keys to look up for the query subfingerprint, the masked value itself first.
+pub fn probe_keys(item: &ReliableSubfingerprint, mask: u32, probe: &MultiProbe) -> Vec<u32> {
+    let mut bits: Vec<_> = (0..32).filter(|&b| mask & (1 << b) != 0).collect();
+    bits.sort_by(|&a, &b| item.reliability[a].total_cmp(&item.reliability[b]).then(a.cmp(&b)));
+    // A pair is never cheaper than its less reliable bit alone, so pairs of bits past the
+    // budget can't make it.
+    bits.truncate(probe.budget);
+
+    let mut flips: Vec<(f32, u32)> = Vec::new();
+    if probe.radius >= 1 {
+        flips.extend(bits.iter().map(|&b| (item.reliability[b], 1 << b)));
+    }
+    if probe.radius >= 2 {
+        for (i, &a) in bits.iter().enumerate() {
+            for &b in &bits[i + 1..] {
+                flips.push((item.reliability[a] + item.reliability[b], (1 << a) | (1 << b)));
+            }
+        }
+    }
+    flips.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let key = item.value & mask;
+    let mut r = Vec::with_capacity(probe.budget);
+    r.push(key);
+    r.extend(flips.iter().take(probe.budget - 1).map(|&(_, flip)| key ^ flip));
+    r
+}
+
+pub(super) fn search<P: Postings>(
+    postings: &P,
+    query: &[ReliableSubfingerprint],
+    max_results: usize,
+    probe: &MultiProbe) -> (Vec<Candidate>, ProbeStats)
+{
+    let start = Instant::now();
+    let mut stats = ProbeStats::default();
+    let mut hits: HashMap<(u32, isize), usize> = HashMap::new();
+    let item_hits = &mut Vec::new();
+    for (query_pos, item) in query.iter().enumerate() {
+        item_hits.clear();
+        for key in probe_keys(item, postings.mask(), probe) {
+            stats.probes += 1;
+            postings.for_each_posting(key, |p| {
+                stats.postings += 1;
+                item_hits.push((p.track_id, p.pos as isize - query_pos as isize));
+            });
+        }
+        // Several probes of the same item may hit the same track position.
+        item_hits.sort_unstable();
+        item_hits.dedup();
+        for &hit in item_hits.iter() {
+            *hits.entry(hit).or_default() += 1;
+        }
+    }
+    let r = rank(hits, max_results);
+    stats.elapsed = start.elapsed();
+    (r, stats)
+}
+
+/// Fraction of queries whose expected track is among their search results.
+pub fn recall(results: &[Vec<Candidate>], expected: &[u32]) -> f64 {
+    assert_eq!(results.len(), expected.len());
+    if results.is_empty() {
+        return 0.0;
+    }
+    let found = results.iter()
+        .zip(expected)
+        .filter(|(r, &track_id)| r.iter().any(|c| c.track_id == track_id))
+        .count();
+    found as f64 / results.len() as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index::Index;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn item(value: u32, unreliable: &[usize]) -> ReliableSubfingerprint {
+        let mut reliability = [1.0; 32];
+        for (i, &b) in unreliable.iter().enumerate() {
+            reliability[b] = i as f32 * 0.01;
+        }
+        ReliableSubfingerprint {
+            value,
+            reliability,
+        }
+    }
+
+    #[test]
+    fn probe_keys_fn() {
+        let v = item(0, &[3, 7, 1]);
+        assert_eq!(probe_keys(&v, !0, &MultiProbe::new(0, 10)), &[0]);
+        assert_eq!(probe_keys(&v, !0, &MultiProbe::new(1, 4)), &[0, 1 << 3, 1 << 7, 1 << 1]);
+        assert_eq!(probe_keys(&v, !0, &MultiProbe::new(2, 5)),
+            &[0, 1 << 3, 1 << 7, (1 << 3) | (1 << 7), 1 << 1]);
+        // Masked bits aren't probed.
+        assert_eq!(probe_keys(&v, !0xf, &MultiProbe::new(1, 3)), &[0, 1 << 7, 1 << 4]);
+
+        let keys = probe_keys(&item(0xffff_ffff, &[]), !0, &MultiProbe::new(2, 1000));
+        assert_eq!(keys.len(), 1 + 32 + 32 * 31 / 2);
+        assert!(keys.iter().all(|k| (!k).count_ones() <= 2));
+    }
+
+    #[test]
+    fn search_noisy() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let idx = &mut Index::new(!0);
+        let tracks: Vec<Vec<u32>> = (0..50).map(|_| (0..100).map(|_| rng.gen()).collect())
+            .collect();
+        for (i, fp) in tracks.iter().enumerate() {
+            idx.insert(i as u32, fp);
+        }
+
+        // Every item has one or two of its least reliable bits flipped.
+        let queries: Vec<Vec<_>> = tracks.iter()
+            .map(|fp| fp[20..60].iter()
+                .map(|&v| {
+                    let unreliable = [rng.gen_range(0, 32), rng.gen_range(0, 32)];
+                    let flip = (1 << unreliable[0]) | (1 << unreliable[1]);
+                    item(v ^ flip, &unreliable)
+                })
+                .collect())
+            .collect();
+        let expected: Vec<_> = (0..tracks.len() as u32).collect();
+
+        let exact: Vec<_> = queries.iter()
+            .map(|q| idx.search(&q.iter().map(|v| v.value).collect::<Vec<_>>(), 1))
+            .collect();
+        assert_eq!(recall(&exact, &expected), 0.0);
+
+        let mut total = ProbeStats::default();
+        let probed: Vec<_> = queries.iter()
+            .map(|q| {
+                let (r, stats) = idx.search_multiprobe(q, 1, &MultiProbe::new(2, 4));
+                total.probes += stats.probes;
+                total.postings += stats.postings;
+                r
+            })
+            .collect();
+        assert_eq!(recall(&probed, &expected), 1.0);
+        assert_eq!(total.probes, 50 * 40 * 4);
+        for (r, track_id) in probed.iter().zip(&expected) {
+            assert_eq!(r[0].track_id, *track_id);
+            assert_eq!(r[0].hits, 40);
+            assert_eq!(r[0].offset, 20);
+        }
+
+        // Radius 1 only finds the items with a single bit flipped.
+        let (r, _) = idx.search_multiprobe(&queries[0], 1, &MultiProbe::new(1, 4));
+        assert!(r[0].hits < 40);
+    }
+}
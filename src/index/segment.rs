@@ -0,0 +1,324 @@
+use crc32fast::Hasher;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::Posting;
+
+const MAGIC: &[u8; 8] = b"CPIXSEG1";
+const FOOTER_MAGIC: &[u8; 4] = b"CPIX";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 16;
+const FOOTER_LEN: usize = 32;
+const POSTING_LEN: usize = 12;
+const TRACK_LEN: usize = 8;
+
+/// Number of postings per block of the block index.
+pub const BLOCK_LEN: usize = 128;
+
+/// Item count of deleted tracks in the track table.
+pub const DELETED: u32 = u32::MAX;
+
+/// Memory mapped immutable index segment. Checksum is verified on open.
+///
+/// All integers are little endian. A segment file consists of:
+///
+/// * Header: magic `CPIXSEG1`, format version (`u32`), key mask (`u32`).
+/// * Postings: `(key, track id, position)` triples of `u32`, sorted.
+/// * Block index: the key of the first posting of every block of `BLOCK_LEN` postings.
+/// * Track table: `(track id, item count)` pairs of `u32` sorted by track id. Item count of
+///   `DELETED` marks a tombstone hiding the track in older segments.
+/// * Footer: posting, block and track counts (`u64`), CRC-32 of everything before it and magic
+///   `CPIX`.
+pub struct Segment {
+    id: u64,
+    path: PathBuf,
+    mmap: Mmap,
+    mask: u32,
+    posting_count: usize,
+    block_count: usize,
+    track_count: usize,
+}
+
+impl Segment {
+    pub fn open(path: impl AsRef<Path>, id: u64) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        // Segment files are never modified after they're written.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let data = &mmap[..];
+        if data.len() < HEADER_LEN + FOOTER_LEN || &data[..8] != MAGIC ||
+            &data[data.len() - 4..] != FOOTER_MAGIC
+        {
+            return Err(invalid_data("not an index segment"));
+        }
+        let version = read_u32(data, 8);
+        if version != VERSION {
+            return Err(invalid_data("unsupported index segment version"));
+        }
+        let mask = read_u32(data, 12);
+
+        let footer = data.len() - FOOTER_LEN;
+        let posting_count = read_u64(data, footer) as usize;
+        let block_count = read_u64(data, footer + 8) as usize;
+        let track_count = read_u64(data, footer + 16) as usize;
+        let expected_len = posting_count.checked_mul(POSTING_LEN)
+            .and_then(|v| v.checked_add(block_count.checked_mul(4)?))
+            .and_then(|v| v.checked_add(track_count.checked_mul(TRACK_LEN)?))
+            .and_then(|v| v.checked_add(HEADER_LEN + FOOTER_LEN));
+        if expected_len != Some(data.len()) ||
+            block_count * BLOCK_LEN < posting_count ||
+            block_count * BLOCK_LEN >= posting_count + BLOCK_LEN
+        {
+            return Err(invalid_data("corrupted index segment"));
+        }
+
+        let crc = read_u32(data, footer + 24);
+        let mut hasher = Hasher::new();
+        hasher.update(&data[..footer + 24]);
+        if hasher.finalize() != crc {
+            return Err(invalid_data("index segment checksum mismatch"));
+        }
+
+        Ok(Self {
+            id,
+            path: path.to_path_buf(),
+            mmap,
+            mask,
+            posting_count,
+            block_count,
+            track_count,
+        })
+    }
+
+    /// Writes a segment. `postings` must be sorted by key, track id and position, `tracks` by
+    /// track id. The file is synced to disk before returning.
+    pub fn write(
+        path: impl AsRef<Path>,
+        mask: u32,
+        postings: impl Iterator<Item=(u32, Posting)>,
+        tracks: &[(u32, u32)]) -> io::Result<()>
+    {
+        let w = &mut ChecksumWriter {
+            inner: BufWriter::new(File::create(path)?),
+            hasher: Hasher::new(),
+        };
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&mask.to_le_bytes())?;
+
+        let mut blocks = Vec::new();
+        let mut posting_count = 0;
+        let mut block_len = BLOCK_LEN;
+        let mut last = None;
+        for (key, p) in postings {
+            let entry = (key, p.track_id, p.pos);
+            debug_assert!(last.map(|l| l < entry).unwrap_or(true));
+            last = Some(entry);
+
+            if block_len == BLOCK_LEN {
+                blocks.push(key);
+                block_len = 0;
+            }
+            block_len += 1;
+            posting_count += 1;
+            w.write_all(&key.to_le_bytes())?;
+            w.write_all(&p.track_id.to_le_bytes())?;
+            w.write_all(&p.pos.to_le_bytes())?;
+        }
+        for key in &blocks {
+            w.write_all(&key.to_le_bytes())?;
+        }
+        debug_assert!(tracks.windows(2).all(|w| w[0].0 < w[1].0));
+        for &(track_id, len) in tracks {
+            w.write_all(&track_id.to_le_bytes())?;
+            w.write_all(&len.to_le_bytes())?;
+        }
+        w.write_all(&(posting_count as u64).to_le_bytes())?;
+        w.write_all(&(blocks.len() as u64).to_le_bytes())?;
+        w.write_all(&(tracks.len() as u64).to_le_bytes())?;
+
+        let crc = w.hasher.clone().finalize();
+        w.inner.write_all(&crc.to_le_bytes())?;
+        w.inner.write_all(FOOTER_MAGIC)?;
+        w.inner.flush()?;
+        w.inner.get_ref().sync_all()
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    pub fn posting_count(&self) -> usize {
+        self.posting_count
+    }
+
+    /// All postings in order along with their keys.
+    pub fn entries(&self) -> impl Iterator<Item=(u32, Posting)> + '_ {
+        (0..self.posting_count).map(move |i| self.entry(i))
+    }
+
+    /// Postings of the masked `key`.
+    pub fn postings(&self, key: u32) -> impl Iterator<Item=Posting> + '_ {
+        let key = key & self.mask;
+        // The last block starting before the key may still contain it.
+        let block = partition_point(self.block_count, |i| self.block_key(i) < key);
+        let start = block.saturating_sub(1) * BLOCK_LEN;
+        (start..self.posting_count)
+            .map(move |i| self.entry(i))
+            .skip_while(move |&(k, _)| k < key)
+            .take_while(move |&(k, _)| k == key)
+            .map(|(_, p)| p)
+    }
+
+    /// Track table as `(track id, item count)` pairs, see `DELETED`.
+    pub fn tracks(&self) -> impl Iterator<Item=(u32, u32)> + '_ {
+        let start = self.tracks_offset();
+        (0..self.track_count).map(move |i| {
+            let offset = start + i * TRACK_LEN;
+            (read_u32(&self.mmap, offset), read_u32(&self.mmap, offset + 4))
+        })
+    }
+
+    fn entry(&self, i: usize) -> (u32, Posting) {
+        let offset = HEADER_LEN + i * POSTING_LEN;
+        (read_u32(&self.mmap, offset), Posting {
+            track_id: read_u32(&self.mmap, offset + 4),
+            pos: read_u32(&self.mmap, offset + 8),
+        })
+    }
+
+    fn block_key(&self, i: usize) -> u32 {
+        read_u32(&self.mmap, HEADER_LEN + self.posting_count * POSTING_LEN + i * 4)
+    }
+
+    fn tracks_offset(&self) -> usize {
+        HEADER_LEN + self.posting_count * POSTING_LEN + self.block_count * 4
+    }
+}
+
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Index of the first `i` in `0..len` for which `pred(i)` is false.
+fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut lo, mut hi) = (0, len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if pred(mid) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(b)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(b)
+}
+
+pub(super) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn posting(track_id: u32, pos: u32) -> Posting {
+        Posting { track_id, pos }
+    }
+
+    #[test]
+    fn write_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = &dir.path().join("1.seg");
+
+        // Enough postings of the same key to span several blocks.
+        let mut postings = Vec::new();
+        for key in 0..10u32 {
+            let count = if key == 5 { BLOCK_LEN as u32 * 3 } else { 7 };
+            for pos in 0..count {
+                postings.push((key * 16, posting(key % 3, pos)));
+            }
+        }
+        let tracks = &[(0, 70), (1, 400), (2, 35), (9, DELETED)];
+        Segment::write(path, !0xf, postings.iter().cloned(), tracks).unwrap();
+
+        let s = Segment::open(path, 1).unwrap();
+        assert_eq!(s.id(), 1);
+        assert_eq!(s.mask(), !0xf);
+        assert_eq!(s.posting_count(), postings.len());
+        assert_eq!(s.entries().collect::<Vec<_>>(), postings);
+        assert_eq!(s.tracks().collect::<Vec<_>>(), tracks);
+
+        for key in 0..10u32 {
+            let expected: Vec<_> = postings.iter()
+                .filter(|(k, _)| *k == key * 16)
+                .map(|&(_, p)| p)
+                .collect();
+            // Masked bits are ignored.
+            assert_eq!(s.postings(key * 16 + 3).collect::<Vec<_>>(), expected);
+        }
+        assert_eq!(s.postings(1000).count(), 0);
+    }
+
+    #[test]
+    fn empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = &dir.path().join("1.seg");
+        Segment::write(path, !0, std::iter::empty(), &[]).unwrap();
+        let s = Segment::open(path, 1).unwrap();
+        assert_eq!(s.postings(0).count(), 0);
+        assert_eq!(s.tracks().count(), 0);
+    }
+
+    #[test]
+    fn corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = &dir.path().join("1.seg");
+        Segment::write(path, !0, vec![(1, posting(1, 0))].into_iter(), &[(1, 1)]).unwrap();
+
+        let mut data = fs::read(path).unwrap();
+        data[HEADER_LEN + 4] ^= 1;
+        fs::write(path, &data).unwrap();
+        assert_eq!(Segment::open(path, 1).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        fs::write(path, &data[..data.len() - 1]).unwrap();
+        assert_eq!(Segment::open(path, 1).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
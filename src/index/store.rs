@@ -0,0 +1,824 @@
+use crc32fast::Hasher;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+use super::{probe, Candidate, Index, MultiProbe, Posting, Postings, ProbeStats};
+use super::segment::{self, invalid_data, Segment};
+use super::wal::{Op, Wal};
+use crate::fingerprint::ReliableSubfingerprint;
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+const MANIFEST_HEADER: &str = "chromaprinter-index 1";
+const SEGMENT_EXT: &str = "seg";
+const WAL_EXT: &str = "wal";
+
+/// Persistent fingerprint index stored in a directory.
+///
+/// Updates are appended to a write-ahead log and kept in an in-memory `Index` until `flush()`
+/// writes them out as a new immutable `Segment`. Segments are memory mapped and tracks in newer
+/// segments shadow the ones in older segments. Once there are more than `max_segments` segments
+/// a background thread merges them into one.
+///
+/// The live segments and logs are listed in a manifest that is replaced atomically, so after
+/// a crash the index opens with all updates acknowledged before it. Files not listed in the
+/// manifest are leftovers of interrupted flushes or merges and are removed on open.
+///
+/// A flush freezes the in-memory updates and starts a new log before writing the segment, so
+/// searches and updates aren't blocked while the segment is written.
+pub struct Store {
+    shared: Arc<Shared>,
+    merger: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+struct Shared {
+    dir: PathBuf,
+    max_segments: usize,
+    state: RwLock<State>,
+
+    /// Held for the whole duration of a flush.
+    flush_lock: Mutex<()>,
+
+    /// Held for the whole duration of a merge.
+    merge_lock: Mutex<()>,
+}
+
+struct State {
+    mask: u32,
+    next_id: u64,
+    wal_id: u64,
+    wal: Wal,
+
+    /// Oldest first.
+    segments: Vec<Arc<Segment>>,
+
+    /// Tracks inserted since the last flush.
+    memtable: Index,
+
+    /// Tracks removed since the last flush.
+    removed: HashSet<u32>,
+
+    /// Updates being written out by a flush, or left over by a failed one. They are older than
+    /// `memtable` and `removed` and newer than all segments.
+    frozen: Option<Arc<Frozen>>,
+
+    /// Id of the newest segment that has each track, unless a tombstone in a newer segment
+    /// removed it.
+    latest: HashMap<u32, u64>,
+
+    /// Error of the last background merge, reported by the next `flush()`.
+    merge_error: Option<io::Error>,
+}
+
+struct Frozen {
+    /// Log with the updates, still listed in the manifest until they are in a segment.
+    wal_id: u64,
+    memtable: Index,
+    removed: HashSet<u32>,
+}
+
+impl Store {
+    /// Opens the index in `dir` or creates a new one if there's none. The `mask` must match the
+    /// one the index was created with.
+    pub fn open(dir: impl AsRef<Path>, mask: u32, max_segments: usize) -> io::Result<Self> {
+        assert!(mask != 0);
+        assert!(max_segments > 0);
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let state = if let Some(manifest) = read_manifest(&dir)? {
+            if manifest.mask != mask {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "index mask mismatch"));
+            }
+            let segments = manifest.segments.iter()
+                .map(|&id| Segment::open(dir.join(file_name(id, SEGMENT_EXT)), id).map(Arc::new))
+                .collect::<io::Result<_>>()?;
+            let (&wal_id, frozen_wal_ids) = manifest.wal_ids.split_last().unwrap();
+            let (wal, ops) = Wal::open(dir.join(file_name(wal_id, WAL_EXT)))?;
+            let mut state = State {
+                mask,
+                next_id: manifest.next_id,
+                wal_id,
+                wal,
+                segments,
+                memtable: Index::new(mask),
+                removed: HashSet::new(),
+                frozen: None,
+                latest: HashMap::new(),
+                merge_error: None,
+            };
+            // The index was closed in the middle of a flush, the next flush will finish it.
+            if let Some(&frozen_wal_id) = frozen_wal_ids.first() {
+                let (_, ops) = Wal::open(dir.join(file_name(frozen_wal_id, WAL_EXT)))?;
+                for op in ops {
+                    state.apply(op);
+                }
+                state.frozen = Some(Arc::new(Frozen {
+                    wal_id: frozen_wal_id,
+                    memtable: mem::replace(&mut state.memtable, Index::new(mask)),
+                    removed: mem::take(&mut state.removed),
+                }));
+            }
+            for op in ops {
+                state.apply(op);
+            }
+            state.update_latest();
+            state
+        } else {
+            let wal_id = 1;
+            let wal_path = dir.join(file_name(wal_id, WAL_EXT));
+            // Leftover of a crash while the index was being created.
+            remove_file(&wal_path)?;
+            let wal = Wal::create(wal_path)?;
+            let state = State {
+                mask,
+                next_id: wal_id + 1,
+                wal_id,
+                wal,
+                segments: Vec::new(),
+                memtable: Index::new(mask),
+                removed: HashSet::new(),
+                frozen: None,
+                latest: HashMap::new(),
+                merge_error: None,
+            };
+            state.write_manifest(&dir, &state.segments)?;
+            state
+        };
+        state.remove_stale_files(&dir)?;
+
+        let shared = Arc::new(Shared {
+            dir,
+            max_segments,
+            state: RwLock::new(state),
+            flush_lock: Mutex::new(()),
+            merge_lock: Mutex::new(()),
+        });
+
+        let (tx, rx) = mpsc::channel();
+        let merger = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for () in rx {
+                    if let Err(e) = shared.merge(shared.max_segments + 1) {
+                        shared.state.write().unwrap().merge_error = Some(e);
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            shared,
+            merger: Some((tx, merger)),
+        })
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.shared.state.read().unwrap().mask
+    }
+
+    /// Number of tracks.
+    pub fn len(&self) -> usize {
+        let s = self.shared.state.read().unwrap();
+        let frozen = s.frozen.as_ref().map(|f| f.memtable.track_ids()
+            .filter(|&t| !s.is_updated(t))
+            .count()).unwrap_or(0);
+        s.latest.keys()
+            .filter(|&&t| !s.is_updated(t) && !s.is_frozen(t))
+            .count() + frozen + s.memtable.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, track_id: u32) -> bool {
+        self.shared.state.read().unwrap().contains(track_id)
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.shared.state.read().unwrap().segments.len()
+    }
+
+    /// Adds the fingerprint of a track, replacing the existing one if any.
+    /// The update is durable once this returns.
+    pub fn insert(&self, track_id: u32, fingerprint: &[u32]) -> io::Result<()> {
+        let s = &mut *self.shared.state.write().unwrap();
+        let op = Op::Insert {
+            track_id,
+            fingerprint: fingerprint.to_vec(),
+        };
+        s.wal.append(&op)?;
+        s.apply(op);
+        Ok(())
+    }
+
+    /// Returns `false` if the track isn't in the index.
+    /// The update is durable once this returns.
+    pub fn remove(&self, track_id: u32) -> io::Result<bool> {
+        let s = &mut *self.shared.state.write().unwrap();
+        if !s.contains(track_id) {
+            return Ok(false);
+        }
+        let op = Op::Remove {
+            track_id,
+        };
+        s.wal.append(&op)?;
+        s.apply(op);
+        Ok(true)
+    }
+
+    /// Same as `Index::search()`.
+    pub fn search(&self, query: &[u32], max_results: usize) -> Vec<Candidate> {
+        super::search_postings(&*self.shared.state.read().unwrap(), query, max_results)
+    }
+
+    /// Same as `Index::search_multiprobe()`.
+    pub fn search_multiprobe(
+        &self,
+        query: &[ReliableSubfingerprint],
+        max_results: usize,
+        probe: &MultiProbe) -> (Vec<Candidate>, ProbeStats)
+    {
+        probe::search(&*self.shared.state.read().unwrap(), query, max_results, probe)
+    }
+
+    /// Writes the updates since the last flush out as a new segment and starts a new log.
+    /// Reports the error of the last failed background merge, if any.
+    pub fn flush(&self) -> io::Result<()> {
+        let _guard = self.shared.flush_lock.lock().unwrap();
+        // Updates left frozen by a failed flush go into their own segment first.
+        self.write_frozen()?;
+        if self.freeze()? {
+            self.write_frozen()?;
+        }
+        match self.shared.state.write().unwrap().merge_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the updates since the last flush to `State::frozen` and starts a new log.
+    /// Returns `false` if there are no updates.
+    fn freeze(&self) -> io::Result<bool> {
+        let dir = &self.shared.dir;
+        let wal_id = {
+            let mut s = self.shared.state.write().unwrap();
+            debug_assert!(s.frozen.is_none());
+            if s.memtable.is_empty() && s.removed.is_empty() {
+                return Ok(false);
+            }
+            s.next_id += 1;
+            s.next_id - 1
+        };
+        let wal = Wal::create(dir.join(file_name(wal_id, WAL_EXT)))?;
+
+        let s = &mut *self.shared.state.write().unwrap();
+        let old_wal = mem::replace(&mut s.wal, wal);
+        s.frozen = Some(Arc::new(Frozen {
+            wal_id: s.wal_id,
+            memtable: mem::replace(&mut s.memtable, Index::new(s.mask)),
+            removed: mem::take(&mut s.removed),
+        }));
+        s.wal_id = wal_id;
+        if let Err(e) = s.write_manifest(dir, &s.segments) {
+            let frozen = Arc::try_unwrap(s.frozen.take().unwrap()).ok().unwrap();
+            s.wal = old_wal;
+            s.wal_id = frozen.wal_id;
+            s.memtable = frozen.memtable;
+            s.removed = frozen.removed;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Writes `State::frozen` out as a new segment. Does nothing if there's nothing frozen.
+    fn write_frozen(&self) -> io::Result<()> {
+        let dir = &self.shared.dir;
+        let (frozen, segment_id, mask) = {
+            let mut s = self.shared.state.write().unwrap();
+            let frozen = match &s.frozen {
+                Some(v) => v.clone(),
+                None => return Ok(()),
+            };
+            s.next_id += 1;
+            (frozen, s.next_id - 1, s.mask)
+        };
+
+        let mut postings: Vec<_> = frozen.memtable.postings.iter()
+            .flat_map(|(&key, postings)| postings.iter().map(move |&p| (key, p)))
+            .collect();
+        postings.sort_by_key(|&(key, p)| (key, p.track_id, p.pos));
+        let mut tracks: Vec<_> = frozen.memtable.tracks.iter()
+            .map(|(&track_id, track)| (track_id, track.len))
+            .chain(frozen.removed.iter().map(|&track_id| (track_id, segment::DELETED)))
+            .collect();
+        tracks.sort_unstable();
+
+        let path = dir.join(file_name(segment_id, SEGMENT_EXT));
+        Segment::write(&path, mask, postings.into_iter(), &tracks)?;
+        let segment = Arc::new(Segment::open(&path, segment_id)?);
+
+        let mut s = self.shared.state.write().unwrap();
+        let mut segments = s.segments.clone();
+        segments.push(segment);
+        s.frozen = None;
+        if let Err(e) = s.write_manifest(dir, &segments) {
+            s.frozen = Some(frozen);
+            return Err(e);
+        }
+        s.segments = segments;
+        s.update_latest();
+        let merge = s.segments.len() > self.shared.max_segments;
+        drop(s);
+
+        remove_file(&dir.join(file_name(frozen.wal_id, WAL_EXT)))?;
+        if merge {
+            if let Some((tx, _)) = &self.merger {
+                tx.send(()).map_err(|_| io::Error::other("index merge thread has stopped"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges all segments into one, waiting for the background merge to finish first if it's
+    /// running.
+    pub fn merge(&self) -> io::Result<()> {
+        self.shared.merge(2)
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Some((tx, merger)) = self.merger.take() {
+            drop(tx);
+            // A panicked merge has been reported by the panic hook already and the segments it
+            // was merging stay in place, so there's nothing left to do about it.
+            let _ = merger.join();
+        }
+    }
+}
+
+impl Shared {
+    /// Merges the segments if there are at least `min_segments` of them.
+    fn merge(&self, min_segments: usize) -> io::Result<()> {
+        let _guard = self.merge_lock.lock().unwrap();
+
+        let (segments, id, mask) = {
+            let mut s = self.state.write().unwrap();
+            if s.segments.len() < min_segments.max(2) {
+                return Ok(());
+            }
+            let id = s.next_id;
+            s.next_id += 1;
+            (s.segments.clone(), id, s.mask)
+        };
+
+        let path = self.dir.join(file_name(id, SEGMENT_EXT));
+        merge_segments(&path, mask, &segments)?;
+        let merged = Arc::new(Segment::open(&path, id)?);
+
+        let mut s = self.state.write().unwrap();
+        // Flushes only ever append segments, so the merged ones are still the oldest.
+        debug_assert!(s.segments.iter().zip(&segments).all(|(a, b)| a.id() == b.id()));
+        let mut new_segments = vec![merged];
+        new_segments.extend_from_slice(&s.segments[segments.len()..]);
+        s.write_manifest(&self.dir, &new_segments)?;
+        s.segments = new_segments;
+        s.update_latest();
+        drop(s);
+
+        for segment in segments {
+            remove_file(segment.path())?;
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    fn apply(&mut self, op: Op) {
+        match op {
+            Op::Insert { track_id, fingerprint } => {
+                self.memtable.insert(track_id, &fingerprint);
+                self.removed.remove(&track_id);
+            }
+            Op::Remove { track_id } => {
+                self.memtable.remove(track_id);
+                self.removed.insert(track_id);
+            }
+        }
+    }
+
+    fn contains(&self, track_id: u32) -> bool {
+        if self.is_updated(track_id) {
+            return self.memtable.contains(track_id);
+        }
+        match &self.frozen {
+            Some(f) if self.is_frozen(track_id) => f.memtable.contains(track_id),
+            _ => self.latest.contains_key(&track_id),
+        }
+    }
+
+    /// Whether the track was inserted or removed since the last flush.
+    fn is_updated(&self, track_id: u32) -> bool {
+        self.memtable.contains(track_id) || self.removed.contains(&track_id)
+    }
+
+    /// Whether the track was inserted or removed in the frozen updates.
+    fn is_frozen(&self, track_id: u32) -> bool {
+        self.frozen.as_ref()
+            .map(|f| f.memtable.contains(track_id) || f.removed.contains(&track_id))
+            .unwrap_or(false)
+    }
+
+    /// Whether postings of the track in the segment are the current ones.
+    fn is_live(&self, track_id: u32, segment_id: u64) -> bool {
+        !self.is_updated(track_id) && !self.is_frozen(track_id) &&
+            self.latest.get(&track_id) == Some(&segment_id)
+    }
+
+    fn update_latest(&mut self) {
+        self.latest.clear();
+        for segment in &self.segments {
+            for (track_id, len) in segment.tracks() {
+                if len == segment::DELETED {
+                    self.latest.remove(&track_id);
+                } else {
+                    self.latest.insert(track_id, segment.id());
+                }
+            }
+        }
+    }
+
+    fn write_manifest(&self, dir: &Path, segments: &[Arc<Segment>]) -> io::Result<()> {
+        let mut text = format!("{}\nmask {:08x}\nnext {}\n",
+            MANIFEST_HEADER, self.mask, self.next_id);
+        // Oldest first.
+        for wal_id in self.frozen.iter().map(|f| f.wal_id).chain(iter::once(self.wal_id)) {
+            text += &format!("wal {}\n", wal_id);
+        }
+        for segment in segments {
+            text += &format!("segment {}\n", segment.id());
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(text.as_bytes());
+        text += &format!("crc {:08x}\n", hasher.finalize());
+
+        let tmp_path = dir.join(MANIFEST_TMP);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, dir.join(MANIFEST))?;
+        sync_dir(dir)
+    }
+
+    fn remove_stale_files(&self, dir: &Path) -> io::Result<()> {
+        let live: HashSet<_> = self.segments.iter()
+            .map(|s| file_name(s.id(), SEGMENT_EXT))
+            .chain(self.frozen.iter().map(|f| file_name(f.wal_id, WAL_EXT)))
+            .chain(iter::once(file_name(self.wal_id, WAL_EXT)))
+            .collect();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|v| v.to_str()).unwrap_or("");
+            let ext = path.extension().and_then(|v| v.to_str());
+            let ours = ext == Some(SEGMENT_EXT) || ext == Some(WAL_EXT) || name == MANIFEST_TMP;
+            if ours && !live.contains(name) {
+                remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Postings for State {
+    fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    fn for_each_posting<F: FnMut(Posting)>(&self, key: u32, mut f: F) {
+        for &p in self.memtable.postings(key) {
+            f(p);
+        }
+        if let Some(frozen) = &self.frozen {
+            for &p in frozen.memtable.postings(key) {
+                if !self.is_updated(p.track_id) {
+                    f(p);
+                }
+            }
+        }
+        for segment in &self.segments {
+            for p in segment.postings(key) {
+                if self.is_live(p.track_id, segment.id()) {
+                    f(p);
+                }
+            }
+        }
+    }
+}
+
+struct Manifest {
+    mask: u32,
+    next_id: u64,
+
+    /// Oldest first. There are two logs if the index was closed in the middle of a flush.
+    wal_ids: Vec<u64>,
+    segments: Vec<u64>,
+}
+
+fn read_manifest(dir: &Path) -> io::Result<Option<Manifest>> {
+    let text = match fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(v) => v,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let corrupted = || invalid_data("corrupted index manifest");
+
+    let crc_pos = text.rfind("crc ").ok_or_else(corrupted)?;
+    let (body, crc) = text.split_at(crc_pos);
+    let crc = u32::from_str_radix(crc[4..].trim_end(), 16).map_err(|_| corrupted())?;
+    let mut hasher = Hasher::new();
+    hasher.update(body.as_bytes());
+    if hasher.finalize() != crc {
+        return Err(invalid_data("index manifest checksum mismatch"));
+    }
+
+    let mut lines = body.lines();
+    if lines.next() != Some(MANIFEST_HEADER) {
+        return Err(invalid_data("not an index manifest"));
+    }
+    let mut r = Manifest {
+        mask: 0,
+        next_id: 0,
+        wal_ids: Vec::new(),
+        segments: Vec::new(),
+    };
+    for line in lines {
+        let mut parts = line.splitn(2, ' ');
+        let (key, value) = (parts.next().unwrap(), parts.next().ok_or_else(corrupted)?);
+        let bad_value = |_| corrupted();
+        match key {
+            "mask" => r.mask = u32::from_str_radix(value, 16).map_err(bad_value)?,
+            "next" => r.next_id = value.parse().map_err(bad_value)?,
+            "wal" => r.wal_ids.push(value.parse().map_err(bad_value)?),
+            "segment" => r.segments.push(value.parse().map_err(bad_value)?),
+            _ => return Err(corrupted()),
+        }
+    }
+    if r.wal_ids.is_empty() || r.wal_ids.len() > 2 {
+        return Err(corrupted());
+    }
+    Ok(Some(r))
+}
+
+/// Merges segments given oldest first into a new one. Only the newest version of each track is
+/// kept. Tombstones are dropped since there are no older segments for them to hide tracks in.
+fn merge_segments(path: &Path, mask: u32, segments: &[Arc<Segment>]) -> io::Result<()> {
+    let mut newest: HashMap<u32, (usize, u32)> = HashMap::new();
+    for (i, segment) in segments.iter().enumerate() {
+        for (track_id, len) in segment.tracks() {
+            if len == segment::DELETED {
+                newest.remove(&track_id);
+            } else {
+                newest.insert(track_id, (i, len));
+            }
+        }
+    }
+    let mut tracks: Vec<_> = newest.iter().map(|(&track_id, &(_, len))| (track_id, len)).collect();
+    tracks.sort_unstable();
+
+    let newest = &newest;
+    let mut entries: Vec<_> = segments.iter()
+        .enumerate()
+        .map(|(i, segment)| segment.entries()
+            .filter(move |(_, p)| newest.get(&p.track_id).map(|&(s, _)| s) == Some(i)))
+        .collect();
+    let mut heap = BinaryHeap::new();
+    for (i, e) in entries.iter_mut().enumerate() {
+        if let Some((key, p)) = e.next() {
+            heap.push(Reverse((key, p.track_id, p.pos, i)));
+        }
+    }
+    let merged = iter::from_fn(|| {
+        let Reverse((key, track_id, pos, i)) = heap.pop()?;
+        if let Some((key, p)) = entries[i].next() {
+            heap.push(Reverse((key, p.track_id, p.pos, i)));
+        }
+        Some((key, Posting { track_id, pos }))
+    });
+
+    Segment::write(path, mask, merged, &tracks)
+}
+
+fn file_name(id: u64, ext: &str) -> String {
+    format!("{:016x}.{}", id, ext)
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files on Windows, renames are durable there anyway.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_fingerprint(rng: &mut StdRng, len: usize) -> Vec<u32> {
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn top(store: &Store, query: &[u32]) -> Option<(u32, usize, isize)> {
+        store.search(query, 1).first().map(|c| (c.track_id, c.hits, c.offset))
+    }
+
+    #[test]
+    fn persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let rng = &mut StdRng::seed_from_u64(1);
+        let fps: Vec<_> = (0..4).map(|_| random_fingerprint(rng, 100)).collect();
+
+        {
+            let store = Store::open(dir.path(), !0, 10).unwrap();
+            store.insert(1, &fps[0]).unwrap();
+            store.insert(2, &fps[1]).unwrap();
+            store.flush().unwrap();
+            assert_eq!(store.segment_count(), 1);
+
+            // Replaced and removed tracks shadow the flushed ones.
+            store.insert(1, &fps[2]).unwrap();
+            assert!(store.remove(2).unwrap());
+            assert!(!store.remove(2).unwrap());
+            store.insert(3, &fps[3]).unwrap();
+            assert_eq!(store.len(), 2);
+            assert_eq!(top(&store, &fps[0]), None);
+            assert_eq!(top(&store, &fps[1]), None);
+            assert_eq!(top(&store, &fps[2][10..]), Some((1, 90, 10)));
+        }
+
+        // Unflushed updates are replayed from the log.
+        {
+            let store = Store::open(dir.path(), !0, 10).unwrap();
+            assert_eq!(store.len(), 2);
+            assert!(store.contains(1) && !store.contains(2) && store.contains(3));
+            assert_eq!(top(&store, &fps[0]), None);
+            assert_eq!(top(&store, &fps[2][10..]), Some((1, 90, 10)));
+            store.flush().unwrap();
+            assert_eq!(store.segment_count(), 2);
+            assert_eq!(top(&store, &fps[1]), None);
+            assert_eq!(top(&store, &fps[3]), Some((3, 100, 0)));
+        }
+
+        let store = Store::open(dir.path(), !0, 10).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(top(&store, &fps[0]), None);
+        assert_eq!(top(&store, &fps[1]), None);
+        assert_eq!(top(&store, &fps[2]), Some((1, 100, 0)));
+        assert_eq!(top(&store, &fps[3]), Some((3, 100, 0)));
+
+        assert!(Store::open(dir.path(), !0xf, 10).is_err());
+    }
+
+    #[test]
+    fn merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let rng = &mut StdRng::seed_from_u64(2);
+        let fps: Vec<_> = (0..20).map(|_| random_fingerprint(rng, 50)).collect();
+
+        {
+            let store = Store::open(dir.path(), !0, 3).unwrap();
+            for (i, fp) in fps.iter().enumerate() {
+                store.insert(i as u32, fp).unwrap();
+                if i % 5 == 4 {
+                    store.remove(i as u32 - 2).unwrap();
+                }
+                if i % 2 == 1 {
+                    store.flush().unwrap();
+                }
+            }
+            store.merge().unwrap();
+            assert_eq!(store.segment_count(), 1);
+        }
+
+        let files = || {
+            let mut r: Vec<_> = fs::read_dir(dir.path()).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect();
+            r.sort();
+            r
+        };
+        assert_eq!(files().len(), 3);
+
+        let store = Store::open(dir.path(), !0, 3).unwrap();
+        assert_eq!(store.len(), 16);
+        for (i, fp) in fps.iter().enumerate() {
+            let expected = if i % 5 == 2 { None } else { Some((i as u32, 50, 0)) };
+            assert_eq!(top(&store, fp), expected);
+        }
+
+        // Background merge after too many flushes.
+        for i in 0..10 {
+            store.insert(100 + i, &fps[i as usize]).unwrap();
+            store.flush().unwrap();
+        }
+        drop(store);
+        let store = Store::open(dir.path(), !0, 3).unwrap();
+        assert!(store.segment_count() <= 3);
+        assert_eq!(store.len(), 26);
+        assert_eq!(store.search(&fps[0], 10).len(), 2);
+        assert_eq!(files().len(), store.segment_count() + 2);
+    }
+
+    #[test]
+    fn stale_files() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = Store::open(dir.path(), !0, 10).unwrap();
+            store.insert(1, &[1, 2, 3]).unwrap();
+            store.flush().unwrap();
+        }
+        // Leftovers of an interrupted flush.
+        fs::write(dir.path().join(file_name(100, SEGMENT_EXT)), b"foo").unwrap();
+        fs::write(dir.path().join(file_name(101, WAL_EXT)), b"").unwrap();
+        fs::write(dir.path().join(MANIFEST_TMP), b"").unwrap();
+        fs::write(dir.path().join("other"), b"").unwrap();
+
+        let store = Store::open(dir.path(), !0, 10).unwrap();
+        assert_eq!(top(&store, &[1, 2, 3]), Some((1, 3, 0)));
+        let mut files: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, &["0000000000000002.wal", "0000000000000003.seg", "MANIFEST", "other"]);
+    }
+
+    #[test]
+    fn interrupted_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let rng = &mut StdRng::seed_from_u64(3);
+        let fps: Vec<_> = (0..5).map(|_| random_fingerprint(rng, 100)).collect();
+
+        let check = |store: &Store| {
+            assert_eq!(store.len(), 2);
+            assert!(!store.contains(0) && store.contains(1) && !store.contains(2));
+            assert!(store.contains(4));
+            assert_eq!(top(store, &fps[0]), None);
+            assert_eq!(top(store, &fps[1]), None);
+            assert_eq!(top(store, &fps[2]), None);
+            assert_eq!(top(store, &fps[3]), Some((1, 100, 0)));
+            assert_eq!(top(store, &fps[4][10..]), Some((4, 90, 10)));
+        };
+
+        {
+            let store = Store::open(dir.path(), !0, 10).unwrap();
+            store.insert(0, &fps[0]).unwrap();
+            store.insert(1, &fps[1]).unwrap();
+            store.insert(2, &fps[2]).unwrap();
+            store.flush().unwrap();
+
+            // Frozen updates shadow the segments and are shadowed by the newer updates.
+            store.remove(0).unwrap();
+            store.insert(1, &fps[3]).unwrap();
+            store.insert(4, &fps[4]).unwrap();
+            assert!(store.freeze().unwrap());
+            store.remove(4).unwrap();
+            store.insert(4, &fps[4]).unwrap();
+            store.remove(2).unwrap();
+            check(&store);
+        }
+
+        // The frozen updates are replayed from their own log and flushed by the next flush.
+        let store = Store::open(dir.path(), !0, 10).unwrap();
+        check(&store);
+        store.flush().unwrap();
+        assert_eq!(store.segment_count(), 3);
+        check(&store);
+        drop(store);
+
+        let store = Store::open(dir.path(), !0, 10).unwrap();
+        check(&store);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 5);
+    }
+}
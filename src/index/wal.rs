@@ -0,0 +1,173 @@
+use crc32fast::Hasher;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const INSERT: u8 = 1;
+const REMOVE: u8 = 2;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    Insert {
+        track_id: u32,
+        fingerprint: Vec<u32>,
+    },
+    Remove {
+        track_id: u32,
+    },
+}
+
+/// Write-ahead log of index updates that aren't in a segment yet.
+/// Each record is the payload length (`u32`), CRC-32 of the payload and the payload itself.
+/// A record that was only partly written when the process died fails its checksum and is
+/// dropped along with everything after it when the log is opened.
+pub struct Wal {
+    file: File,
+}
+
+impl Wal {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.sync_all()?;
+        Ok(Self {
+            file,
+        })
+    }
+
+    /// Opens an existing log, returning the operations recorded in it.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, Vec<Op>)> {
+        let mut file = OpenOptions::new().read(true).append(true).open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut ops = Vec::new();
+        let mut valid_len = 0;
+        while let Some((op, len)) = read_record(&data[valid_len..]) {
+            ops.push(op);
+            valid_len += len;
+        }
+        if valid_len < data.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+
+        Ok((Self {
+            file,
+        }, ops))
+    }
+
+    /// Appends the operation and syncs it to disk.
+    pub fn append(&mut self, op: &Op) -> io::Result<()> {
+        let mut payload = Vec::new();
+        match op {
+            Op::Insert { track_id, fingerprint } => {
+                payload.push(INSERT);
+                payload.extend_from_slice(&track_id.to_le_bytes());
+                payload.extend_from_slice(&(fingerprint.len() as u32).to_le_bytes());
+                for v in fingerprint {
+                    payload.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            Op::Remove { track_id } => {
+                payload.push(REMOVE);
+                payload.extend_from_slice(&track_id.to_le_bytes());
+            }
+        }
+
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&hasher.finalize().to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+}
+
+/// Returns the operation and record length or `None` if the record is incomplete or corrupted.
+fn read_record(data: &[u8]) -> Option<(Op, usize)> {
+    let len = read_u32(data, 0)? as usize;
+    let crc = read_u32(data, 4)?;
+    let payload = data.get(8..8usize.checked_add(len)?)?;
+    let mut hasher = Hasher::new();
+    hasher.update(payload);
+    if hasher.finalize() != crc {
+        return None;
+    }
+
+    let track_id = read_u32(payload, 1)?;
+    let op = match *payload.first()? {
+        INSERT => {
+            let count = read_u32(payload, 5)? as usize;
+            if payload.len() != 9 + count * 4 {
+                return None;
+            }
+            Op::Insert {
+                track_id,
+                fingerprint: (0..count).map(|i| read_u32(payload, 9 + i * 4).unwrap()).collect(),
+            }
+        }
+        REMOVE if payload.len() == 5 => Op::Remove { track_id },
+        _ => return None,
+    };
+    Some((op, 8 + len))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let b = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = &dir.path().join("1.wal");
+
+        let ops = vec![
+            Op::Insert { track_id: 1, fingerprint: vec![1, 2, 3] },
+            Op::Remove { track_id: 7 },
+            Op::Insert { track_id: 2, fingerprint: vec![] },
+        ];
+        {
+            let wal = &mut Wal::create(path).unwrap();
+            for op in &ops {
+                wal.append(op).unwrap();
+            }
+        }
+        assert!(Wal::create(path).is_err());
+
+        let (mut wal, read) = Wal::open(path).unwrap();
+        assert_eq!(read, ops);
+        wal.append(&Op::Remove { track_id: 1 }).unwrap();
+        drop(wal);
+
+        // Torn last record.
+        let len = fs::metadata(path).unwrap().len();
+        let f = OpenOptions::new().write(true).open(path).unwrap();
+        f.set_len(len - 2).unwrap();
+        drop(f);
+        let (mut wal, read) = Wal::open(path).unwrap();
+        assert_eq!(read, ops);
+
+        // Appending after truncation works.
+        wal.append(&Op::Remove { track_id: 3 }).unwrap();
+        drop(wal);
+        let (_, read) = Wal::open(path).unwrap();
+        assert_eq!(read.len(), 4);
+        assert_eq!(read[3], Op::Remove { track_id: 3 });
+
+        // Corrupted record drops everything after it.
+        let mut data = fs::read(path).unwrap();
+        data[12] ^= 1;
+        fs::write(path, &data).unwrap();
+        let (_, read) = Wal::open(path).unwrap();
+        assert_eq!(read, &[]);
+    }
+}
@@ -7,15 +7,57 @@
 compile_error!("Exactly one FFT library must be selected via features: fftw, vdsp. \
                 Did you forgot to disable default features?");
 
+#[cfg(feature = "acoustid")]
+pub mod acoustid;
 mod audio;
+mod boundary;
+mod capi;
 mod chroma;
 mod fingerprint;
+mod index;
+mod monitor;
 mod pipeline;
+mod score;
+#[cfg(feature = "server")]
+mod server;
+mod speed;
+pub mod stream;
+mod sync;
 #[cfg(test)]
 mod test_util;
 mod util;
 
-use crate::chroma::Chroma;
+use crate::audio::{Downmix, FFT, Resample};
+use crate::chroma::{Chroma, Normalize};
+use crate::fingerprint::{Calculator, ReliabilityCalculator};
+use crate::fingerprint::rolling_image::RollingImage;
+use crate::pipeline::{InplaceThenInplace, Then, ThenInplace};
+
+pub use crate::audio::{Agc, Biquad, DcRemoval, PreEmphasis};
+pub use crate::audio::{NoiseEstimator, NoiseProfile, SpectralSubtract};
+pub use crate::boundary::{confirm_boundaries, find_boundaries, Boundary, BoundaryConfig};
+pub use crate::boundary::{BoundaryKind, ConfirmedBoundary};
+pub use crate::chroma::{Cens, Quantize};
+pub use crate::chroma::{Dtw, StepPattern, WarpingPath};
+pub use crate::chroma::{Key, KeyEstimate, KeyEstimator, KeyProfile, KeySegment, Mode};
+pub use crate::chroma::{ChordLabel, ChordQuality, ChordRecognizer, ChordSegment};
+pub use crate::chroma::{Section, SectionLabel, SelfSimilarity, StructureAnalyzer};
+pub use crate::chroma::{AlignedRegion, VersionMatch, VersionMatcher};
+pub use crate::fingerprint::{best_match, match_transposed, Match, TransposedMatch};
+pub use crate::fingerprint::ReliableSubfingerprint;
+pub use crate::fingerprint::acoustid_compare2;
+pub use crate::fingerprint::{find_clip, ClipMatch};
+pub use crate::fingerprint::{find_repeats, Occurrence, RepeatConfig, RepeatedSegment};
+pub use crate::fingerprint::{find_duplicates, DedupConfig, DedupStats, DuplicateCluster};
+pub use crate::index::{Candidate, Index, Posting, Search, Store, DEFAULT_MASK};
+pub use crate::index::{probe_keys, recall, MultiProbe, ProbeStats};
+pub use crate::monitor::{Event, Monitor, MonitorConfig};
+pub use crate::pipeline::{Inplace, Step};
+pub use crate::score::{midi, Note, NoteOnset, ScoreAligner};
+pub use crate::speed::{match_speed, rates, RateMatch};
+pub use crate::sync::{sync, SyncOffset};
+#[cfg(feature = "server")]
+pub use crate::server::{Server, ServerConfig};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Algorithm {
@@ -23,6 +65,22 @@ pub enum Algorithm {
 }
 
 impl Algorithm {
+    /// Number of the algorithm in libchromaprint, also stored in compressed fingerprints.
+    pub fn id(&self) -> u8 {
+        use Algorithm::*;
+        match self {
+            Test2 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        use Algorithm::*;
+        match id {
+            1 => Some(Test2),
+            _ => None,
+        }
+    }
+
     fn fp_config(&self) -> &FpConfig {
         use Algorithm::*;
         match self {
@@ -60,6 +118,47 @@ impl Filter {
             width,
         }
     }
+
+    /// Applies the filter to the image rows starting at `x`.
+    /// The image columns are circularly rotated left by `shift` first.
+    fn apply(&self, image: &RollingImage, x: usize, shift: usize) -> f64 {
+        use FilterKind::*;
+
+        let y = self.y as usize;
+        let w = self.width as usize;
+        let h = self.height as usize;
+        let area = |r1, c1, r2, c2| image.rotated_area(x + r1, y + c1, x + r2, y + c2, shift);
+
+        let (a, b) = match self.kind {
+            // .oo.
+            // .oo.
+            F0 => (area(0, 0, w, h), 0.0),
+            // .xx.
+            // .oo.
+            F1 => (area(0, h / 2, w, h), area(0, 0, w, h / 2)),
+            // .ox.
+            // .ox.
+            F2 => (area(w / 2, 0, w, h), area(0, 0, w / 2, h)),
+            // .ox.
+            // .xo.
+            F3 => (area(0, h / 2, w / 2, h) + area(w / 2, 0, w, h / 2),
+                area(0, 0, w / 2, h / 2) + area(w / 2, h / 2, w, h)),
+            // .o.
+            // .x.
+            // .o.
+            F4 => {
+                let h3 = h / 3;
+                (area(0, h3, w, 2 * h3), area(0, 0, w, h3) + area(0, 2 * h3, w, h))
+            }
+            // oxo
+            F5 => {
+                let w3 = w / 3;
+                (area(w3, 0, 2 * w3, h), area(0, 0, w3, h) + area(2 * w3, 0, w, h))
+            }
+        };
+
+        ((1.0 + a) / (1.0 + b)).ln()
+    }
 }
 
 #[derive(Debug)]
@@ -80,6 +179,15 @@ impl Quantizer {
             3
         }
     }
+
+    /// Distances from the thresholds that flip the high and the low bit of the Gray coded value,
+    /// relative to the threshold range.
+    fn bit_distances(&self, value: f64) -> (f64, f64) {
+        let range = (self.2 - self.0).max(f64::EPSILON);
+        let high = (value - self.1).abs();
+        let low = (value - self.0).abs().min((value - self.2).abs());
+        (high / range, low / range)
+    }
 }
 
 struct Classifier {
@@ -94,6 +202,18 @@ impl Classifier {
             quantizer,
         }
     }
+
+    fn classify(&self, image: &RollingImage, x: usize, shift: usize) -> u32 {
+        self.quantizer.quantize(self.filter.apply(image, x, shift))
+    }
+
+    /// Same as `classify()` along with `Quantizer::bit_distances()` of the filter response.
+    fn classify_with_reliability(&self, image: &RollingImage, x: usize, shift: usize)
+        -> (u32, (f64, f64))
+    {
+        let value = self.filter.apply(image, x, shift);
+        (self.quantizer.quantize(value), self.quantizer.bit_distances(value))
+    }
 }
 
 const DEFAULT_SAMPLE_RATE: u32 = 11025;
@@ -193,30 +313,245 @@ static FP_CONFIG_TEST2: FpConfig = FpConfig::new(
 
 
 
-struct Fingerprinter {
-    chroma: Chroma,
-//	ChromaNormalizer *m_chroma_normalizer;
-//    chroma_filter: ChromaFilter,
-//	FFT *m_fft;
-//	AudioProcessor *m_audio_processor;
-//	FingerprintCalculator *m_fingerprint_calculator;
-//	FingerprinterConfiguration *m_config;
-//	SilenceRemover *m_silence_remover;
+const MIN_FREQ: u32 = 28;
+const MAX_FREQ: u32 = 3520;
+const NORMALIZE_THRESHOLD: f64 = 0.01;
+
+type PreprocessStep = Option<Box<dyn Inplace<i16>>>;
+type SpectrumStep = Option<Box<dyn Inplace<f64>>>;
+type ChromaCallback = Box<dyn FnMut(&[f64])>;
+type MonoPipeline = ThenInplace<i16, i16, Downmix, PreprocessStep>;
+type AudioPipeline = ThenInplace<i16, f64,
+    Then<i16, i16, f64, Then<i16, i16, i16, MonoPipeline, Resample>, FFT>, SpectrumStep>;
+type ChromaPipeline = Then<i16, f64, f64, AudioPipeline, Chroma>;
+type FeaturePipeline = ThenInplace<i16, f64, Then<i16, f64, f64, ChromaPipeline, chroma::Filter>,
+    InplaceThenInplace<f64, Normalize, Option<ChromaTap>>>;
+
+/// Optional steps inserted into the `Fingerprinter` pipeline.
+#[derive(Default)]
+pub struct FingerprinterOptions {
+    /// Applied to the downmixed mono signal at the input sample rate, for example `Biquad`,
+    /// `DcRemoval`, `PreEmphasis` or `Agc`.
+    pub preprocess: Option<Box<dyn Inplace<i16>>>,
+
+    /// Applied to each `FFT` power frame before chroma extraction, for example
+    /// `SpectralSubtract` with a profile from `noise_profile()`.
+    pub spectrum: Option<Box<dyn Inplace<f64>>>,
+
+    /// Called with each normalized chroma frame the fingerprint is computed from, so analyzers
+    /// like `KeyEstimator` or `ChordRecognizer` can share the fingerprinting pass.
+    pub chroma: Option<ChromaCallback>,
+}
+
+struct ChromaTap(ChromaCallback);
+
+impl Inplace<f64> for ChromaTap {
+    fn process(&mut self, in_out: &mut [f64]) {
+        (self.0)(in_out);
+    }
+}
+
+/// Computes fingerprints from interleaved PCM audio.
+/// Each output slice holds the subfingerprint of one item. For fingerprinters created with
+/// `transposed()` it holds one subfingerprint per transposition instead, see
+/// `fingerprint::transposition`.
+pub struct Fingerprinter(Then<i16, f64, u32, FeaturePipeline, Calculator>);
+
+impl Fingerprinter {
+    pub fn new(algorithm: Algorithm, sample_rate: u32, channel_count: u32) -> Self {
+        Self::with_options(algorithm, sample_rate, channel_count, Default::default())
+    }
+
+    pub fn with_options(
+        algorithm: Algorithm,
+        sample_rate: u32,
+        channel_count: u32,
+        options: FingerprinterOptions) -> Self
+    {
+        Self::with_calculator(algorithm, sample_rate, channel_count, options,
+            Calculator::new(algorithm))
+    }
+
+    pub fn transposed(algorithm: Algorithm, sample_rate: u32, channel_count: u32) -> Self {
+        Self::with_calculator(algorithm, sample_rate, channel_count, Default::default(),
+            Calculator::transposed(algorithm))
+    }
+
+    fn with_calculator(
+        algorithm: Algorithm,
+        sample_rate: u32,
+        channel_count: u32,
+        options: FingerprinterOptions,
+        calculator: Calculator) -> Self
+    {
+        Self(feature_pipeline(algorithm, sample_rate, channel_count, options).then(calculator))
+    }
+}
+
+fn audio_pipeline(
+    algorithm: Algorithm,
+    sample_rate: u32,
+    channel_count: u32,
+    preprocess: PreprocessStep,
+    spectrum: SpectrumStep) -> AudioPipeline
+{
+    let config = algorithm.fp_config();
+    Downmix::new(channel_count)
+        .then_inplace(preprocess)
+        .then(Resample::new(sample_rate, config.sample_rate()))
+        .then(FFT::new(config.frame_size as usize, config.frame_overlap as usize))
+        .then_inplace(spectrum)
+}
+
+fn feature_pipeline(
+    algorithm: Algorithm,
+    sample_rate: u32,
+    channel_count: u32,
+    options: FingerprinterOptions) -> FeaturePipeline
+{
+    let config = algorithm.fp_config();
+    audio_pipeline(algorithm, sample_rate, channel_count, options.preprocess,
+        options.spectrum)
+        .then(Chroma::new(MIN_FREQ, MAX_FREQ, config.frame_size, config.sample_rate(),
+            config.interpolate))
+        .then(chroma::Filter::new(config.filter_coefficients))
+        .then_inplace(Normalize::new(NORMALIZE_THRESHOLD).then(options.chroma.map(ChromaTap)))
+}
+
+impl Step<i16, u32> for Fingerprinter {
+    fn process<F>(&mut self, input: &[i16], output: F)
+        where F: FnMut(&[u32])
+    {
+        self.0.process(input, output);
+    }
+
+    fn finish<F>(&mut self, output: F)
+        where F: FnMut(&[u32])
+    {
+        self.0.finish(output);
+    }
+}
+
+/// Fingerprints the whole interleaved PCM `input` at once.
+pub fn fingerprint(algorithm: Algorithm, sample_rate: u32, channel_count: u32, input: &[i16])
+    -> Vec<u32>
+{
+    let mut r = Vec::new();
+    let fp = &mut Fingerprinter::new(algorithm, sample_rate, channel_count);
+    fp.process(input, |v| r.extend_from_slice(v));
+    fp.finish(|v| r.extend_from_slice(v));
+    r
+}
+
+/// Fingerprints the whole interleaved PCM `input` at once, along with the reliability of each
+/// subfingerprint bit. Used to prioritize lookups of noisy queries, see `index::probe`.
+pub fn fingerprint_with_reliability(
+    algorithm: Algorithm,
+    sample_rate: u32,
+    channel_count: u32,
+    input: &[i16]) -> Vec<ReliableSubfingerprint>
+{
+    let mut r = Vec::new();
+    let fp = &mut feature_pipeline(algorithm, sample_rate, channel_count, Default::default())
+        .then(ReliabilityCalculator::new(algorithm));
+    fp.process(input, |v| r.extend_from_slice(v));
+    fp.finish(|v| r.extend_from_slice(v));
+    r
+}
+
+/// Estimates the noise in the interleaved PCM `input` from its `frame_count` quietest `FFT`
+/// frames. The profile is meant for `SpectralSubtract` in `FingerprinterOptions::spectrum`.
+/// Returns `None` if the input is shorter than one frame.
+pub fn noise_profile(
+    algorithm: Algorithm,
+    sample_rate: u32,
+    channel_count: u32,
+    input: &[i16],
+    frame_count: usize) -> Option<NoiseProfile>
+{
+    let estimator = &mut NoiseEstimator::new(frame_count);
+    let buf = &mut Vec::new();
+    let mut estimate = |v: &[f64]| {
+        buf.clear();
+        buf.extend_from_slice(v);
+        estimator.process(buf);
+    };
+    let p = &mut audio_pipeline(algorithm, sample_rate, channel_count, None, None);
+    p.process(input, &mut estimate);
+    p.finish(&mut estimate);
+    estimator.profile()
 }
 
+/// Fingerprinting context with the life cycle of libchromaprint's `ChromaprintContext`:
+/// `start()`, any number of `feed()` calls and `finish()`, after which the fingerprint is
+/// available until the next `start()`.
 pub struct Chromaprint {
     algorithm: Algorithm,
+    fingerprinter: Option<Fingerprinter>,
+    fingerprint: Vec<u32>,
+}
+
+impl Chromaprint {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self {
+            algorithm,
+            fingerprinter: None,
+            fingerprint: Vec::new(),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
 
+    /// Starts a new fingerprint of interleaved PCM audio, discarding the previous one.
+    pub fn start(&mut self, sample_rate: u32, channel_count: u32) {
+        self.fingerprinter = Some(Fingerprinter::new(self.algorithm, sample_rate, channel_count));
+        self.fingerprint.clear();
+    }
+
+    /// Returns `false` if fingerprinting isn't started.
+    pub fn feed(&mut self, input: &[i16]) -> bool {
+        let fingerprint = &mut self.fingerprint;
+        if let Some(fp) = &mut self.fingerprinter {
+            fp.process(input, |v| fingerprint.extend_from_slice(v));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns `false` if fingerprinting isn't started.
+    pub fn finish(&mut self) -> bool {
+        let fingerprint = &mut self.fingerprint;
+        if let Some(mut fp) = self.fingerprinter.take() {
+            fp.finish(|v| fingerprint.extend_from_slice(v));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Subfingerprints computed so far.
+    pub fn fingerprint(&self) -> &[u32] {
+        &self.fingerprint
+    }
+
+    pub fn clear_fingerprint(&mut self) {
+        self.fingerprint.clear();
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    use audio::*;
     use chroma::*;
-    use pipeline::Step;
-    use pipeline::test_util::collect;
+    use pipeline::test_util::{collect, collect_flat};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use std::cell::RefCell;
+    use std::rc::Rc;
     use test_util::*;
 
     #[test]
@@ -243,6 +578,149 @@ mod test {
 
         // FIXME
     }
+
+    #[test]
+    fn fingerprinter() {
+        // The test file is too short for a fingerprint on its own.
+        let inp = &read_audio_raw(include_bytes!("../tests/data/test_stereo_44100.raw")).repeat(5)[..];
+        let config = Algorithm::Test2.fp_config();
+
+        let act = &fingerprint(Algorithm::Test2, 44100, 2, inp);
+
+        // Every chroma frame after the chroma filter and the widest classifier are warmed up
+        // yields an item.
+        let frames = (inp.len() / 2 * 11025 / 44100 - config.frame_overlap as usize) /
+            config.item_duration() as usize;
+        let warm_up = config.filter_coefficients.len() + config.max_filter_width as usize - 2;
+        assert!((act.len() as isize - (frames - warm_up) as isize).abs() <= 1,
+            "{} {}", act.len(), frames - warm_up);
+
+        // Streaming in small chunks gives the same result.
+        let fp = &mut Fingerprinter::new(Algorithm::Test2, 44100, 2);
+        let streamed = &mut Vec::new();
+        for chunk in inp.chunks(1000) {
+            fp.process(chunk, collect_flat(streamed));
+        }
+        fp.finish(collect_flat(streamed));
+        assert_eq!(streamed, act);
+
+        // Shift 0 of the transposed fingerprinter is the plain fingerprint.
+        let transposed = &mut Vec::new();
+        let fp = &mut Fingerprinter::transposed(Algorithm::Test2, 44100, 2);
+        fp.process(inp, collect_flat(transposed));
+        fp.finish(collect_flat(transposed));
+        let shift0: Vec<_> = transposed.chunks(BAND_COUNT).map(|v| v[0]).collect();
+        assert_eq!(&shift0, act);
+
+        let reliable = fingerprint_with_reliability(Algorithm::Test2, 44100, 2, inp);
+        assert_eq!(&reliable.iter().map(|v| v.value).collect::<Vec<_>>(), act);
+    }
+
+    #[test]
+    fn chroma_tap() {
+        let inp = &read_audio_raw(include_bytes!("../tests/data/test_stereo_44100.raw")).repeat(5)[..];
+
+        let expected = &mut Vec::new();
+        let p = &mut feature_pipeline(Algorithm::Test2, 44100, 2, Default::default());
+        p.process(inp, collect(expected));
+        p.finish(collect(expected));
+        assert!(!expected.is_empty());
+
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let fp = &mut Fingerprinter::with_options(Algorithm::Test2, 44100, 2,
+            FingerprinterOptions {
+                chroma: Some(Box::new({
+                    let frames = frames.clone();
+                    move |v| frames.borrow_mut().push(v.to_vec())
+                })),
+                ..Default::default()
+            });
+        let actual = &mut Vec::new();
+        for chunk in inp.chunks(1000) {
+            fp.process(chunk, collect_flat(actual));
+        }
+        fp.finish(collect_flat(actual));
+
+        assert_eq!(&*frames.borrow(), expected);
+        assert_eq!(actual, &fingerprint(Algorithm::Test2, 44100, 2, inp));
+    }
+
+    #[test]
+    fn preprocess() {
+        const SAMPLE_RATE: u32 = 11025;
+
+        let left = melody(1, SAMPLE_RATE, 20);
+        let right = melody(2, SAMPLE_RATE, 20);
+        let stereo: Vec<_> = left.iter().zip(&right).flat_map(|(&l, &r)| vec![l, r]).collect();
+        let new_filter = || Biquad::high_pass(SAMPLE_RATE, 300.0, Biquad::BUTTERWORTH_Q)
+            .then(Agc::new(SAMPLE_RATE, 0.3, 10.0, 0.01, 0.5));
+
+        // The filters see the downmixed signal, so their state isn't shared between channels.
+        let mono = &mut Vec::new();
+        let dm = &mut Downmix::new(2).then_inplace(new_filter());
+        dm.process(&stereo, collect_flat(mono));
+        dm.finish(collect_flat(mono));
+        let expected = &fingerprint(Algorithm::Test2, SAMPLE_RATE, 1, mono);
+
+        let fp = &mut Fingerprinter::with_options(Algorithm::Test2, SAMPLE_RATE, 2,
+            FingerprinterOptions {
+                preprocess: Some(Box::new(new_filter())),
+                ..Default::default()
+            });
+        let actual = &mut Vec::new();
+        for chunk in stereo.chunks(1000) {
+            fp.process(chunk, collect_flat(actual));
+        }
+        fp.finish(collect_flat(actual));
+        assert!(!expected.is_empty());
+        assert_eq!(actual, expected);
+
+        let unfiltered = &fingerprint(Algorithm::Test2, SAMPLE_RATE, 2, &stereo);
+        assert_ne!(unfiltered, expected);
+    }
+
+    #[test]
+    fn denoise() {
+        const SAMPLE_RATE: u32 = 11025;
+        const NOISE_AMP: i16 = 12000;
+
+        let rng = &mut StdRng::seed_from_u64(1);
+        let mut noise = |len| (0..len)
+            .map(|_| rng.gen_range(-NOISE_AMP, NOISE_AMP))
+            .collect::<Vec<i16>>();
+
+        let clean = &melody(1, SAMPLE_RATE, 30);
+        let noisy: Vec<_> = clean.iter().zip(noise(clean.len()))
+            .map(|(&c, n)| c.saturating_add(n))
+            .collect();
+        let profile = noise_profile(Algorithm::Test2, SAMPLE_RATE, 1,
+            &noise(5 * SAMPLE_RATE as usize), 20).unwrap();
+
+        let reference = &fingerprint(Algorithm::Test2, SAMPLE_RATE, 1, clean);
+        let plain = &fingerprint(Algorithm::Test2, SAMPLE_RATE, 1, &noisy);
+        let fp = &mut Fingerprinter::with_options(Algorithm::Test2, SAMPLE_RATE, 1,
+            FingerprinterOptions {
+                spectrum: Some(Box::new(SpectralSubtract::new(profile, 2.0, 0.01))),
+                ..Default::default()
+            });
+        let denoised = &mut Vec::new();
+        fp.process(&noisy, collect_flat(denoised));
+        fp.finish(collect_flat(denoised));
+        assert_eq!(denoised.len(), reference.len());
+
+        let bit_errors = |fp: &[u32]| fp.iter().zip(reference)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum::<u32>() as f64 / (reference.len() * 32) as f64;
+        assert!(bit_errors(denoised) < bit_errors(plain) / 2.0);
+    }
+
+    #[test]
+    fn bit_distances() {
+        let q = Quantizer(-1.0, 0.0, 1.0);
+        assert_eq!(q.bit_distances(0.0), (0.0, 0.5));
+        assert_eq!(q.bit_distances(-1.5), (0.75, 0.25));
+        assert_eq!(q.bit_distances(0.75), (0.375, 0.125));
+    }
 }
 
 
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+
+use crate::{Algorithm, Fingerprinter};
+use crate::index::Search;
+use crate::pipeline::Step;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// Track started playing at `time` seconds since the start of the stream.
+    Started {
+        track_id: u32,
+        time: f64,
+    },
+
+    /// Track stopped playing at `time` seconds since the start of the stream.
+    Ended {
+        track_id: u32,
+        time: f64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MonitorConfig {
+    /// Number of most recent subfingerprints searched in the index.
+    pub window_len: usize,
+
+    /// Number of new subfingerprints between searches.
+    pub hop: usize,
+
+    /// Minimum number of hits for a track to be detected in a window.
+    pub min_hits: usize,
+
+    /// Number of consecutive windows a track must be detected in before it's reported started.
+    pub start_after: usize,
+
+    /// Number of consecutive windows a playing track must be missing from before it's reported
+    /// ended.
+    pub end_after: usize,
+}
+
+impl Default for MonitorConfig {
+    /// Searches the last ~10 seconds every ~second.
+    fn default() -> Self {
+        Self {
+            window_len: 80,
+            hop: 8,
+            min_hits: 10,
+            start_after: 2,
+            end_after: 3,
+        }
+    }
+}
+
+/// Monitors a continuous PCM stream for plays of tracks in an index.
+/// The stream is fingerprinted as it comes and the index is searched with a sliding window of
+/// the most recent subfingerprints. Detections only turn into events after several consecutive
+/// windows agree, so short dropouts and spurious matches don't produce events.
+/// Memory use doesn't grow with the stream length.
+pub struct Monitor<S> {
+    index: S,
+    config: MonitorConfig,
+    item_duration: f64,
+
+    /// Audio covered by a subfingerprint, in seconds past its start.
+    item_span: f64,
+
+    fingerprinter: Fingerprinter,
+    window: VecDeque<u32>,
+
+    /// Total number of subfingerprints so far.
+    item_count: u64,
+
+    since_search: usize,
+    playing: Option<Playing>,
+
+    /// Track detected in the latest windows that isn't reported yet.
+    pending: Option<Pending>,
+
+    events: Vec<Event>,
+}
+
+struct Playing {
+    track_id: u32,
+    misses: usize,
+
+    /// Most hits in a window so far, i.e. hits when the track covers the whole window.
+    peak_hits: usize,
+
+    /// Estimated end of the track in the last window it was detected in.
+    last_seen: f64,
+}
+
+struct Pending {
+    track_id: u32,
+    count: usize,
+    start: f64,
+}
+
+impl<S: Search> Monitor<S> {
+    pub fn new(
+        index: S,
+        algorithm: Algorithm,
+        sample_rate: u32,
+        channel_count: u32,
+        config: MonitorConfig) -> Self
+    {
+        assert!(config.window_len > 0);
+        assert!(config.hop > 0);
+        assert!(config.start_after > 0);
+        assert!(config.end_after > 0);
+        Self {
+            index,
+            config,
+            item_duration: algorithm.fp_config().item_duration_in_seconds(),
+            item_span: algorithm.fp_config().delay_in_seconds() +
+                algorithm.fp_config().item_duration_in_seconds(),
+            fingerprinter: Fingerprinter::new(algorithm, sample_rate, channel_count),
+            window: VecDeque::with_capacity(config.window_len),
+            item_count: 0,
+            since_search: 0,
+            playing: None,
+            pending: None,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn index(&self) -> &S {
+        &self.index
+    }
+
+    /// Track that's currently playing.
+    pub fn playing(&self) -> Option<u32> {
+        self.playing.as_ref().map(|p| p.track_id)
+    }
+
+    fn push(&mut self, item: u32) {
+        if self.window.len() == self.config.window_len {
+            self.window.pop_front();
+        }
+        self.window.push_back(item);
+        self.item_count += 1;
+        self.since_search += 1;
+        if self.window.len() == self.config.window_len && self.since_search >= self.config.hop {
+            self.since_search = 0;
+            self.search();
+        }
+    }
+
+    /// Start of the audio the item at `pos` in the window covers.
+    fn time(&self, pos: f64) -> f64 {
+        ((self.item_count - self.window.len() as u64) as f64 + pos) * self.item_duration
+    }
+
+    fn search(&mut self) {
+        let query: Vec<_> = self.window.iter().cloned().collect();
+        let detected = self.index.search(&query, 1).into_iter()
+            .find(|c| c.hits >= self.config.min_hits);
+
+        if let Some(playing) = &self.playing {
+            if let Some(c) = detected.filter(|c| c.track_id == playing.track_id) {
+                // A track that's ending covers the start of the window, the share of the
+                // window it covers shows where it ends.
+                let peak_hits = playing.peak_hits.max(c.hits);
+                let covered = c.hits as f64 / peak_hits as f64 * self.window.len() as f64;
+                let last_seen = self.time(covered - 1.0) + self.item_span;
+                let playing = self.playing.as_mut().unwrap();
+                playing.misses = 0;
+                playing.peak_hits = peak_hits;
+                playing.last_seen = last_seen;
+                self.pending = None;
+                return;
+            }
+        }
+        if let Some(playing) = &mut self.playing {
+            playing.misses += 1;
+            if playing.misses >= self.config.end_after {
+                self.events.push(Event::Ended {
+                    track_id: playing.track_id,
+                    time: playing.last_seen,
+                });
+                self.playing = None;
+            }
+        }
+
+        let c = if let Some(v) = detected {
+            v
+        } else {
+            self.pending = None;
+            return;
+        };
+        match &mut self.pending {
+            Some(p) if p.track_id == c.track_id => p.count += 1,
+            _ => {
+                // The track starts within the window if its first item aligns past the window
+                // start.
+                let start = self.time((-c.offset).max(0) as f64);
+                self.pending = Some(Pending {
+                    track_id: c.track_id,
+                    count: 1,
+                    start,
+                });
+            }
+        }
+        let p = self.pending.as_ref().unwrap();
+        if p.count >= self.config.start_after && self.playing.is_none() {
+            self.events.push(Event::Started {
+                track_id: p.track_id,
+                time: p.start,
+            });
+            self.playing = Some(Playing {
+                track_id: p.track_id,
+                misses: 0,
+                peak_hits: c.hits,
+                last_seen: self.time(self.window.len() as f64 - 1.0) + self.item_span,
+            });
+            self.pending = None;
+        }
+    }
+
+    fn flush_events<F>(&mut self, mut output: F)
+        where F: FnMut(&[Event])
+    {
+        if !self.events.is_empty() {
+            output(&self.events);
+            self.events.clear();
+        }
+    }
+}
+
+impl<S: Search> Step<i16, Event> for Monitor<S> {
+    fn process<F>(&mut self, input: &[i16], output: F)
+        where F: FnMut(&[Event])
+    {
+        let items = &mut Vec::new();
+        self.fingerprinter.process(input, |v| items.extend_from_slice(v));
+        for &item in items.iter() {
+            self.push(item);
+        }
+        self.flush_events(output);
+    }
+
+    /// Reports the playing track as ended.
+    fn finish<F>(&mut self, output: F)
+        where F: FnMut(&[Event])
+    {
+        let items = &mut Vec::new();
+        self.fingerprinter.finish(|v| items.extend_from_slice(v));
+        for &item in items.iter() {
+            self.push(item);
+        }
+        if let Some(p) = self.playing.take() {
+            self.events.push(Event::Ended {
+                track_id: p.track_id,
+                time: p.last_seen,
+            });
+        }
+        self.flush_events(output);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fingerprint;
+    use crate::index::Index;
+    use crate::pipeline::test_util::*;
+    use crate::test_util::melody;
+
+    const SAMPLE_RATE: u32 = 11025;
+
+    fn silence(secs: usize) -> Vec<i16> {
+        vec![0; secs * SAMPLE_RATE as usize]
+    }
+
+    #[test]
+    fn test() {
+        let a = melody(1, SAMPLE_RATE, 30);
+        let b = melody(2, SAMPLE_RATE, 30);
+        let c = melody(3, SAMPLE_RATE, 30);
+        let index = &mut Index::default();
+        index.insert(1, &fingerprint(Algorithm::Test2, SAMPLE_RATE, 1, &a));
+        index.insert(2, &fingerprint(Algorithm::Test2, SAMPLE_RATE, 1, &b));
+        index.insert(3, &fingerprint(Algorithm::Test2, SAMPLE_RATE, 1, &c));
+
+        // Middle of A, silence, B from the start, straight into the start of A.
+        let secs = |v: usize| v * SAMPLE_RATE as usize;
+        let stream = [&a[secs(10)..], &silence(15), &b[..], &a[..secs(20)]].concat();
+
+        let m = &mut Monitor::new(&*index, Algorithm::Test2, SAMPLE_RATE, 1,
+            MonitorConfig::default());
+        let mut events = Vec::new();
+        for chunk in stream.chunks(4096) {
+            m.process(chunk, collect_flat(&mut events));
+        }
+        m.finish(collect_flat(&mut events));
+
+        let expected = [
+            Event::Started { track_id: 1, time: 0.0 },
+            Event::Ended { track_id: 1, time: 20.0 },
+            Event::Started { track_id: 2, time: 35.0 },
+            Event::Ended { track_id: 2, time: 65.0 },
+            Event::Started { track_id: 1, time: 65.0 },
+            Event::Ended { track_id: 1, time: 85.0 },
+        ];
+        assert_eq!(events.len(), expected.len(), "{:?}", events);
+        for (act, exp) in events.iter().zip(&expected) {
+            match (act, exp) {
+                (Event::Started { track_id: a, time: at }, Event::Started { track_id: e, time: et })
+                | (Event::Ended { track_id: a, time: at }, Event::Ended { track_id: e, time: et })
+                => {
+                    assert_eq!(a, e, "{:?}", events);
+                    assert!((at - et).abs() < 3.0, "{:?}", events);
+                }
+                _ => panic!("{:?}", events),
+            }
+        }
+        assert_eq!(m.playing(), None);
+        assert!(m.window.len() <= MonitorConfig::default().window_len);
+    }
+}
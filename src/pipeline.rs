@@ -81,6 +81,21 @@ pub trait Inplace<T> {
     }
 }
 
+impl<T, S: Inplace<T> + ?Sized> Inplace<T> for Box<S> {
+    fn process(&mut self, in_out: &mut [T]) {
+        (**self).process(in_out);
+    }
+}
+
+/// Does nothing if `None`.
+impl<T, S: Inplace<T>> Inplace<T> for Option<S> {
+    fn process(&mut self, in_out: &mut [T]) {
+        if let Some(v) = self {
+            v.process(in_out);
+        }
+    }
+}
+
 pub struct ThenInplace<I, O, S1, S2> {
     step: S1,
     inplace: S2,
@@ -67,13 +67,13 @@ mod test {
         assert_eq!(&b.buf, &[4, 5]);
 
         assert_eq!(&process(b, &[6, 7, 8, 9]), &[vec![4, 5, 6], vec![7, 8, 9]]);
-        assert_eq!(&b.buf, &[]);
+        assert!(b.buf.is_empty());
 
         assert!(process(b, &[10]).is_empty());
         assert_eq!(&b.buf, &[10]);
 
         assert_eq!(&finish(b), &[vec![10]]);
-        assert_eq!(&b.buf, &[]);
+        assert!(b.buf.is_empty());
     }
 
     #[test]
@@ -94,6 +94,6 @@ mod test {
         assert!(b.buf.is_empty());
 
         assert!(finish(b).is_empty());
-        assert_eq!(&b.buf, &[]);
+        assert!(b.buf.is_empty());
     }
 }
\ No newline at end of file
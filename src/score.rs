@@ -0,0 +1,269 @@
+pub mod midi;
+
+use crate::{Algorithm, MIN_FREQ, MAX_FREQ, NORMALIZE_THRESHOLD};
+use crate::audio::{Downmix, FFT, Resample};
+use crate::chroma::{self, Chroma, Dtw, Normalize, StepPattern, WarpingPath};
+use crate::pipeline::{Inplace, Step};
+
+pub use midi::Note;
+
+/// Weights of the harmonics rendered for each note, starting with the fundamental.
+/// Real instruments put a good share of their energy into overtones, the third and fifth
+/// harmonics in particular land in other chroma bands.
+const HARMONIC_WEIGHTS: &[f64] = &[1.0, 0.5, 0.33, 0.25, 0.2];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteOnset {
+    pub note: Note,
+
+    /// Time in seconds at which the note starts in the recording.
+    pub onset: f64,
+}
+
+/// Aligns scores read from MIDI files to recordings of them.
+/// The score is rendered straight into chroma frames with the same hop and window as the
+/// `FFT`/`Chroma` pipeline of the `algorithm`, so both sequences can be aligned with `Dtw`.
+pub struct ScoreAligner {
+    algorithm: Algorithm,
+    dtw: Dtw,
+
+    /// Search radius of multiscale DTW or `None` for full DTW.
+    radius: Option<usize>,
+}
+
+impl ScoreAligner {
+    pub fn new(algorithm: Algorithm, pattern: StepPattern, radius: Option<usize>) -> Self {
+        Self {
+            algorithm,
+            dtw: Dtw::new(pattern),
+            radius,
+        }
+    }
+
+    /// Duration of a chroma frame hop in seconds.
+    pub fn frame_duration(&self) -> f64 {
+        self.algorithm.fp_config().item_duration_in_seconds()
+    }
+
+    fn window_duration(&self) -> f64 {
+        let config = self.algorithm.fp_config();
+        config.frame_size as f64 / config.sample_rate() as f64
+    }
+
+    /// Renders the notes into normalized chroma frames. Frame `i` covers the same time span as
+    /// the `FFT` frame `i` would and every note adds energy in proportion to its velocity and
+    /// how much of the frame it overlaps. Percussion notes carry no pitch and are skipped.
+    pub fn render(&self, notes: &[Note]) -> Vec<Vec<f64>> {
+        let hop = self.frame_duration();
+        let window = self.window_duration();
+        let end = notes.iter().map(|n| n.end).fold(0.0, f64::max);
+        let frame_count = (end / hop).ceil() as usize;
+
+        let mut r = vec![vec![0.0; chroma::BAND_COUNT]; frame_count];
+        for note in notes {
+            if note.channel == midi::PERCUSSION_CHANNEL {
+                continue;
+            }
+            let first = ((note.start - window) / hop).floor().max(0.0) as usize;
+            for (i, frame) in r.iter_mut().enumerate().skip(first) {
+                let frame_start = i as f64 * hop;
+                if frame_start >= note.end {
+                    break;
+                }
+                let overlap = note.end.min(frame_start + window) - note.start.max(frame_start);
+                if overlap <= 0.0 {
+                    continue;
+                }
+                let energy = overlap / window * note.velocity as f64 / 127.0;
+                for (h, &weight) in HARMONIC_WEIGHTS.iter().enumerate() {
+                    let semitones = (12.0 * ((h + 1) as f64).log2()).round() as usize;
+                    // `Chroma` bands start right at note frequencies, so the energy of a tone
+                    // leaks into the band below as much as into its own.
+                    let key = note.key as usize + semitones;
+                    frame[band(key)] += energy * weight / 2.0;
+                    frame[band(key + chroma::BAND_COUNT - 1)] += energy * weight / 2.0;
+                }
+            }
+        }
+
+        let normalize = &mut Normalize::new(NORMALIZE_THRESHOLD);
+        for frame in &mut r {
+            normalize.process(frame);
+        }
+        r
+    }
+
+    /// Computes normalized chroma frames of interleaved PCM audio.
+    pub fn audio_chroma(&self, sample_rate: u32, channel_count: u32, input: &[i16])
+        -> Vec<Vec<f64>>
+    {
+        let config = self.algorithm.fp_config();
+        let pipeline = &mut Downmix::new(channel_count)
+            .then(Resample::new(sample_rate, config.sample_rate()))
+            .then(FFT::new(config.frame_size as usize, config.frame_overlap as usize))
+            .then(Chroma::new(MIN_FREQ, MAX_FREQ, config.frame_size, config.sample_rate(),
+                config.interpolate))
+            .then_inplace(Normalize::new(NORMALIZE_THRESHOLD));
+
+        let mut r = Vec::new();
+        pipeline.process(input, |v| r.push(v.to_vec()));
+        pipeline.finish(|v| r.push(v.to_vec()));
+        r
+    }
+
+    /// Aligns the notes to the chroma frames of a recording as returned by `audio_chroma()`.
+    /// Returns onsets in the same order as `notes` or `None` if the alignment fails.
+    pub fn align(&self, notes: &[Note], audio_chroma: &[Vec<f64>]) -> Option<Vec<NoteOnset>> {
+        let score_chroma = self.render(notes);
+        let path = match self.radius {
+            Some(radius) => self.dtw.align_multiscale(&score_chroma, audio_chroma, radius),
+            None => self.dtw.align(&score_chroma, audio_chroma),
+        }?;
+        let hop = self.frame_duration();
+        Some(notes.iter()
+            .map(|&note| NoteOnset {
+                note,
+                onset: onset_frame(&path, note.start / hop) * hop,
+            })
+            .collect())
+    }
+
+    /// Aligns the notes to the interleaved PCM audio.
+    pub fn align_audio(&self, notes: &[Note], sample_rate: u32, channel_count: u32,
+        input: &[i16]) -> Option<Vec<NoteOnset>>
+    {
+        self.align(notes, &self.audio_chroma(sample_rate, channel_count, input))
+    }
+}
+
+impl Default for ScoreAligner {
+    fn default() -> Self {
+        Self::new(Algorithm::Test2, StepPattern::Symmetric, Some(8))
+    }
+}
+
+/// Maps a score frame to the recording. Unlike `WarpingPath::map()` this picks the first
+/// recording frame aligned with the score frame: when the recording lingers on a frame the
+/// note starts at the beginning of the run, not in its middle.
+fn onset_frame(path: &WarpingPath, frame: f64) -> f64 {
+    let pairs = &path.pairs;
+    let i = pairs.iter().position(|&(a, _)| a as f64 >= frame).unwrap_or(pairs.len() - 1);
+    let (a1, b1) = pairs[i];
+    if i == 0 || a1 as f64 <= frame {
+        return b1 as f64;
+    }
+    // The previous score frame may span several recording frames, interpolate from its last one.
+    let (a0, b0) = pairs[i - 1];
+    b0 as f64 + (b1 as f64 - b0 as f64) * (frame - a0 as f64) / (a1 - a0) as f64
+}
+
+/// Chroma band of a MIDI key. Band 0 is A, which is key 69.
+fn band(key: usize) -> usize {
+    (key + chroma::BAND_COUNT - 69 % chroma::BAND_COUNT) % chroma::BAND_COUNT
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f64::consts::PI;
+    use super::midi::test_util::Writer;
+
+    fn note(key: u8, start: f64, end: f64) -> Note {
+        Note { key, velocity: 100, channel: 0, start, end }
+    }
+
+    fn sine_tones(notes: &[Note], tempo: f64, sample_rate: u32) -> Vec<i16> {
+        let end = notes.iter().map(|n| n.end).fold(0.0, f64::max) * tempo;
+        let mut r = vec![0.0; (end * sample_rate as f64) as usize];
+        for n in notes {
+            let freq = 440.0 * 2f64.powf((n.key as f64 - 69.0) / 12.0);
+            let start = (n.start * tempo * sample_rate as f64) as usize;
+            let end = (n.end * tempo * sample_rate as f64) as usize;
+            for (i, v) in r[start..end].iter_mut().enumerate() {
+                *v += (2.0 * PI * freq * i as f64 / sample_rate as f64).sin() * 8000.0;
+            }
+        }
+        r.into_iter().map(|v| v as i16).collect()
+    }
+
+    #[test]
+    fn band_fn() {
+        assert_eq!(band(69), 0);
+        assert_eq!(band(60), 3);
+        assert_eq!(band(21), 0);
+        assert_eq!(band(0), 3);
+        assert_eq!(band(127), 10);
+    }
+
+    #[test]
+    fn render() {
+        let a = &ScoreAligner::default();
+        let hop = a.frame_duration();
+        let chroma = a.render(&[
+            note(69, 0.0, 1.0),
+            Note { channel: midi::PERCUSSION_CHANNEL, ..note(60, 0.0, 1.0) },
+            note(60, 1.0, 2.0),
+        ]);
+        assert_eq!(chroma.len(), (2.0 / hop).ceil() as usize);
+
+        let first = &chroma[0];
+        assert!(first.iter().all(|&v| v <= first[0]));
+        assert_eq!(first[0], first[11]);
+        // Third harmonic is E.
+        assert!(first[7] > 0.0);
+        assert_eq!(first[2], 0.0);
+
+        let last = chroma.last().unwrap();
+        assert_eq!(last[0], 0.0);
+        assert_eq!(last[3], last[2]);
+        assert!(last[3] > 0.6);
+    }
+
+    #[test]
+    fn align_stretched() {
+        let a = &ScoreAligner::new(Algorithm::Test2, StepPattern::Symmetric, None);
+        let notes = &[
+            note(60, 0.0, 1.0),
+            note(64, 1.0, 2.0),
+            note(67, 2.0, 3.0),
+            note(72, 3.0, 4.0),
+        ];
+
+        // Recording played twice slower.
+        let stretched: Vec<_> = notes.iter()
+            .map(|n| note(n.key, n.start * 2.0, n.end * 2.0))
+            .collect();
+        let audio_chroma = a.render(&stretched);
+
+        let onsets = a.align(notes, &audio_chroma).unwrap();
+        for (o, n) in onsets.iter().zip(notes) {
+            assert_eq!(o.note, *n);
+            assert!((o.onset - n.start * 2.0).abs() < 0.3, "{:?}", o);
+        }
+    }
+
+    #[test]
+    fn align_audio() {
+        let data = Writer::new(4)
+            // 60 bpm: one second per quarter note.
+            .track(&[
+                (0, &[0xff, 0x51, 3, 0x0f, 0x42, 0x40]),
+                (0, &[0x90, 60, 100]), (4, &[0x80, 60, 0]),
+                (0, &[0x90, 64, 100]), (4, &[0x80, 64, 0]),
+                (0, &[0x90, 67, 100]), (4, &[0x80, 67, 0]),
+                (0, &[0x90, 71, 100]), (4, &[0x80, 71, 0]),
+                (0, &[0x90, 62, 100]), (4, &[0x80, 62, 0]),
+            ])
+            .build();
+        let notes = &midi::read(&data).unwrap();
+        assert_eq!(notes.len(), 5);
+
+        let tempo = 1.25;
+        let audio = sine_tones(notes, tempo, 11025);
+
+        let onsets = ScoreAligner::default().align_audio(notes, 11025, 1, &audio).unwrap();
+        for o in &onsets {
+            assert!((o.onset - o.note.start * tempo).abs() < 0.4, "{:?}", o);
+        }
+    }
+}
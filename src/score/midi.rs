@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    UnexpectedEof,
+    BadChunk([u8; 4]),
+    BadHeader,
+    UnsupportedFormat(u16),
+    BadEvent(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnexpectedEof => write!(f, "unexpected end of MIDI data"),
+            Error::BadChunk(id) => write!(f, "unexpected MIDI chunk {:?}", id),
+            Error::BadHeader => write!(f, "bad MIDI header"),
+            Error::UnsupportedFormat(v) => write!(f, "unsupported MIDI format {}", v),
+            Error::BadEvent(v) => write!(f, "bad MIDI event status 0x{:02x}", v),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// MIDI channel reserved for percussion in General MIDI (channel 10, zero based).
+pub const PERCUSSION_CHANNEL: u8 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Note {
+    /// MIDI key number, 60 is middle C.
+    pub key: u8,
+
+    pub velocity: u8,
+    pub channel: u8,
+
+    /// Start time in seconds.
+    pub start: f64,
+
+    /// End time in seconds.
+    pub end: f64,
+}
+
+/// Reads notes from a Standard MIDI File (format 0, 1 or 2).
+/// Notes are sorted by start time. Tempo changes from all tracks apply to all tracks.
+pub fn read(data: &[u8]) -> Result<Vec<Note>> {
+    let rd = &mut Reader::new(data);
+
+    if rd.chunk_id()? != *b"MThd" {
+        return Err(Error::BadHeader);
+    }
+    let header = rd.chunk()?;
+    if header.len() < 6 {
+        return Err(Error::BadHeader);
+    }
+    let format = u16::from_be_bytes([header[0], header[1]]);
+    if format > 2 {
+        return Err(Error::UnsupportedFormat(format));
+    }
+    let track_count = u16::from_be_bytes([header[2], header[3]]);
+    let division = u16::from_be_bytes([header[4], header[5]]);
+
+    let mut tempos = Vec::new();
+    let mut tick_notes = Vec::new();
+    for _ in 0..track_count {
+        let id = rd.chunk_id()?;
+        let chunk = rd.chunk()?;
+        // Unknown chunks must be skipped.
+        if id == *b"MTrk" {
+            read_track(chunk, &mut tempos, &mut tick_notes)?;
+        }
+    }
+
+    let clock = Clock::new(division, tempos);
+    let mut r: Vec<Note> = tick_notes.iter()
+        .map(|n| Note {
+            key: n.key,
+            velocity: n.velocity,
+            channel: n.channel,
+            start: clock.seconds(n.start),
+            end: clock.seconds(n.end),
+        })
+        .collect();
+    r.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap().then(a.key.cmp(&b.key)));
+    Ok(r)
+}
+
+struct TickNote {
+    key: u8,
+    velocity: u8,
+    channel: u8,
+    start: u64,
+    end: u64,
+}
+
+fn read_track(data: &[u8], tempos: &mut Vec<(u64, u32)>, notes: &mut Vec<TickNote>)
+    -> Result<()>
+{
+    let rd = &mut Reader::new(data);
+
+    // Pending note ons by (channel, key).
+    let mut on: HashMap<(u8, u8), Vec<(u64, u8)>> = HashMap::new();
+    let mut tick = 0;
+    let mut running_status = None;
+
+    while !rd.is_empty() {
+        tick += rd.var_len()? as u64;
+
+        let mut status = rd.peek()?;
+        if status & 0x80 != 0 {
+            rd.skip(1)?;
+        } else {
+            status = running_status.ok_or(Error::BadEvent(status))?;
+        }
+
+        match status {
+            0xff => {
+                let kind = rd.byte()?;
+                let len = rd.var_len()? as usize;
+                let payload = rd.bytes(len)?;
+                match kind {
+                    // End of track.
+                    0x2f => break,
+                    // Set tempo.
+                    0x51 if len == 3 => {
+                        let usecs = u32::from_be_bytes([0, payload[0], payload[1], payload[2]]);
+                        tempos.push((tick, usecs));
+                    }
+                    _ => {}
+                }
+            }
+            0xf0 | 0xf7 => {
+                let len = rd.var_len()? as usize;
+                rd.skip(len)?;
+            }
+            0x80..=0xef => {
+                running_status = Some(status);
+                let channel = status & 0x0f;
+                match status & 0xf0 {
+                    0x80 | 0x90 => {
+                        let key = rd.byte()?;
+                        let velocity = rd.byte()?;
+                        if status & 0xf0 == 0x90 && velocity > 0 {
+                            on.entry((channel, key)).or_default().push((tick, velocity));
+                        } else if let Some(pending) = on.get_mut(&(channel, key)) {
+                            if !pending.is_empty() {
+                                let (start, velocity) = pending.remove(0);
+                                notes.push(TickNote { key, velocity, channel, start, end: tick });
+                            }
+                        }
+                    }
+                    0xc0 | 0xd0 => rd.skip(1)?,
+                    _ => rd.skip(2)?,
+                }
+            }
+            _ => return Err(Error::BadEvent(status)),
+        }
+    }
+
+    // Notes that are never released end with the track.
+    for ((channel, key), pending) in on {
+        for (start, velocity) in pending {
+            notes.push(TickNote { key, velocity, channel, start, end: tick });
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts ticks to seconds.
+struct Clock {
+    /// Seconds per tick for SMPTE time division.
+    smpte: Option<f64>,
+
+    ticks_per_quarter: f64,
+
+    /// Tempo changes as (tick, seconds at tick, microseconds per quarter note).
+    tempos: Vec<(u64, f64, u32)>,
+}
+
+impl Clock {
+    const DEFAULT_TEMPO: u32 = 500_000;
+
+    fn new(division: u16, mut tempos: Vec<(u64, u32)>) -> Self {
+        if division & 0x8000 != 0 {
+            let fps = -((division >> 8) as u8 as i8) as f64;
+            let ticks_per_frame = (division & 0xff) as f64;
+            // 29 means 29.97 drop frame.
+            let fps = if fps == 29.0 { 29.97 } else { fps };
+            return Self {
+                smpte: Some(1.0 / (fps * ticks_per_frame)),
+                ticks_per_quarter: 1.0,
+                tempos: Vec::new(),
+            };
+        }
+
+        tempos.sort_by_key(|&(tick, _)| tick);
+        let ticks_per_quarter = division.max(1) as f64;
+        let mut r = Vec::with_capacity(tempos.len() + 1);
+        r.push((0, 0.0, Self::DEFAULT_TEMPO));
+        for (tick, usecs) in tempos {
+            let &(prev_tick, prev_secs, prev_usecs) = r.last().unwrap();
+            let secs = prev_secs +
+                (tick - prev_tick) as f64 * prev_usecs as f64 / 1e6 / ticks_per_quarter;
+            if tick == prev_tick {
+                r.pop();
+            }
+            r.push((tick, secs, usecs));
+        }
+        Self {
+            smpte: None,
+            ticks_per_quarter,
+            tempos: r,
+        }
+    }
+
+    fn seconds(&self, tick: u64) -> f64 {
+        if let Some(secs_per_tick) = self.smpte {
+            return tick as f64 * secs_per_tick;
+        }
+        let i = self.tempos.iter().rposition(|&(t, _, _)| t <= tick).unwrap();
+        let (t, secs, usecs) = self.tempos[i];
+        secs + (tick - t) as f64 * usecs as f64 / 1e6 / self.ticks_per_quarter
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek(&self) -> Result<u8> {
+        self.data.first().cloned().ok_or(Error::UnexpectedEof)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let r = self.peek()?;
+        self.data = &self.data[1..];
+        Ok(r)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::UnexpectedEof);
+        }
+        let (r, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(r)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn var_len(&mut self) -> Result<u32> {
+        let mut r = 0u32;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            r = (r << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(r);
+            }
+        }
+        Err(Error::BadHeader)
+    }
+
+    fn chunk_id(&mut self) -> Result<[u8; 4]> {
+        let v = self.bytes(4)?;
+        Ok([v[0], v[1], v[2], v[3]])
+    }
+
+    fn chunk(&mut self) -> Result<&'a [u8]> {
+        let len = self.bytes(4)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+        self.bytes(len as usize)
+    }
+}
+
+#[cfg(test)]
+pub mod test_util {
+    /// Builds Standard MIDI Files for tests.
+    pub struct Writer {
+        division: u16,
+        tracks: Vec<Vec<u8>>,
+    }
+
+    impl Writer {
+        pub fn new(division: u16) -> Self {
+            Self {
+                division,
+                tracks: Vec::new(),
+            }
+        }
+
+        /// Adds a track of (delta ticks, event bytes).
+        pub fn track(&mut self, events: &[(u32, &[u8])]) -> &mut Self {
+            let mut t = Vec::new();
+            for &(delta, event) in events {
+                var_len(delta, &mut t);
+                t.extend_from_slice(event);
+            }
+            self.tracks.push(t);
+            self
+        }
+
+        pub fn build(&self) -> Vec<u8> {
+            let mut r = b"MThd\0\0\0\x06".to_vec();
+            let format: u16 = if self.tracks.len() > 1 { 1 } else { 0 };
+            r.extend_from_slice(&format.to_be_bytes());
+            r.extend_from_slice(&(self.tracks.len() as u16).to_be_bytes());
+            r.extend_from_slice(&self.division.to_be_bytes());
+            for t in &self.tracks {
+                r.extend_from_slice(b"MTrk");
+                r.extend_from_slice(&(t.len() as u32).to_be_bytes());
+                r.extend_from_slice(t);
+            }
+            r
+        }
+    }
+
+    pub fn var_len(mut v: u32, out: &mut Vec<u8>) {
+        let mut bytes = vec![(v & 0x7f) as u8];
+        v >>= 7;
+        while v > 0 {
+            bytes.push((v & 0x7f) as u8 | 0x80);
+            v >>= 7;
+        }
+        bytes.reverse();
+        out.extend_from_slice(&bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::test_util::*;
+
+    #[test]
+    fn var_len() {
+        for &v in &[0, 0x40, 0x7f, 0x80, 0x2000, 0x3fff, 0x4000, 0x0fff_ffff] {
+            let buf = &mut Vec::new();
+            test_util::var_len(v, buf);
+            assert_eq!(Reader::new(buf).var_len(), Ok(v));
+        }
+    }
+
+    #[test]
+    fn read_fn() {
+        let data = Writer::new(96)
+            // Tempo track: 120 bpm, then 60 bpm from the second quarter.
+            .track(&[
+                (0, &[0xff, 0x51, 3, 0x07, 0xa1, 0x20]),
+                (96, &[0xff, 0x51, 3, 0x0f, 0x42, 0x40]),
+                (0, &[0xff, 0x2f, 0]),
+            ])
+            .track(&[
+                (0, &[0x90, 60, 100]),
+                // Running status, note on with zero velocity is note off.
+                (96, &[64, 90]),
+                (0, &[60, 0]),
+                (0, &[0xc0, 5]),
+                (0, &[0xf0, 2, 1, 0xf7]),
+                (96, &[0x80, 64, 0]),
+                // Percussion, never released.
+                (0, &[0x99, 36, 120]),
+                (48, &[0xff, 0x2f, 0]),
+            ])
+            .build();
+
+        let notes = read(&data).unwrap();
+        assert_eq!(notes, &[
+            Note { key: 60, velocity: 100, channel: 0, start: 0.0, end: 0.5 },
+            Note { key: 64, velocity: 90, channel: 0, start: 0.5, end: 1.5 },
+            Note { key: 36, velocity: 120, channel: 9, start: 1.5, end: 2.0 },
+        ]);
+    }
+
+    #[test]
+    fn smpte() {
+        // 25 fps, 40 ticks per frame: 1 ms per tick.
+        let data = Writer::new(0xe728)
+            .track(&[(0, &[0x90, 69, 100]), (250, &[0x80, 69, 0])])
+            .build();
+        assert_eq!(read(&data).unwrap()[0].end, 0.25);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(read(b"RIFF\0\0\0\x06"), Err(Error::BadHeader));
+        assert_eq!(read(b"MThd\0\0\0\x06\0\x03\0\0\0\x60"), Err(Error::UnsupportedFormat(3)));
+        assert_eq!(read(b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x09"),
+            Err(Error::UnexpectedEof));
+        let data = Writer::new(96).track(&[(0, &[0x40, 1])]).build();
+        assert_eq!(read(&data), Err(Error::BadEvent(0x40)));
+    }
+}
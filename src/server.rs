@@ -0,0 +1,443 @@
+use serde_json::{json, Value};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{fingerprint, Algorithm};
+use crate::audio::wav;
+use crate::fingerprint::{acoustid_compare2, best_match, compress, decompress};
+use crate::fingerprint::codec::base64;
+use crate::index::{Store, DEFAULT_MASK};
+
+/// libsamplerate supports resampling ratios of `1 / 256..=256`.
+const MAX_RESAMPLE_RATIO: u64 = 256;
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub addr: String,
+
+    /// Directory of the index, created if it doesn't exist.
+    pub index_dir: PathBuf,
+
+    pub algorithm: Algorithm,
+
+    /// Maximum number of requests handled at the same time.
+    pub max_concurrency: usize,
+
+    /// Requests with larger bodies are rejected.
+    pub max_body_len: usize,
+
+    /// Maximum number of lookup results.
+    pub max_results: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:8080".into(),
+            index_dir: "index".into(),
+            algorithm: Algorithm::Test2,
+            max_concurrency: 4,
+            max_body_len: 64 << 20,
+            max_results: 10,
+        }
+    }
+}
+
+/// HTTP service exposing fingerprinting, comparison and the index with JSON responses.
+///
+/// * `POST /fingerprint` fingerprints a WAV file or raw 16-bit little endian PCM given with
+///   `sample_rate` and `channels` query parameters. Responds with `duration` and the compressed
+///   base64 `fingerprint`, plus the `raw` subfingerprints if the `raw` query parameter is set.
+/// * `POST /compare` with `a`, `b` and optional `max_offset` responds with the
+///   `acoustid_compare2()` `score`, and `offset` and `bit_score` of `best_match()`.
+/// * `POST /lookup` with `fingerprint` and optional `max_results` responds with `results`
+///   having `track_id`, `hits` and `offset`.
+/// * `PUT /tracks/{id}` with `fingerprint` adds or replaces a track in the index.
+/// * `DELETE /tracks/{id}` removes a track from the index.
+///
+/// Request bodies other than audio are JSON objects. Fingerprints in them are either arrays of
+/// subfingerprints or compressed base64 strings. Errors are responded with `error`.
+pub struct Server {
+    http: tiny_http::Server,
+    store: Store,
+    config: ServerConfig,
+    stopped: AtomicBool,
+}
+
+struct Error {
+    status: u16,
+    message: String,
+}
+
+impl Error {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(400, message)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::new(500, e.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+impl Server {
+    pub fn new(config: ServerConfig) -> io::Result<Self> {
+        assert!(config.max_concurrency > 0);
+        let store = Store::open(&config.index_dir, DEFAULT_MASK, 8)?;
+        let http = tiny_http::Server::http(&config.addr)
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            http,
+            store,
+            config,
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /// Handles requests on `max_concurrency` threads until `stop()` is called.
+    pub fn run(&self) {
+        thread::scope(|s| {
+            for _ in 0..self.config.max_concurrency {
+                s.spawn(|| {
+                    while !self.stopped.load(Ordering::SeqCst) {
+                        if let Ok(request) = self.http.recv() {
+                            self.respond(request);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        for _ in 0..self.config.max_concurrency {
+            self.http.unblock();
+        }
+    }
+
+    fn respond(&self, mut request: Request) {
+        // A panic fails the request but leaves the worker running.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.handle(&mut request)))
+            .unwrap_or_else(|_| Err(Error::new(500, "internal error")));
+        let (status, body) = match result {
+            Ok(v) => (200, v),
+            Err(e) => (e.status, json!({ "error": e.message })),
+        };
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        // The client may be gone already.
+        let _ = request.respond(response);
+    }
+
+    fn handle(&self, request: &mut Request) -> Result<Value> {
+        let url = request.url().to_string();
+        let (path, query) = match url.find('?') {
+            Some(i) => (&url[..i], &url[i + 1..]),
+            None => (&url[..], ""),
+        };
+        let segments: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let method = request.method().clone();
+        match (&method, &segments[..]) {
+            (Method::Post, ["fingerprint"]) => self.fingerprint(&read_body(request,
+                self.config.max_body_len)?, query),
+            (Method::Post, ["compare"]) => self.compare(&self.read_json(request)?),
+            (Method::Post, ["lookup"]) => self.lookup(&self.read_json(request)?),
+            (Method::Put, ["tracks", id]) => {
+                let fp = parse_fingerprint(&self.read_json(request)?["fingerprint"])?;
+                let track_id = parse_track_id(id)?;
+                self.store.insert(track_id, &fp)?;
+                Ok(json!({ "track_id": track_id }))
+            }
+            (Method::Delete, ["tracks", id]) => {
+                let removed = self.store.remove(parse_track_id(id)?)?;
+                Ok(json!({ "removed": removed }))
+            }
+            (_, ["fingerprint"]) | (_, ["compare"]) | (_, ["lookup"]) | (_, ["tracks", _]) =>
+                Err(Error::new(405, "method not allowed")),
+            _ => Err(Error::new(404, "not found")),
+        }
+    }
+
+    fn fingerprint(&self, body: &[u8], query: &str) -> Result<Value> {
+        let param = |name: &str| query.split('&')
+            .filter_map(|p| {
+                let mut kv = p.splitn(2, '=');
+                Some((kv.next()?, kv.next().unwrap_or("")))
+            })
+            .find(|&(k, _)| k == name)
+            .map(|(_, v)| v);
+        let parse = |name: &str| param(name)
+            .ok_or_else(|| Error::bad_request(format!("missing {}", name)))?
+            .parse::<u32>()
+            .ok()
+            .filter(|&v| v > 0)
+            .ok_or_else(|| Error::bad_request(format!("bad {}", name)));
+
+        let audio = if body.starts_with(b"RIFF") {
+            wav::read(body).map_err(|e| Error::bad_request(e.to_string()))?
+        } else {
+            let channel_count = parse("channels")?;
+            wav::Wav {
+                sample_rate: parse("sample_rate")?,
+                channel_count,
+                samples: body[..body.len() / 2 * 2].chunks_exact(2)
+                    .map(|v| i16::from_le_bytes([v[0], v[1]]))
+                    .collect(),
+            }
+        };
+        let rate = audio.sample_rate as u64;
+        let target_rate = self.config.algorithm.fp_config().sample_rate() as u64;
+        if rate * MAX_RESAMPLE_RATIO < target_rate || rate > target_rate * MAX_RESAMPLE_RATIO {
+            return Err(Error::bad_request(format!("unsupported sample_rate {}", rate)));
+        }
+        let fp = fingerprint(self.config.algorithm, audio.sample_rate, audio.channel_count,
+            &audio.samples);
+        let duration = audio.samples.len() as f64 /
+            (audio.sample_rate as f64 * audio.channel_count as f64);
+        let mut r = json!({
+            "duration": duration,
+            "fingerprint": base64::encode(&compress(&fp, self.config.algorithm.id())),
+        });
+        if param("raw").is_some() {
+            r["raw"] = json!(fp);
+        }
+        Ok(r)
+    }
+
+    fn compare(&self, body: &Value) -> Result<Value> {
+        let a = &parse_fingerprint(&body["a"])?;
+        let b = &parse_fingerprint(&body["b"])?;
+        let max_offset = parse_count(&body["max_offset"])?.unwrap_or(0);
+        let m = best_match(a, b, if max_offset == 0 { a.len().max(b.len()) } else { max_offset });
+        Ok(json!({
+            "score": acoustid_compare2(a, b, max_offset),
+            "offset": m.map(|m| m.offset),
+            "bit_score": m.map(|m| m.score),
+        }))
+    }
+
+    fn lookup(&self, body: &Value) -> Result<Value> {
+        let fp = &parse_fingerprint(&body["fingerprint"])?;
+        let max_results = parse_count(&body["max_results"])?
+            .unwrap_or(self.config.max_results)
+            .min(self.config.max_results);
+        let results: Vec<_> = self.store.search(fp, max_results).into_iter()
+            .map(|c| json!({
+                "track_id": c.track_id,
+                "hits": c.hits,
+                "offset": c.offset,
+            }))
+            .collect();
+        Ok(json!({ "results": results }))
+    }
+
+    fn read_json(&self, request: &mut Request) -> Result<Value> {
+        let body = read_body(request, self.config.max_body_len)?;
+        let v: Value = serde_json::from_slice(&body)
+            .map_err(|e| Error::bad_request(format!("bad JSON: {}", e)))?;
+        if v.is_object() {
+            Ok(v)
+        } else {
+            Err(Error::bad_request("expected a JSON object"))
+        }
+    }
+}
+
+fn read_body(request: &mut Request, max_len: usize) -> Result<Vec<u8>> {
+    let too_large = || Error::new(413, format!("body is larger than {} bytes", max_len));
+    if request.body_length().map(|v| v > max_len).unwrap_or(false) {
+        return Err(too_large());
+    }
+    let mut r = Vec::new();
+    request.as_reader().take(max_len as u64 + 1).read_to_end(&mut r)?;
+    if r.len() > max_len {
+        return Err(too_large());
+    }
+    Ok(r)
+}
+
+fn parse_fingerprint(v: &Value) -> Result<Vec<u32>> {
+    let bad = || Error::bad_request("bad fingerprint");
+    match v {
+        Value::String(s) => base64::decode(s.as_bytes())
+            .and_then(|v| decompress(&v))
+            .map(|(fp, _)| fp)
+            .ok_or_else(bad),
+        Value::Array(items) => items.iter()
+            .map(|v| v.as_u64().filter(|&v| v <= u32::MAX as u64).map(|v| v as u32))
+            .collect::<Option<_>>()
+            .ok_or_else(bad),
+        Value::Null => Err(Error::bad_request("missing fingerprint")),
+        _ => Err(bad()),
+    }
+}
+
+fn parse_count(v: &Value) -> Result<Option<usize>> {
+    match v {
+        Value::Null => Ok(None),
+        _ => v.as_u64()
+            .map(|v| Some(v as usize))
+            .ok_or_else(|| Error::bad_request(format!("expected a count, got {}", v))),
+    }
+}
+
+fn parse_track_id(s: &str) -> Result<u32> {
+    s.parse().map_err(|_| Error::bad_request(format!("bad track id {}", s)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use crate::test_util::*;
+
+    struct TestServer {
+        server: Arc<Server>,
+        thread: Option<thread::JoinHandle<()>>,
+        _dir: tempfile::TempDir,
+    }
+
+    impl TestServer {
+        fn new(max_body_len: usize) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let server = Arc::new(Server::new(ServerConfig {
+                addr: "127.0.0.1:0".into(),
+                index_dir: dir.path().join("index"),
+                max_body_len,
+                ..Default::default()
+            }).unwrap());
+            let thread = {
+                let server = server.clone();
+                thread::spawn(move || server.run())
+            };
+            Self {
+                server,
+                thread: Some(thread),
+                _dir: dir,
+            }
+        }
+
+        /// Returns the status and JSON body of the response.
+        fn request(&self, method: &str, path: &str, body: &[u8]) -> (u16, Value) {
+            let mut s = TcpStream::connect(self.server.addr().unwrap()).unwrap();
+            write!(s, "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                Content-Length: {}\r\n\r\n", method, path, body.len()).unwrap();
+            // The server may respond before reading a body that's too large.
+            let _ = s.write_all(body);
+            let mut response = Vec::new();
+            s.read_to_end(&mut response).unwrap();
+            let response = String::from_utf8(response).unwrap();
+            let status = response[9..12].parse().unwrap();
+            let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+            (status, serde_json::from_str(body).unwrap())
+        }
+
+        fn json(&self, method: &str, path: &str, body: Value) -> (u16, Value) {
+            self.request(method, path, body.to_string().as_bytes())
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.server.stop();
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test() {
+        let s = &TestServer::new(1 << 20);
+        let audio = &melody(1, 11025, 20);
+        let expected = fingerprint(Algorithm::Test2, 11025, 1, audio);
+        let pcm: Vec<u8> = audio.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+
+        let (status, r) = s.request("POST", "/fingerprint?sample_rate=11025&channels=1&raw=1",
+            &pcm);
+        assert_eq!(status, 200, "{}", r);
+        assert_eq!(r["duration"], json!(20.0));
+        assert_eq!(r["raw"], json!(expected));
+        let compressed = r["fingerprint"].clone();
+
+        let (status, r) = s.request("POST", "/fingerprint", &wav::write(11025, 1, audio));
+        assert_eq!(status, 200, "{}", r);
+        assert_eq!(r["fingerprint"], compressed);
+        assert_eq!(r["raw"], Value::Null);
+
+        let (status, r) = s.request("POST", "/fingerprint?sample_rate=11025", &pcm);
+        assert_eq!((status, r["error"].as_str()), (400, Some("missing channels")));
+
+        let (status, r) = s.json("POST", "/compare", json!({
+            "a": compressed,
+            "b": &expected[10..],
+        }));
+        assert_eq!(status, 200, "{}", r);
+        assert_eq!(r["offset"], json!(-10));
+        assert_eq!(r["bit_score"], json!(1.0));
+        assert_eq!(r["score"], json!(acoustid_compare2(&expected, &expected[10..], 0)));
+
+        let (status, _) = s.json("PUT", "/tracks/7", json!({ "fingerprint": compressed }));
+        assert_eq!(status, 200);
+        let (status, r) = s.json("POST", "/lookup", json!({ "fingerprint": &expected[50..100] }));
+        assert_eq!(status, 200, "{}", r);
+        assert_eq!(r["results"], json!([{ "track_id": 7, "hits": 50, "offset": 50 }]));
+
+        let (status, r) = s.request("DELETE", "/tracks/7", &[]);
+        assert_eq!((status, &r["removed"]), (200, &json!(true)));
+        let (_, r) = s.json("POST", "/lookup", json!({ "fingerprint": &expected[50..100] }));
+        assert_eq!(r["results"], json!([]));
+    }
+
+    #[test]
+    fn errors() {
+        let s = &TestServer::new(100);
+        assert_eq!(s.request("GET", "/nothing", &[]).0, 404);
+        assert_eq!(s.request("GET", "/lookup", &[]).0, 405);
+        assert_eq!(s.request("POST", "/lookup", b"{").0, 400);
+        assert_eq!(s.json("POST", "/lookup", json!([1])).0, 400);
+        assert_eq!(s.json("POST", "/lookup", json!({ "fingerprint": "!" })).0, 400);
+        assert_eq!(s.json("POST", "/lookup", json!({ "fingerprint": [-1] })).0, 400);
+        assert_eq!(s.json("PUT", "/tracks/x", json!({ "fingerprint": [1] })).0, 400);
+        let (status, r) = s.request("POST", "/fingerprint?sample_rate=8000&channels=1",
+            &[0; 101]);
+        assert_eq!(status, 413, "{}", r);
+    }
+
+    #[test]
+    fn sample_rate() {
+        let s = &TestServer::new(1 << 20);
+        let (status, r) = s.request("POST", "/fingerprint?sample_rate=1&channels=1", &[0; 100]);
+        assert_eq!((status, r["error"].as_str()), (400, Some("unsupported sample_rate 1")));
+        let (status, r) = s.request("POST", "/fingerprint", &wav::write(11025 * 257, 1, &[0; 50]));
+        assert_eq!(status, 400, "{}", r);
+
+        // The server keeps working.
+        let pcm: Vec<u8> = melody(1, 8000, 5).iter().flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        let (status, r) = s.request("POST", "/fingerprint?sample_rate=8000&channels=1", &pcm);
+        assert_eq!(status, 200, "{}", r);
+        assert_eq!(r["duration"], json!(5.0));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{Algorithm, Fingerprinter};
+use crate::fingerprint::compare::{best_match, Match};
+use crate::pipeline::Step;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateMatch {
+    /// Playback rate of the query relative to the reference, e.g. `1.03` if the query plays
+    /// 3% faster.
+    pub rate: f64,
+
+    /// Item of the reference aligned with the first item of the query.
+    pub offset: isize,
+
+    pub score: f64,
+}
+
+/// Playback rates from `min` to `max` inclusive with the given `step`.
+pub fn rates(min: f64, max: f64, step: f64) -> Vec<f64> {
+    assert!(min > 0.0 && min <= max);
+    assert!(step > 0.0);
+    let count = ((max - min) / step + 1e-9).floor() as usize + 1;
+    (0..count).map(|i| min + i as f64 * step).collect()
+}
+
+/// Fingerprints the interleaved PCM `input` assuming each of the playback `rates` and matches
+/// every fingerprint against `reference`. Speeding up playback scales tempo and pitch alike,
+/// so the query is undone by resampling from `sample_rate / rate` instead of `sample_rate`.
+/// Returns the best matching rate.
+pub fn match_speed(
+    algorithm: Algorithm,
+    sample_rate: u32,
+    channel_count: u32,
+    input: &[i16],
+    reference: &[u32],
+    rates: &[f64],
+    max_offset: usize) -> Option<RateMatch>
+{
+    let mut best: Option<RateMatch> = None;
+    let query = &mut Vec::new();
+    for &rate in rates {
+        assert!(rate > 0.0);
+        let adjusted_rate = (sample_rate as f64 / rate).round() as u32;
+
+        query.clear();
+        let fp = &mut Fingerprinter::new(algorithm, adjusted_rate, channel_count);
+        fp.process(input, |v| query.extend_from_slice(v));
+        fp.finish(|v| query.extend_from_slice(v));
+
+        if let Some(Match { offset, score }) = best_match(query, reference, max_offset) {
+            if best.map(|b| score > b.score).unwrap_or(true) {
+                best = Some(RateMatch {
+                    rate,
+                    offset,
+                    score,
+                });
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::Resample;
+    use crate::fingerprint;
+    use crate::pipeline::test_util::*;
+    use crate::test_util::read_audio_raw;
+
+    #[test]
+    fn rates_fn() {
+        let r = rates(0.96, 1.04, 0.02);
+        assert_eq!(r.len(), 5);
+        assert!((r[4] - 1.04).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test() {
+        let audio = read_audio_raw(include_bytes!("../tests/data/test_mono_44100.raw")).repeat(8);
+        let reference = fingerprint(Algorithm::Test2, 44100, 1, &audio);
+
+        // Play 4% faster: squeeze the audio into fewer samples at the same sample rate.
+        let faster = process_all_flat(&mut Resample::new(44100, 42404), &audio);
+
+        let m = match_speed(Algorithm::Test2, 44100, 1, &faster, &reference,
+            &rates(0.96, 1.04, 0.02), reference.len()).unwrap();
+        assert!((m.rate - 1.04).abs() < 1e-9, "{}", m.rate);
+        assert!(m.score > 0.9, "{}", m.score);
+
+        let plain = best_match(&fingerprint(Algorithm::Test2, 44100, 1, &faster), &reference,
+            reference.len()).unwrap();
+        assert!(plain.score < m.score);
+    }
+}
@@ -0,0 +1,860 @@
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::{Algorithm, Fingerprinter};
+use crate::audio::wav;
+use crate::pipeline::Step;
+use crate::util::connect_http;
+
+/// Bytes buffered at most while waiting for the end of a WAV header.
+const MAX_WAV_HEADER_LEN: usize = 64 << 10;
+
+const MAX_HEADER_LINE_COUNT: usize = 100;
+const MAX_HEADER_LINE_LEN: usize = 8 << 10;
+
+/// Redirects followed at most per connection.
+const MAX_REDIRECTS: usize = 5;
+
+/// Bytes buffered before decoding MP3, enough for minimp3 to sync on 10 frames of the largest
+/// size.
+#[cfg(feature = "mp3")]
+const MIN_MP3_BUF_LEN: usize = 16 << 10;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Http(u16),
+
+    /// No decoder for the content type of the stream.
+    UnsupportedContentType(String),
+
+    Decode(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Http(status) => write!(f, "unexpected HTTP status {}", status),
+            Error::UnsupportedContentType(s) => write!(f, "unsupported content type {:?}", s),
+            Error::Decode(s) => write!(f, "can't decode stream: {}", s),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Decodes the body of a stream into PCM audio.
+pub trait Decoder {
+    /// Decodes the next bytes of the stream, appending whole interleaved frames to `output`.
+    fn decode(&mut self, input: &[u8], output: &mut Vec<i16>) -> Result<()>;
+
+    /// Sample rate and channel count, once known.
+    fn format(&self) -> Option<(u32, u32)>;
+}
+
+/// Decodes raw 16-bit PCM.
+pub struct PcmDecoder {
+    sample_rate: u32,
+    channel_count: u32,
+    big_endian: bool,
+
+    /// Bytes of the incomplete frame.
+    buf: Vec<u8>,
+}
+
+impl PcmDecoder {
+    pub fn new(sample_rate: u32, channel_count: u32, big_endian: bool) -> Self {
+        assert!(sample_rate > 0);
+        assert!(channel_count > 0);
+        Self {
+            sample_rate,
+            channel_count,
+            big_endian,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Creates a decoder for big endian `audio/L16` with `rate` and `channels` parameters.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mut parts = content_type.split(';').map(|s| s.trim());
+        if !parts.next()?.eq_ignore_ascii_case("audio/L16") {
+            return None;
+        }
+        let mut sample_rate = None;
+        let mut channel_count = 1;
+        for param in parts {
+            let mut kv = param.splitn(2, '=').map(|s| s.trim());
+            let name = kv.next()?.to_ascii_lowercase();
+            let value = kv.next().and_then(|v| v.parse().ok()).filter(|&v| v > 0);
+            match name.as_str() {
+                "rate" => sample_rate = Some(value?),
+                "channels" => channel_count = value?,
+                _ => {}
+            }
+        }
+        Some(Self::new(sample_rate?, channel_count, true))
+    }
+}
+
+impl Decoder for PcmDecoder {
+    fn decode(&mut self, input: &[u8], output: &mut Vec<i16>) -> Result<()> {
+        self.buf.extend_from_slice(input);
+        let frame_len = 2 * self.channel_count as usize;
+        let len = self.buf.len() / frame_len * frame_len;
+        let big_endian = self.big_endian;
+        output.extend(self.buf[..len].chunks_exact(2).map(|v| if big_endian {
+            i16::from_be_bytes([v[0], v[1]])
+        } else {
+            i16::from_le_bytes([v[0], v[1]])
+        }));
+        self.buf.drain(..len);
+        Ok(())
+    }
+
+    fn format(&self) -> Option<(u32, u32)> {
+        Some((self.sample_rate, self.channel_count))
+    }
+}
+
+/// Decodes a WAV file with 16-bit PCM samples. The length of the data in the header is ignored,
+/// as it's usually bogus in live streams.
+#[derive(Default)]
+pub struct WavDecoder {
+    header: Vec<u8>,
+    pcm: Option<PcmDecoder>,
+}
+
+impl WavDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Decoder for WavDecoder {
+    fn decode(&mut self, input: &[u8], output: &mut Vec<i16>) -> Result<()> {
+        if let Some(pcm) = &mut self.pcm {
+            return pcm.decode(input, output);
+        }
+        self.header.extend_from_slice(input);
+        if self.header.len() < 12 {
+            return Ok(());
+        }
+        match wav::read_header(&self.header) {
+            Ok(h) => {
+                let mut pcm = PcmDecoder::new(h.sample_rate, h.channel_count, false);
+                pcm.decode(&self.header[h.data_offset..], output)?;
+                self.pcm = Some(pcm);
+                self.header = Vec::new();
+                Ok(())
+            }
+            Err(wav::Error::UnexpectedEof) | Err(wav::Error::MissingChunk("data"))
+                if self.header.len() < MAX_WAV_HEADER_LEN => Ok(()),
+            Err(e) => Err(Error::Decode(e.to_string())),
+        }
+    }
+
+    fn format(&self) -> Option<(u32, u32)> {
+        self.pcm.as_ref().and_then(|v| v.format())
+    }
+}
+
+/// Decodes MPEG audio with minimp3. Layer I and II streams are decoded too.
+#[cfg(feature = "mp3")]
+pub struct Mp3Decoder {
+    dec: Box<minimp3_sys::mp3dec_t>,
+    buf: Vec<u8>,
+    format: Option<(u32, u32)>,
+}
+
+#[cfg(feature = "mp3")]
+impl Mp3Decoder {
+    pub fn new() -> Self {
+        // `mp3dec_t` is plain data, `mp3dec_init()` only resets the header.
+        let mut dec: Box<minimp3_sys::mp3dec_t> = Box::new(unsafe { std::mem::zeroed() });
+        unsafe { minimp3_sys::mp3dec_init(&mut *dec) };
+        Self {
+            dec,
+            buf: Vec::new(),
+            format: None,
+        }
+    }
+}
+
+#[cfg(feature = "mp3")]
+impl Default for Mp3Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "mp3")]
+impl Decoder for Mp3Decoder {
+    fn decode(&mut self, input: &[u8], output: &mut Vec<i16>) -> Result<()> {
+        self.buf.extend_from_slice(input);
+        let mut pos = 0;
+        // minimp3 treats the end of the buffer as the end of the stream, so a few frames are
+        // kept buffered for it to find the next frame.
+        while self.buf.len() - pos >= MIN_MP3_BUF_LEN {
+            let pcm = &mut [0; minimp3_sys::MINIMP3_MAX_SAMPLES_PER_FRAME as usize];
+            let info = &mut minimp3_sys::mp3dec_frame_info_t {
+                frame_bytes: 0,
+                frame_offset: 0,
+                channels: 0,
+                hz: 0,
+                layer: 0,
+                bitrate_kbps: 0,
+            };
+            let data = &self.buf[pos..];
+            let frame_len = unsafe {
+                minimp3_sys::mp3dec_decode_frame(&mut *self.dec, data.as_ptr(),
+                    data.len() as i32, pcm.as_mut_ptr(), info)
+            };
+            if info.frame_bytes <= 0 {
+                break;
+            }
+            pos += info.frame_bytes as usize;
+            if frame_len <= 0 {
+                // Skipped ID3 tags or garbage.
+                continue;
+            }
+            let format = (info.hz as u32, info.channels as u32);
+            if *self.format.get_or_insert(format) != format {
+                return Err(Error::Decode(format!("format changed from {:?} to {:?}",
+                    self.format.unwrap(), format)));
+            }
+            output.extend_from_slice(&pcm[..frame_len as usize * info.channels as usize]);
+        }
+        self.buf.drain(..pos);
+        Ok(())
+    }
+
+    fn format(&self) -> Option<(u32, u32)> {
+        self.format
+    }
+}
+
+/// Returns the decoder for WAV and `audio/L16` content types, and for MP3 with the `mp3`
+/// feature. Streams in other formats like AAC or Ogg need a decoder passed to
+/// `StreamReader::with_decoder()`.
+pub fn default_decoder(content_type: &str) -> Option<Box<dyn Decoder>> {
+    let mime = content_type.split(';').next().unwrap().trim().to_ascii_lowercase();
+    match mime.as_str() {
+        "audio/wav" | "audio/wave" | "audio/x-wav" | "audio/vnd.wave" =>
+            Some(Box::new(WavDecoder::new())),
+        "audio/l16" => PcmDecoder::from_content_type(content_type)
+            .map(|v| Box::new(v) as Box<dyn Decoder>),
+        #[cfg(feature = "mp3")]
+        "audio/mpeg" | "audio/mp3" => Some(Box::new(Mp3Decoder::new())),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamConfig {
+    /// Plain `http://` URL of the stream.
+    pub url: String,
+
+    /// Timeout for connecting and for every read, a stalled stream is reconnected.
+    pub timeout: Duration,
+
+    pub reconnect_delay: Duration,
+
+    /// Number of connections in a row that fail before producing audio, after which reading
+    /// gives up.
+    pub max_reconnects: u32,
+}
+
+impl StreamConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            timeout: Duration::from_secs(10),
+            reconnect_delay: Duration::from_secs(1),
+            max_reconnects: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StreamEvent {
+    /// Connected, the audio that follows has this format.
+    Started {
+        sample_rate: u32,
+        channel_count: u32,
+    },
+
+    /// Stream title from the ICY metadata changed. The title is reported again after
+    /// reconnecting.
+    Title(String),
+
+    /// Decoded interleaved frames.
+    Audio(Vec<i16>),
+
+    /// Connection was lost after it started, the audio that follows isn't continuous.
+    Disconnected,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FingerprintEvent<'a> {
+    /// New fingerprint started after connecting.
+    Started,
+
+    /// Stream title changed before the audio of the subfingerprints that follow.
+    Title(&'a str),
+
+    /// Next subfingerprints of the current fingerprint.
+    Items(&'a [u32]),
+}
+
+type NewDecoder = Box<dyn FnMut(&str) -> Option<Box<dyn Decoder>>>;
+
+/// Reads live audio from an HTTP or Icecast/SHOUTcast stream, reconnecting when the connection
+/// drops. ICY metadata is requested and stripped from the audio.
+pub struct StreamReader {
+    config: StreamConfig,
+    new_decoder: NewDecoder,
+    connection: Option<Connection>,
+
+    /// Connections in a row that failed before producing audio.
+    failures: u32,
+
+    events: VecDeque<StreamEvent>,
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+    decoder: Box<dyn Decoder>,
+
+    /// Audio bytes between ICY metadata blocks.
+    metaint: Option<usize>,
+
+    /// Audio bytes left until the next metadata block.
+    until_meta: usize,
+
+    started: bool,
+    title: Option<String>,
+}
+
+impl StreamReader {
+    /// Creates a reader decoding with `default_decoder()`.
+    pub fn new(config: StreamConfig) -> Self {
+        Self::with_decoder(config, default_decoder)
+    }
+
+    /// Creates a reader decoding with decoders returned by `new_decoder` for the content type
+    /// of the stream.
+    pub fn with_decoder<F>(config: StreamConfig, new_decoder: F) -> Self
+        where F: 'static + FnMut(&str) -> Option<Box<dyn Decoder>>
+    {
+        Self {
+            config,
+            new_decoder: Box::new(new_decoder),
+            connection: None,
+            failures: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Returns the next event, connecting as needed. Fails when the content type is
+    /// unsupported or after `max_reconnects` failed connections in a row.
+    pub fn read(&mut self) -> Result<StreamEvent> {
+        loop {
+            if let Some(e) = self.events.pop_front() {
+                return Ok(e);
+            }
+            let r = match self.connection {
+                Some(_) => self.read_connection(),
+                None => self.connect(),
+            };
+            if let Err(e) = r {
+                let started = self.connection.take().map(|c| c.started).unwrap_or(false);
+                let fatal = matches!(e, Error::UnsupportedContentType(_));
+                if fatal || self.failures >= self.config.max_reconnects {
+                    return Err(e);
+                }
+                self.failures += 1;
+                if started {
+                    self.events.push_back(StreamEvent::Disconnected);
+                }
+                thread::sleep(self.config.reconnect_delay);
+            }
+        }
+    }
+
+    /// Fingerprints the stream, starting a new fingerprint on every connection. Returns when
+    /// `output` returns `false` or reading fails.
+    pub fn fingerprint<F>(&mut self, algorithm: Algorithm, mut output: F) -> Result<()>
+        where F: FnMut(FingerprintEvent) -> bool
+    {
+        let mut fingerprinter: Option<Fingerprinter> = None;
+        let items = &mut Vec::new();
+        loop {
+            items.clear();
+            let event = self.read();
+            let go_on = match &event {
+                Ok(StreamEvent::Started { sample_rate, channel_count }) => {
+                    fingerprinter = Some(Fingerprinter::new(algorithm, *sample_rate,
+                        *channel_count));
+                    output(FingerprintEvent::Started)
+                }
+                Ok(StreamEvent::Title(title)) => output(FingerprintEvent::Title(title)),
+                Ok(StreamEvent::Audio(samples)) => {
+                    if let Some(fp) = &mut fingerprinter {
+                        fp.process(samples, |v| items.extend_from_slice(v));
+                    }
+                    items.is_empty() || output(FingerprintEvent::Items(items))
+                }
+                Ok(StreamEvent::Disconnected) | Err(_) => {
+                    if let Some(mut fp) = fingerprinter.take() {
+                        fp.finish(|v| items.extend_from_slice(v));
+                    }
+                    items.is_empty() || output(FingerprintEvent::Items(items))
+                }
+            };
+            event?;
+            if !go_on {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Connects to the stream, following redirects to other `http://` URLs.
+    fn connect(&mut self) -> Result<()> {
+        let mut url = self.config.url.clone();
+        let mut redirects = 0;
+        loop {
+            let (mut stream, host, path) = connect_http(&url, self.config.timeout)?;
+            // HTTP/1.0 keeps the body from being chunked.
+            write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nIcy-MetaData: 1\r\n\
+                User-Agent: chromaprinter\r\n\r\n", path, host)?;
+            let mut stream = BufReader::new(stream);
+
+            let mut line = String::new();
+            read_header_line(&mut stream, &mut line)?;
+            // SHOUTcast responds with "ICY 200 OK".
+            let status = line.split_whitespace().nth(1)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                    format!("bad status line {:?}", line.trim_end())))?;
+            let mut content_type = String::new();
+            let mut metaint = None;
+            let mut location = None;
+            for _ in 0..MAX_HEADER_LINE_COUNT {
+                if read_header_line(&mut stream, &mut line)? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let mut kv = line.splitn(2, ':');
+                let name = kv.next().unwrap().trim().to_ascii_lowercase();
+                let value = kv.next().unwrap_or("").trim();
+                match name.as_str() {
+                    "content-type" => content_type = value.to_string(),
+                    "icy-metaint" => metaint = value.parse().ok().filter(|&v| v > 0),
+                    "location" => location = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            match (status, location) {
+                (200, _) => {}
+                (301 | 302 | 303 | 307 | 308, Some(location)) if redirects < MAX_REDIRECTS => {
+                    // Only absolute paths are resolved against the current URL.
+                    url = if location.starts_with('/') {
+                        format!("http://{}{}", host, location)
+                    } else {
+                        location
+                    };
+                    redirects += 1;
+                    continue;
+                }
+                _ => return Err(Error::Http(status)),
+            }
+
+            let decoder = (self.new_decoder)(&content_type)
+                .ok_or(Error::UnsupportedContentType(content_type))?;
+            self.connection = Some(Connection {
+                stream,
+                decoder,
+                metaint,
+                until_meta: metaint.unwrap_or(0),
+                started: false,
+                title: None,
+            });
+            return Ok(());
+        }
+    }
+
+    fn read_connection(&mut self) -> Result<()> {
+        let c = self.connection.as_mut().unwrap();
+        let buf = &mut [0; 4096];
+        let len = match c.metaint {
+            Some(_) => c.until_meta.min(buf.len()),
+            None => buf.len(),
+        };
+        let n = c.stream.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended").into());
+        }
+        let mut samples = Vec::new();
+        c.decoder.decode(&buf[..n], &mut samples)?;
+        if !c.started {
+            if let Some((sample_rate, channel_count)) = c.decoder.format() {
+                c.started = true;
+                self.failures = 0;
+                self.events.push_back(StreamEvent::Started { sample_rate, channel_count });
+            }
+        }
+        if !samples.is_empty() {
+            self.events.push_back(StreamEvent::Audio(samples));
+        }
+
+        if let Some(metaint) = c.metaint {
+            c.until_meta -= n;
+            if c.until_meta == 0 {
+                c.until_meta = metaint;
+                let len = &mut [0];
+                c.stream.read_exact(len)?;
+                let meta = &mut vec![0; len[0] as usize * 16];
+                c.stream.read_exact(meta)?;
+                if let Some(title) = parse_stream_title(meta) {
+                    if c.title.as_ref() != Some(&title) {
+                        self.events.push_back(StreamEvent::Title(title.clone()));
+                        c.title = Some(title);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a line of the response header into `line`, failing if it's longer than
+/// `MAX_HEADER_LINE_LEN`. Returns the length of the line, `0` at the end of the stream.
+fn read_header_line(stream: &mut BufReader<TcpStream>, line: &mut String) -> io::Result<usize> {
+    line.clear();
+    let len = stream.take(MAX_HEADER_LINE_LEN as u64).read_line(line)?;
+    if len == MAX_HEADER_LINE_LEN && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "header line is too long"));
+    }
+    Ok(len)
+}
+
+/// Parses `StreamTitle='...';` out of an ICY metadata block.
+fn parse_stream_title(meta: &[u8]) -> Option<String> {
+    let end = meta.iter().position(|&b| b == 0).unwrap_or(meta.len());
+    let meta = &meta[..end];
+    // Metadata is UTF-8 in newer servers and Latin-1 in older ones.
+    let meta = String::from_utf8(meta.to_vec())
+        .unwrap_or_else(|_| meta.iter().map(|&b| b as char).collect());
+    const START: &str = "StreamTitle='";
+    let start = meta.find(START)? + START.len();
+    let rest = &meta[start..];
+    let len = rest.find("';").unwrap_or_else(|| rest.trim_end_matches('\'').len());
+    Some(rest[..len].to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use crate::fingerprint;
+    use crate::test_util::*;
+
+    /// Streaming server serving the responses in order, one per connection.
+    struct MockServer {
+        url: String,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl MockServer {
+        fn new(responses: Vec<Vec<u8>>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/stream", listener.local_addr().unwrap());
+            let thread = thread::spawn(move || {
+                for response in responses {
+                    let (mut s, _) = listener.accept().unwrap();
+                    let mut request = BufReader::new(s.try_clone().unwrap());
+                    let mut line = String::new();
+                    let mut icy = false;
+                    while request.read_line(&mut line).unwrap() > 2 {
+                        icy |= line.eq_ignore_ascii_case("Icy-MetaData: 1\r\n");
+                        line.clear();
+                    }
+                    assert!(icy);
+                    // The client may have disconnected already.
+                    let _ = s.write_all(&response);
+                }
+            });
+            Self {
+                url,
+                thread: Some(thread),
+            }
+        }
+
+        fn reader(&self) -> StreamReader {
+            StreamReader::new(StreamConfig {
+                reconnect_delay: Duration::from_millis(1),
+                max_reconnects: 2,
+                ..StreamConfig::new(&self.url[..])
+            })
+        }
+    }
+
+    impl Drop for MockServer {
+        fn drop(&mut self) {
+            // A failed test may leave the server waiting for connections that never come.
+            if !thread::panicking() {
+                self.thread.take().unwrap().join().unwrap();
+            }
+        }
+    }
+
+    /// ICY response with a metadata block after every `metaint` bytes of `body`.
+    fn icy(content_type: &str, metaint: usize, body: &[u8], titles: &[&str]) -> Vec<u8> {
+        let mut r = format!("ICY 200 OK\r\nicy-name: Test\r\nContent-Type: {}\r\n\
+            icy-metaint: {}\r\n\r\n", content_type, metaint).into_bytes();
+        for (i, chunk) in body.chunks(metaint).enumerate() {
+            r.extend_from_slice(chunk);
+            if chunk.len() == metaint {
+                let mut meta = match titles.get(i) {
+                    Some(t) => format!("StreamTitle='{}';StreamUrl='';", t).into_bytes(),
+                    None => Vec::new(),
+                };
+                meta.resize(meta.len() + (16 - meta.len() % 16) % 16, 0);
+                r.push((meta.len() / 16) as u8);
+                r.extend_from_slice(&meta);
+            }
+        }
+        r
+    }
+
+    fn read_all(reader: &mut StreamReader) -> (Vec<StreamEvent>, Error) {
+        let mut events = Vec::new();
+        loop {
+            match reader.read() {
+                Ok(StreamEvent::Audio(samples)) => match events.last_mut() {
+                    Some(StreamEvent::Audio(v)) => v.extend_from_slice(&samples),
+                    _ => events.push(StreamEvent::Audio(samples)),
+                },
+                Ok(e) => events.push(e),
+                Err(e) => return (events, e),
+            }
+        }
+    }
+
+    #[test]
+    fn icy_metadata() {
+        let audio = &melody(1, 11025, 2);
+        let body = &wav::write(11025, 1, audio);
+        let s = MockServer::new(vec![
+            icy("audio/wav", 1000, body, &["A", "A", "It's B", "", "", "C"]),
+        ]);
+        let (events, e) = read_all(&mut s.reader());
+
+        let titles: Vec<_> = events.iter()
+            .filter_map(|e| match e {
+                StreamEvent::Title(t) => Some(&t[..]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(titles, &["A", "It's B", "", "C"]);
+        let samples: Vec<_> = events.iter()
+            .filter_map(|e| match e {
+                StreamEvent::Audio(v) => Some(v),
+                _ => None,
+            })
+            .flatten()
+            .cloned()
+            .collect();
+        assert_eq!(&samples, audio);
+        assert_eq!(events[0], StreamEvent::Started { sample_rate: 11025, channel_count: 1 });
+        assert_eq!(events.last(), Some(&StreamEvent::Disconnected));
+        assert!(matches!(e, Error::Io(_)), "{:?}", e);
+    }
+
+    #[test]
+    fn reconnect() {
+        let audio = &melody(2, 11025, 20);
+        let to_be = |v: &[i16]| v.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect::<Vec<_>>();
+        let content_type = "audio/L16; rate=11025; channels=2";
+        let (a, b) = audio.split_at(audio.len() / 2);
+        let s = MockServer::new(vec![
+            // Dropped in the middle of a sample.
+            icy(content_type, 4096, &to_be(a)[..a.len() * 2 - 1], &["A"]),
+            b"HTTP/1.0 503 Service Unavailable\r\n\r\n".to_vec(),
+            format!("HTTP/1.0 200 OK\r\nContent-Type: {}\r\n\r\n", content_type).into_bytes()
+                .into_iter().chain(to_be(b)).collect(),
+        ]);
+
+        let mut fps = Vec::new();
+        let mut titles = Vec::new();
+        let e = s.reader().fingerprint(Algorithm::Test2, |e| {
+            match e {
+                FingerprintEvent::Started => fps.push(Vec::new()),
+                FingerprintEvent::Title(t) => titles.push((fps.len(), t.to_string())),
+                FingerprintEvent::Items(v) => fps.last_mut().unwrap().extend_from_slice(v),
+            }
+            true
+        }).unwrap_err();
+        assert!(matches!(e, Error::Io(_)), "{:?}", e);
+        assert_eq!(titles, &[(1, "A".to_string())]);
+        assert_eq!(fps, vec![
+            fingerprint(Algorithm::Test2, 11025, 2, &a[..a.len() - 2]),
+            fingerprint(Algorithm::Test2, 11025, 2, b),
+        ]);
+    }
+
+    #[test]
+    fn stop() {
+        let audio = &melody(3, 11025, 10);
+        let s = MockServer::new(vec![icy("audio/x-wav", 512, &wav::write(11025, 1, audio), &[])]);
+        let mut item_count = 0;
+        s.reader().fingerprint(Algorithm::Test2, |e| {
+            if let FingerprintEvent::Items(v) = e {
+                item_count += v.len();
+            }
+            item_count < 10
+        }).unwrap();
+        assert!(item_count >= 10);
+    }
+
+    #[test]
+    fn unsupported() {
+        let s = MockServer::new(vec![b"ICY 200 OK\r\ncontent-type: audio/aac\r\n\r\n".to_vec()]);
+        match s.reader().read() {
+            Err(Error::UnsupportedContentType(t)) => assert_eq!(t, "audio/aac"),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn redirect() {
+        let audio = &melody(4, 11025, 5);
+        let s = MockServer::new(vec![
+            b"HTTP/1.0 302 Found\r\nLocation: /moved\r\n\r\n".to_vec(),
+            b"HTTP/1.1 307 Temporary Redirect\r\nlocation: /moved/again\r\n\r\n".to_vec(),
+            icy("audio/wav", 1000, &wav::write(11025, 1, audio), &["A"]),
+        ]);
+        let (events, e) = read_all(&mut s.reader());
+        assert!(matches!(e, Error::Io(_)), "{:?}", e);
+        assert_eq!(events[0], StreamEvent::Started { sample_rate: 11025, channel_count: 1 });
+
+        // Redirect loops fail like other unexpected statuses.
+        let redirect = b"HTTP/1.0 301 Moved Permanently\r\nLocation: /stream\r\n\r\n";
+        let s = MockServer::new(vec![redirect.to_vec(); 3 * (MAX_REDIRECTS + 1)]);
+        match s.reader().read() {
+            Err(Error::Http(301)) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn long_header_line() {
+        let long = &vec![b'x'; 2 * MAX_HEADER_LINE_LEN];
+        let s = MockServer::new(vec![
+            [&b"ICY 200 OK "[..], long].concat(),
+            [&b"ICY 200 OK\r\nicy-name: "[..], long].concat(),
+            [&b"ICY 200 OK\r\nicy-name: "[..], long, b"\r\n\r\n"].concat(),
+        ]);
+        match s.reader().read() {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", e),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    /// Silent MPEG-1 Layer III frame, mono 44.1 kHz at 128 kbps.
+    #[cfg(feature = "mp3")]
+    fn silent_mp3_frame() -> Vec<u8> {
+        let mut r = vec![0xff, 0xfb, 0x90, 0xc0];
+        // Zero side info and main data decode to silence.
+        r.resize(1152 * 128 * 125 / 44100, 0);
+        r
+    }
+
+    #[cfg(feature = "mp3")]
+    #[test]
+    fn mp3() {
+        let frame = &silent_mp3_frame();
+        let body = [&b"ID3\x03\0\0\0\0\0\x02\0\0"[..], &frame.repeat(100)].concat();
+        let s = MockServer::new(vec![icy("audio/mpeg", 1000, &body, &["A"])]);
+        let (events, e) = read_all(&mut s.reader());
+        assert!(matches!(e, Error::Io(_)), "{:?}", e);
+        // Decoding starts after some frames are buffered.
+        assert_eq!(events[0], StreamEvent::Title("A".into()));
+        assert_eq!(events[1], StreamEvent::Started { sample_rate: 44100, channel_count: 1 });
+        match &events[2] {
+            // Frames at the end aren't decoded until more data arrives.
+            StreamEvent::Audio(v) => {
+                assert!(v.len() >= 1152 * 50 && v.len() % 1152 == 0, "{}", v.len());
+                assert!(v.iter().all(|&v| v == 0));
+            }
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn decoders() {
+        let samples = &[1, -2, 3, -4, 5, -6];
+        let data = &wav::write(8000, 2, samples);
+        let wav = &mut WavDecoder::new();
+        let out = &mut Vec::new();
+        for b in data.chunks(3) {
+            wav.decode(b, out).unwrap();
+        }
+        assert_eq!(wav.format(), Some((8000, 2)));
+        assert_eq!(out, samples);
+
+        let pcm = &mut PcmDecoder::from_content_type("audio/L16;rate=8000").unwrap();
+        assert_eq!(pcm.format(), Some((8000, 1)));
+        out.clear();
+        pcm.decode(&[0, 1, 0xff], out).unwrap();
+        assert_eq!(out, &[1]);
+        pcm.decode(&[0xfe], out).unwrap();
+        assert_eq!(out, &[1, -2]);
+
+        assert!(PcmDecoder::from_content_type("audio/L16").is_none());
+        assert!(PcmDecoder::from_content_type("audio/L16; rate=x").is_none());
+        assert!(WavDecoder::new().decode(b"RIFX\0\0\0\0WAVE", out).is_err());
+
+        #[cfg(feature = "mp3")]
+        {
+            let mp3 = &mut Mp3Decoder::new();
+            out.clear();
+            mp3.decode(&[0xff; MIN_MP3_BUF_LEN], out).unwrap();
+            assert_eq!(mp3.format(), None);
+            let frames = &silent_mp3_frame().repeat(50);
+            for b in frames.chunks(1000) {
+                mp3.decode(b, out).unwrap();
+            }
+            assert_eq!(mp3.format(), Some((44100, 1)));
+            assert!(!out.is_empty() && out.len() % 1152 == 0);
+        }
+    }
+
+    #[test]
+    fn parse_stream_title_() {
+        assert_eq!(parse_stream_title(b"StreamTitle='A - B';StreamUrl='x';\0\0"),
+            Some("A - B".into()));
+        assert_eq!(parse_stream_title(b"StreamTitle='Don't';\0"), Some("Don't".into()));
+        assert_eq!(parse_stream_title(b"StreamTitle='Caf\xe9';"), Some("Caf\u{e9}".into()));
+        assert_eq!(parse_stream_title(b"StreamTitle='Cut"), Some("Cut".into()));
+        assert_eq!(parse_stream_title(b"StreamUrl='x';"), None);
+        assert_eq!(parse_stream_title(b""), None);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{fingerprint, Algorithm};
+use crate::fingerprint::compare::best_match;
+
+/// Length of the audio cross-correlated at the decimated rate, in seconds.
+const COARSE_WINDOW: f64 = 8.0;
+
+/// Length of the audio cross-correlated at the full rate, in seconds.
+const FINE_WINDOW: f64 = 1.0;
+
+/// Rate the audio is decimated to for the first refinement step.
+const DECIMATED_RATE: u32 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyncOffset {
+    /// Sample of `b` aligned with the first sample of `a`, counting samples of one channel.
+    /// Negative if `a` starts earlier.
+    pub offset: isize,
+
+    /// `offset` in seconds.
+    pub seconds: f64,
+
+    /// Fraction of matching fingerprint bits at the coarse offset, `0..=1`.
+    pub score: f64,
+
+    /// Normalized cross-correlation of the audio at `offset`, `0..=1`.
+    pub confidence: f64,
+}
+
+/// Finds the offset between two recordings of the same event, e.g. audio tracks of several
+/// cameras, with the accuracy of a sample. Both must be interleaved PCM of the same sample
+/// rate and channel count.
+/// The fingerprints are matched first, with offsets up to `max_offset` items, which gives the
+/// offset to within an item. The offset is then refined by cross-correlating the audio around
+/// it, first decimated and then at the full rate.
+pub fn sync(
+    algorithm: Algorithm,
+    sample_rate: u32,
+    channel_count: u32,
+    a: &[i16],
+    b: &[i16],
+    max_offset: usize) -> Option<SyncOffset>
+{
+    let m = best_match(
+        &fingerprint(algorithm, sample_rate, channel_count, a),
+        &fingerprint(algorithm, sample_rate, channel_count, b),
+        max_offset)?;
+
+    let a = &mono(a, channel_count);
+    let b = &mono(b, channel_count);
+    let item = algorithm.fp_config().item_duration_in_seconds() * sample_rate as f64;
+    let coarse = (m.offset as f64 * item).round() as isize;
+
+    let step = (sample_rate / DECIMATED_RATE).max(1) as usize;
+    let (lag, _) = correlate(
+        &decimate(a, step),
+        &decimate(b, step),
+        coarse / step as isize,
+        (2.0 * item).ceil() as usize / step + 1,
+        (COARSE_WINDOW * sample_rate as f64) as usize / step)?;
+    let (offset, confidence) = correlate(
+        a,
+        b,
+        lag * step as isize,
+        2 * step,
+        (FINE_WINDOW * sample_rate as f64) as usize)?;
+
+    Some(SyncOffset {
+        offset,
+        seconds: offset as f64 / sample_rate as f64,
+        score: m.score,
+        confidence: confidence.max(0.0),
+    })
+}
+
+fn mono(input: &[i16], channel_count: u32) -> Vec<f64> {
+    assert!(channel_count > 0);
+    input.chunks_exact(channel_count as usize)
+        .map(|frame| frame.iter().map(|&v| v as f64).sum::<f64>() / channel_count as f64)
+        .collect()
+}
+
+/// Averages of each `step` samples.
+fn decimate(input: &[f64], step: usize) -> Vec<f64> {
+    input.chunks_exact(step)
+        .map(|v| v.iter().sum::<f64>() / step as f64)
+        .collect()
+}
+
+/// Finds the lag within `radius` of `center` where `b` correlates best with `a`.
+/// A window of `a` of up to `window` samples that overlaps `b` at every lag is used.
+/// Returns the lag and the normalized correlation at it.
+fn correlate(a: &[f64], b: &[f64], center: isize, radius: usize, window: usize)
+    -> Option<(isize, f64)>
+{
+    let lo = center - radius as isize;
+    let hi = center + radius as isize;
+    let start = (-lo).max(0);
+    let end = (a.len() as isize).min(b.len() as isize - hi);
+    if end <= start {
+        return None;
+    }
+    // Middle of the part of `a` that's available.
+    let len = (window as isize).min(end - start);
+    let start = (start + (end - start - len) / 2) as usize;
+    let a = &a[start..start + len as usize];
+    let a_energy: f64 = a.iter().map(|v| v * v).sum();
+
+    let mut best: Option<(isize, f64)> = None;
+    for lag in lo..=hi {
+        let b_start = (start as isize + lag) as usize;
+        let b = &b[b_start..b_start + a.len()];
+        let dot: f64 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+        let b_energy: f64 = b.iter().map(|v| v * v).sum();
+        let energy = (a_energy * b_energy).sqrt();
+        let v = if energy > 0.0 { dot / energy } else { 0.0 };
+        if best.map(|(_, b)| v > b).unwrap_or(true) {
+            best = Some((lag, v));
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::test_util::melody;
+
+    const SAMPLE_RATE: u32 = 11025;
+
+    fn add_noise(input: &[i16], seed: u64, amplitude: i16) -> Vec<i16> {
+        let rng = &mut StdRng::seed_from_u64(seed);
+        input.iter().map(|&v| v.saturating_add(rng.gen_range(-amplitude, amplitude))).collect()
+    }
+
+    #[test]
+    fn test() {
+        // Some noise in the source makes the correlation peak sharp, the rest differs between
+        // the recordings.
+        let source = &add_noise(&melody(1, SAMPLE_RATE, 20), 1, 2000);
+        let shift = 3 * SAMPLE_RATE as usize + 4321;
+        let a = &add_noise(&source[..15 * SAMPLE_RATE as usize], 2, 1000);
+        let b = &add_noise(&source[shift..], 3, 1000);
+
+        let act = sync(Algorithm::Test2, SAMPLE_RATE, 1, a, b, 100).unwrap();
+        assert_eq!(act.offset, -(shift as isize));
+        assert!((act.seconds + shift as f64 / SAMPLE_RATE as f64).abs() < 1e-9);
+        assert!(act.score > 0.8, "{}", act.score);
+        assert!(act.confidence > 0.8, "{}", act.confidence);
+
+        let act = sync(Algorithm::Test2, SAMPLE_RATE, 1, b, a, 100).unwrap();
+        assert_eq!(act.offset, shift as isize);
+
+        let stereo: Vec<i16> = b.iter().flat_map(|&v| vec![v, v / 2]).collect();
+        let a_stereo: Vec<i16> = a.iter().flat_map(|&v| vec![v / 2, v]).collect();
+        let act = sync(Algorithm::Test2, SAMPLE_RATE, 2, &a_stereo, &stereo, 100).unwrap();
+        assert_eq!(act.offset, -(shift as isize));
+    }
+
+    #[test]
+    fn unrelated() {
+        let a = &melody(1, SAMPLE_RATE, 10);
+        let b = &melody(2, SAMPLE_RATE, 10);
+        let act = sync(Algorithm::Test2, SAMPLE_RATE, 1, a, b, 100).unwrap();
+        assert!(act.confidence < 0.5, "{}", act.confidence);
+        assert_eq!(sync(Algorithm::Test2, SAMPLE_RATE, 1, &[], b, 100), None);
+    }
+}
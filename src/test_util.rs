@@ -1,4 +1,7 @@
 use byteorder::{LE, ReadBytesExt};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::f64::consts::PI;
 use std::io::{Cursor, ErrorKind};
 
 pub fn read_audio_raw(bytes: &[u8]) -> Vec<i16> {
@@ -16,4 +19,31 @@ pub fn read_audio_raw(bytes: &[u8]) -> Vec<i16> {
         }
     }
     r
-}
\ No newline at end of file
+}
+
+/// Random melody of two-note chords.
+pub fn melody(seed: u64, sample_rate: u32, secs: usize) -> Vec<i16> {
+    melody_in(seed, sample_rate, secs, &(48..84).collect::<Vec<_>>())
+}
+
+/// Random melody of two-note chords of the given MIDI keys.
+pub fn melody_in(seed: u64, sample_rate: u32, secs: usize, keys: &[i32]) -> Vec<i16> {
+    let rng = &mut StdRng::seed_from_u64(seed);
+    let mut r = Vec::new();
+    while r.len() < secs * sample_rate as usize {
+        let len = rng.gen_range(sample_rate / 4, sample_rate / 2) as usize;
+        let chord = [keys[rng.gen_range(0, keys.len())], keys[rng.gen_range(0, keys.len())]];
+        for i in 0..len {
+            let t = i as f64 / sample_rate as f64;
+            let v: f64 = chord.iter()
+                .map(|&k| {
+                    let freq = 440.0 * 2f64.powf((k - 69) as f64 / 12.0);
+                    (2.0 * PI * freq * t).sin()
+                })
+                .sum();
+            r.push((v * 5000.0) as i16);
+        }
+    }
+    r.truncate(secs * sample_rate as usize);
+    r
+}
@@ -1,3 +1,7 @@
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
 pub fn freq_to_index(freq: f64, frame_size: u32, sample_rate: u32) -> u32 {
     (frame_size as f64 * freq / sample_rate as f64).round() as u32
 }
@@ -8,4 +12,83 @@ pub fn index_to_freq(i: u32, frame_size: u32, sample_rate: u32) -> f64 {
 
 pub fn hamming_distance(a: u32, b: u32) -> u32 {
     (a ^ b).count_ones()
-}
\ No newline at end of file
+}
+
+/// Rounds and saturates a sample to the `i16` range.
+pub fn saturate_i16(v: f64) -> i16 {
+    let v = v.round();
+    if v >= i16::MAX as f64 {
+        i16::MAX
+    } else if v <= i16::MIN as f64 {
+        i16::MIN
+    } else {
+        v as i16
+    }
+}
+
+/// Union-find over `0..len`.
+pub struct DisjointSet {
+    parents: Vec<usize>,
+}
+
+impl DisjointSet {
+    pub fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len).collect(),
+        }
+    }
+
+    /// Representative of the set containing `i`.
+    pub fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    pub fn union(&mut self, a: usize, b: usize) {
+        let a = self.find(a);
+        let b = self.find(b);
+        if a != b {
+            self.parents[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+/// Connects to the host of a plain `http://` URL with `timeout` for connecting and every read and
+/// write. Returns the stream, and the host and path of the URL.
+pub fn connect_http(url: &str, timeout: Duration) -> io::Result<(TcpStream, &str, &str)> {
+    connect_url(url, "http://", 80, timeout)
+}
+
+/// Same as `connect_http()` for URLs starting with `scheme`, connecting to `default_port` if the
+/// URL has no port.
+pub fn connect_url<'a>(url: &'a str, scheme: &str, default_port: u16, timeout: Duration)
+    -> io::Result<(TcpStream, &'a str, &'a str)>
+{
+    let rest = url.strip_prefix(scheme).ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported URL {}", url)))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.rsplit(']').next().unwrap().contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, default_port)
+    };
+
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, format!("can't resolve {}", host));
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok((stream, host, path));
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}